@@ -0,0 +1,9 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use sharku::tracker::decode_compact_peers;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(peers) = decode_compact_peers(data) {
+        assert_eq!(peers.len() * 6, data.len());
+    }
+});
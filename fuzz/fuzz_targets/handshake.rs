@@ -0,0 +1,20 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use sharku::net::handshake;
+use tokio::io::AsyncWriteExt;
+
+fuzz_target!(|data: &[u8]| {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    runtime.block_on(async {
+        // Big enough for our whole handshake: the peer side never reads
+        let (mut ours, mut theirs) = tokio::io::duplex(1024);
+        theirs
+            .write_all(&data[..data.len().min(512)])
+            .await
+            .unwrap();
+        theirs.shutdown().await.unwrap();
+        let _ = handshake(&mut ours, &[0; 20], "fuzz").await;
+    });
+});
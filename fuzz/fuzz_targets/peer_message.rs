@@ -0,0 +1,13 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use sharku::codec::{parse_message, MAX_MESSAGE_LEN};
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(message) = parse_message(data) {
+        // What we parse, we write back the same
+        let mut buf = Default::default();
+        message.write(&mut buf);
+        assert!(buf.len() <= 4 + MAX_MESSAGE_LEN);
+        assert_eq!(parse_message(&buf[4..]).unwrap(), message);
+    }
+});
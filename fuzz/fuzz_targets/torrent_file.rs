@@ -0,0 +1,13 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use sharku::torrent_file::decode_torrent;
+use sharku::tracker::info_hash;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(torrent) = decode_torrent(data) {
+        let _ = info_hash(&torrent);
+        let _ = torrent.announce_tiers();
+        let _ = torrent.info.total_length();
+        let _ = torrent.info.pieces_count();
+    }
+});
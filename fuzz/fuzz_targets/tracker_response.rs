@@ -0,0 +1,7 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use sharku::tracker::decode_http_response;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = decode_http_response(data);
+});
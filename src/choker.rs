@@ -0,0 +1,321 @@
+use actix::prelude::*;
+use rand::Rng;
+use std::collections::HashMap;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+use crate::peer::{PeerCommand, PeerConnected, PeerGone, PeerStats};
+use crate::pieces::{HavePieces, PiecesActor};
+
+/// How often the unchoked peers are picked again.
+const RECHOKE_INTERVAL: Duration = Duration::from_secs(10);
+/// The optimistic unchoke moves to another peer every that many rechokes (30 s).
+const OPTIMISTIC_ROUNDS: u32 = 3;
+/// A peer connected for less than that is more likely to be optimistically unchoked.
+const NEW_PEER_AGE: Duration = Duration::from_secs(60);
+/// How much more likely a new peer is to be optimistically unchoked.
+const NEW_PEER_WEIGHT: u32 = 3;
+
+struct ChokedPeer {
+    commands: mpsc::UnboundedSender<PeerCommand>,
+    stats: Arc<PeerStats>,
+    connected_at: Instant,
+    choked: bool,
+    // Counters at the last rechoke, to compute the rates over the round
+    last_downloaded: u64,
+    last_uploaded: u64,
+}
+
+/// Decides which peers we upload to: the ones giving us the most (tit-for-tat), plus one picked
+/// at random so that new peers get a chance to prove themselves.
+pub struct ChokerActor {
+    peers: HashMap<usize, ChokedPeer>,
+    /// Unchoked peers, including the optimistic one
+    slots: usize,
+    optimistic: Option<usize>,
+    round: u32,
+    pieces_actor: Addr<PiecesActor>,
+}
+
+impl ChokerActor {
+    pub fn new(slots: usize, pieces_actor: Addr<PiecesActor>) -> Self {
+        assert!(slots > 0);
+        ChokerActor {
+            peers: HashMap::new(),
+            slots,
+            optimistic: None,
+            round: 0,
+            pieces_actor,
+        }
+    }
+
+    /// Pick the unchoked peers: by rate we download from them while leeching, or by rate we
+    /// upload to them while seeding.
+    fn rechoke(&mut self, seeding: bool, now: Instant) {
+        let mut rates = HashMap::with_capacity(self.peers.len());
+        for (&peer, p) in self.peers.iter_mut() {
+            let downloaded = p.stats.downloaded.load(Ordering::Relaxed);
+            let uploaded = p.stats.uploaded.load(Ordering::Relaxed);
+            let rate = if seeding {
+                uploaded - p.last_uploaded
+            } else {
+                downloaded - p.last_downloaded
+            };
+            p.last_downloaded = downloaded;
+            p.last_uploaded = uploaded;
+            rates.insert(peer, rate);
+        }
+
+        let previous = self.optimistic.filter(|&p| self.is_interested(p));
+        let rotate = previous.is_none() || self.round.is_multiple_of(OPTIMISTIC_ROUNDS);
+        self.round += 1;
+        let kept = if rotate { None } else { previous };
+
+        let mut interested: Vec<usize> = self
+            .peers
+            .keys()
+            .copied()
+            .filter(|&p| self.is_interested(p) && Some(p) != kept)
+            .collect();
+        // Sort by peer too, to be deterministic on ties
+        interested.sort_by_key(|p| (std::cmp::Reverse(rates[p]), *p));
+        interested.truncate(self.slots - 1);
+
+        if rotate {
+            let mut excluded = interested.clone();
+            excluded.extend(previous);
+            // Only keep the same peer if there is nobody else
+            self.optimistic = self
+                .pick_optimistic(now, &excluded)
+                .or_else(|| previous.filter(|p| !interested.contains(p)));
+        }
+
+        for (&peer, p) in self.peers.iter_mut() {
+            let unchoke = Some(peer) == self.optimistic || interested.contains(&peer);
+            if unchoke != p.choked {
+                continue;
+            }
+            p.choked = !unchoke;
+            let command = if unchoke {
+                PeerCommand::Unchoke
+            } else {
+                PeerCommand::Choke
+            };
+            // The connection is closing if it fails, `PeerGone` follows.
+            let _ = p.commands.send(command);
+        }
+    }
+
+    /// Random interested peer not in `excluded`, new peers weighing more.
+    fn pick_optimistic(&self, now: Instant, excluded: &[usize]) -> Option<usize> {
+        let weight = |p: &ChokedPeer| {
+            if now.saturating_duration_since(p.connected_at) < NEW_PEER_AGE {
+                NEW_PEER_WEIGHT
+            } else {
+                1
+            }
+        };
+        let mut candidates: Vec<(usize, u32)> = self
+            .peers
+            .iter()
+            .filter(|(peer, _)| self.is_interested(**peer) && !excluded.contains(peer))
+            .map(|(&peer, p)| (peer, weight(p)))
+            .collect();
+        candidates.sort_unstable();
+
+        let total: u32 = candidates.iter().map(|(_, w)| w).sum();
+        if total == 0 {
+            return None;
+        }
+        let mut ticket = rand::thread_rng().gen_range(0..total);
+        for (peer, w) in candidates {
+            if ticket < w {
+                return Some(peer);
+            }
+            ticket -= w;
+        }
+        unreachable!()
+    }
+
+    fn is_interested(&self, peer: usize) -> bool {
+        self.peers
+            .get(&peer)
+            .map(|p| p.stats.interested.load(Ordering::Relaxed))
+            .unwrap_or(false)
+    }
+}
+
+impl Actor for ChokerActor {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Context<Self>) {
+        ctx.run_interval(RECHOKE_INTERVAL, |choker, ctx| {
+            let have = choker.pieces_actor.send(HavePieces);
+            ctx.spawn(have.into_actor(choker).map(|have, choker, _| {
+                let seeding = have.map(|have| have.all()).unwrap_or(false);
+                choker.rechoke(seeding, Instant::now());
+            }));
+        });
+    }
+}
+
+impl Handler<PeerConnected> for ChokerActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: PeerConnected, _: &mut Context<Self>) -> Self::Result {
+        self.peers.insert(
+            msg.peer,
+            ChokedPeer {
+                commands: msg.commands,
+                stats: msg.stats,
+                connected_at: Instant::now(),
+                choked: true,
+                last_downloaded: 0,
+                last_uploaded: 0,
+            },
+        );
+    }
+}
+
+impl Handler<PeerGone> for ChokerActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: PeerGone, _: &mut Context<Self>) -> Self::Result {
+        self.peers.remove(&msg.peer);
+        if self.optimistic == Some(msg.peer) {
+            self.optimistic = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix::prelude::*;
+    use std::collections::HashMap;
+    use std::env;
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+    use tokio::sync::mpsc;
+
+    use crate::choker::{ChokedPeer, ChokerActor};
+    use crate::fs::FileActor;
+    use crate::peer::{PeerCommand, PeerStats};
+    use crate::pieces::PiecesActor;
+    use crate::torrent_file::Info;
+
+    /// A choker for a torrent of a single small piece, the choking does not depend on it.
+    fn choker(name: &str, slots: usize) -> ChokerActor {
+        let mut bytes = b"d6:lengthi16e4:name4:test12:piece lengthi16384e6:pieces20:".to_vec();
+        bytes.extend_from_slice(&[0; 20]);
+        bytes.push(b'e');
+        let info: Info = serde_bencode::from_bytes(&bytes).unwrap();
+        let path = env::temp_dir().join(name);
+        let file_actor = FileActor::new(&path, info.total_length(), info.piece_length)
+            .unwrap()
+            .start();
+        ChokerActor::new(slots, PiecesActor::new(&info, file_actor).start())
+    }
+
+    fn add_peer(
+        choker: &mut ChokerActor,
+        peer: usize,
+        connected_at: Instant,
+    ) -> (Arc<PeerStats>, mpsc::UnboundedReceiver<PeerCommand>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let stats = Arc::new(PeerStats::default());
+        stats.interested.store(true, Ordering::Relaxed);
+        choker.peers.insert(
+            peer,
+            ChokedPeer {
+                commands: tx,
+                stats: stats.clone(),
+                connected_at,
+                choked: true,
+                last_downloaded: 0,
+                last_uploaded: 0,
+            },
+        );
+        (stats, rx)
+    }
+
+    fn unchoked(choker: &ChokerActor) -> Vec<usize> {
+        let mut peers: Vec<usize> = choker
+            .peers
+            .iter()
+            .filter(|(_, p)| !p.choked)
+            .map(|(&peer, _)| peer)
+            .collect();
+        peers.sort_unstable();
+        peers
+    }
+
+    #[actix::test]
+    async fn fastest_peers_should_be_unchoked() {
+        let now = Instant::now();
+        let old = now - Duration::from_secs(120);
+        let mut choker = choker("sharku_fastest_peers_should_be_unchoked", 3);
+        let mut peers = HashMap::new();
+        for peer in 0..5 {
+            peers.insert(peer, add_peer(&mut choker, peer, old));
+        }
+        // Peer 4 is fast but not interested: it does not need to be unchoked
+        peers[&4].0.interested.store(false, Ordering::Relaxed);
+        for (peer, downloaded) in [(0, 100), (1, 500), (2, 300), (3, 50), (4, 1000)] {
+            peers[&peer].0.add_downloaded(downloaded);
+        }
+
+        choker.rechoke(false, now);
+        let optimistic = choker.optimistic.unwrap();
+        assert!([0, 3].contains(&optimistic));
+        let mut expected = vec![1, 2, optimistic];
+        expected.sort_unstable();
+        assert_eq!(unchoked(&choker), expected);
+        assert_eq!(
+            peers.get_mut(&1).unwrap().1.try_recv().unwrap(),
+            PeerCommand::Unchoke
+        );
+
+        // Seeding: upload rates count, the download counters are ignored
+        for (peer, uploaded) in [(0, 900), (2, 10), (3, 800)] {
+            peers[&peer].0.add_uploaded(uploaded);
+        }
+        choker.rechoke(true, now + Duration::from_secs(10));
+        assert_eq!(choker.optimistic, Some(optimistic));
+        assert_eq!(unchoked(&choker), vec![0, 2, 3]);
+        assert_eq!(
+            peers.get_mut(&1).unwrap().1.try_recv(),
+            Ok(PeerCommand::Choke)
+        );
+    }
+
+    #[actix::test]
+    async fn optimistic_unchoke_should_rotate_and_favour_new_peers() {
+        let now = Instant::now();
+        let mut choker = choker("sharku_optimistic_unchoke_should_rotate", 1);
+        let mut new_picked = 0;
+        let (_old_stats, _old_rx) = add_peer(&mut choker, 0, now - Duration::from_secs(120));
+        let (_new_stats, _new_rx) = add_peer(&mut choker, 1, now);
+        for _ in 0..300 {
+            if choker.pick_optimistic(now, &[]) == Some(1) {
+                new_picked += 1;
+            }
+        }
+        // Expected 225 out of 300
+        assert!(new_picked > 180, "{}", new_picked);
+
+        // Only rotated every 3rd rechoke
+        choker.rechoke(false, now);
+        let first = choker.optimistic.unwrap();
+        choker.rechoke(false, now);
+        choker.rechoke(false, now);
+        assert_eq!(choker.optimistic, Some(first));
+        assert_eq!(unchoked(&choker), vec![first]);
+        // The slot goes to the other peer
+        choker.rechoke(false, now);
+        assert_ne!(choker.optimistic, Some(first));
+        assert_eq!(unchoked(&choker), vec![1 - first]);
+    }
+}
@@ -0,0 +1,501 @@
+use anyhow::Result;
+use bit_vec::BitVec;
+use byteorder::{BigEndian, ReadBytesExt};
+use bytes::{Buf, BufMut, BytesMut};
+use std::convert::TryInto;
+use std::fmt;
+use std::io::Cursor;
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::message::{Message, MessageKind, BLOCK_LENGTH};
+use crate::metadata::METADATA_PIECE_LEN;
+
+/// Longest bitfield payload, enough for 8 million pieces.
+const MAX_BITFIELD_LEN: usize = 1 << 20;
+/// Longest extended payload: a metadata piece, after its bencoded header.
+const MAX_EXTENDED_LEN: usize = METADATA_PIECE_LEN + 1024;
+/// Longest message after the length prefix, whatever its kind: a `Bitfield`.
+pub const MAX_MESSAGE_LEN: usize = 1 + MAX_BITFIELD_LEN;
+
+/// A peer sent something that does not follow the protocol.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProtocolError {
+    /// Message longer than its kind allows
+    TooLong(usize),
+    /// Message shorter than its kind requires
+    Truncated(MessageKind),
+    UnknownId(u8),
+    /// Bitfield of the wrong length or with spare bits set
+    InvalidBitfield(String),
+    /// Extension protocol message we cannot make sense of
+    InvalidExtended(String),
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolError::TooLong(len) => write!(f, "Message too long: len={}", len),
+            ProtocolError::Truncated(kind) => write!(f, "Truncated message: {:?}", kind),
+            ProtocolError::UnknownId(id) => write!(f, "Unknown message id: {}", id),
+            ProtocolError::InvalidBitfield(reason) => write!(f, "Invalid bitfield: {}", reason),
+            ProtocolError::InvalidExtended(reason) => {
+                write!(f, "Invalid extended message: {}", reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+/// Length-prefixed peer wire messages, exchanged after the handshake.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct PeerCodec;
+
+impl Decoder for PeerCodec {
+    type Item = Message;
+    type Error = anyhow::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Message>> {
+        if src.len() < 4 {
+            return Ok(None);
+        }
+        let len = u32::from_be_bytes(src[..4].try_into().unwrap()) as usize;
+        if len > MAX_MESSAGE_LEN {
+            return Err(ProtocolError::TooLong(len).into());
+        }
+        // Rejected before the rest of the frame is buffered
+        if len > 0 && src.len() > 4 {
+            let (_, max_len) = payload_len(&message_kind(src[4])?);
+            if len - 1 > max_len {
+                return Err(ProtocolError::TooLong(len).into());
+            }
+        }
+        if src.len() < 4 + len {
+            src.reserve(4 + len - src.len());
+            return Ok(None);
+        }
+
+        src.advance(4);
+        let frame = src.split_to(len);
+        Ok(Some(parse_message(&frame)?))
+    }
+}
+
+impl Encoder<Message> for PeerCodec {
+    type Error = anyhow::Error;
+
+    fn encode(&mut self, message: Message, dst: &mut BytesMut) -> Result<()> {
+        message.write(dst);
+        Ok(())
+    }
+}
+
+impl Message {
+    /// Length of the message after the length prefix: tag and payload.
+    pub fn size(&self) -> u32 {
+        let payload = match &self {
+            Message::KeepAlive => return 0,
+            Message::Have(_) => 4,
+            Message::Bitfield(bytes) => bytes.len().div_ceil(8) as u32,
+            Message::Request { .. } => 4 + 4 + 4,
+            Message::Piece { data, .. } => 4 + 4 + data.len() as u32,
+            Message::Cancel { .. } => 4 + 4 + 4,
+            Message::Extended { payload, .. } => 1 + payload.len() as u32,
+            Message::Choke | Message::Unchoke | Message::Interested | Message::NotInterested => 0,
+        };
+        1 + payload
+    }
+
+    /// Serialize the message with its length prefix.
+    pub fn write(&self, dst: &mut BytesMut) {
+        dst.reserve(4 + self.size() as usize);
+        dst.put_u32(self.size());
+        if let Some(tag) = self.tag() {
+            dst.put_u8(tag as u8);
+        }
+
+        match &self {
+            Message::Have(piece) => dst.put_u32(*piece),
+            Message::Bitfield(bytes) => dst.put_slice(&bytes.to_bytes()),
+            Message::Request {
+                index,
+                begin,
+                length,
+            }
+            | Message::Cancel {
+                index,
+                begin,
+                length,
+            } => {
+                dst.put_u32(*index);
+                dst.put_u32(*begin);
+                dst.put_u32(*length);
+            }
+            Message::Piece { index, begin, data } => {
+                dst.put_u32(*index);
+                dst.put_u32(*begin);
+                dst.put_slice(data);
+            }
+            Message::Extended { id, payload } => {
+                dst.put_u8(*id);
+                dst.put_slice(payload);
+            }
+            Message::KeepAlive
+            | Message::Choke
+            | Message::Unchoke
+            | Message::Interested
+            | Message::NotInterested => {}
+        };
+    }
+}
+
+/// Parse a message without its length prefix. Empty, it is a keep-alive.
+pub fn parse_message(buf: &[u8]) -> Result<Message, ProtocolError> {
+    if buf.len() > MAX_MESSAGE_LEN {
+        return Err(ProtocolError::TooLong(buf.len()));
+    }
+    let (id, payload) = match buf.split_first() {
+        Some(split) => split,
+        None => return Ok(Message::KeepAlive),
+    };
+    let kind = message_kind(*id)?;
+    let (min_len, max_len) = payload_len(&kind);
+    if payload.len() < min_len {
+        return Err(ProtocolError::Truncated(kind));
+    }
+    if payload.len() > max_len {
+        return Err(ProtocolError::TooLong(buf.len()));
+    }
+
+    // Lengths are checked above, reading cannot fail
+    let mut cursor = Cursor::new(payload);
+    let mut read_u32 = || ReadBytesExt::read_u32::<BigEndian>(&mut cursor).unwrap();
+    let message = match kind {
+        MessageKind::Choke => Message::Choke,
+        MessageKind::Unchoke => Message::Unchoke,
+        MessageKind::Interested => Message::Interested,
+        MessageKind::NotInterested => Message::NotInterested,
+        MessageKind::Have => Message::Have(read_u32()),
+        MessageKind::Bitfield => Message::Bitfield(BitVec::from_bytes(payload)),
+        MessageKind::Request => Message::Request {
+            index: read_u32(),
+            begin: read_u32(),
+            length: read_u32(),
+        },
+        MessageKind::Piece => Message::Piece {
+            index: read_u32(),
+            begin: read_u32(),
+            data: payload[4 + 4..].to_owned(),
+        },
+        MessageKind::Cancel => Message::Cancel {
+            index: read_u32(),
+            begin: read_u32(),
+            length: read_u32(),
+        },
+        MessageKind::Extended => Message::Extended {
+            id: payload[0],
+            payload: payload[1..].to_owned(),
+        },
+    };
+    Ok(message)
+}
+
+/// Payload length of a message kind, at least and at most.
+fn payload_len(kind: &MessageKind) -> (usize, usize) {
+    let block = BLOCK_LENGTH as usize;
+    match kind {
+        MessageKind::Choke
+        | MessageKind::Unchoke
+        | MessageKind::Interested
+        | MessageKind::NotInterested => (0, 0),
+        MessageKind::Have => (4, 4),
+        MessageKind::Request | MessageKind::Cancel => (4 + 4 + 4, 4 + 4 + 4),
+        MessageKind::Bitfield => (0, MAX_BITFIELD_LEN),
+        MessageKind::Piece => (4 + 4, 4 + 4 + block),
+        MessageKind::Extended => (1, 1 + MAX_EXTENDED_LEN),
+    }
+}
+
+fn message_kind(id: u8) -> Result<MessageKind, ProtocolError> {
+    let kind = match id {
+        0 => MessageKind::Choke,
+        1 => MessageKind::Unchoke,
+        2 => MessageKind::Interested,
+        3 => MessageKind::NotInterested,
+        4 => MessageKind::Have,
+        5 => MessageKind::Bitfield,
+        6 => MessageKind::Request,
+        7 => MessageKind::Piece,
+        8 => MessageKind::Cancel,
+        20 => MessageKind::Extended,
+        _ => return Err(ProtocolError::UnknownId(id)),
+    };
+    Ok(kind)
+}
+
+#[cfg(test)]
+mod tests {
+    use bit_vec::BitVec;
+    use bytes::BytesMut;
+    use proptest::prelude::*;
+    use tokio_util::codec::{Decoder, Encoder};
+
+    use crate::codec::{parse_message, PeerCodec, ProtocolError, MAX_MESSAGE_LEN};
+    use crate::message::{Message, MessageKind, BLOCK_LENGTH};
+
+    /// One message of each kind.
+    fn messages() -> Vec<Message> {
+        vec![
+            Message::KeepAlive,
+            Message::Choke,
+            Message::Unchoke,
+            Message::Interested,
+            Message::NotInterested,
+            Message::Have(0xcafe),
+            Message::Bitfield(BitVec::from_bytes(&[0b1010_0000, 0b0000_0001])),
+            Message::Request {
+                index: 1,
+                begin: BLOCK_LENGTH,
+                length: BLOCK_LENGTH,
+            },
+            Message::Piece {
+                index: 1,
+                begin: BLOCK_LENGTH,
+                data: vec![7; BLOCK_LENGTH as usize],
+            },
+            Message::Cancel {
+                index: 1,
+                begin: BLOCK_LENGTH,
+                length: BLOCK_LENGTH,
+            },
+            Message::Extended {
+                id: 0,
+                payload: b"d1:md11:ut_metadatai1eee".to_vec(),
+            },
+        ]
+    }
+
+    fn any_message() -> impl Strategy<Value = Message> {
+        let block = || (any::<u32>(), any::<u32>(), any::<u32>());
+        prop_oneof![
+            Just(Message::KeepAlive),
+            Just(Message::Choke),
+            Just(Message::Unchoke),
+            Just(Message::Interested),
+            Just(Message::NotInterested),
+            any::<u32>().prop_map(Message::Have),
+            prop::collection::vec(any::<u8>(), 0..512)
+                .prop_map(|bytes| Message::Bitfield(BitVec::from_bytes(&bytes))),
+            block().prop_map(|(index, begin, length)| Message::Request {
+                index,
+                begin,
+                length
+            }),
+            (
+                any::<u32>(),
+                any::<u32>(),
+                prop::collection::vec(any::<u8>(), 0..=BLOCK_LENGTH as usize)
+            )
+                .prop_map(|(index, begin, data)| Message::Piece { index, begin, data }),
+            block().prop_map(|(index, begin, length)| Message::Cancel {
+                index,
+                begin,
+                length
+            }),
+            (any::<u8>(), prop::collection::vec(any::<u8>(), 0..512))
+                .prop_map(|(id, payload)| Message::Extended { id, payload }),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn written_messages_should_parse_back(message in any_message()) {
+            let mut buf = BytesMut::new();
+            message.write(&mut buf);
+            prop_assert_eq!(buf.len(), 4 + message.size() as usize);
+            prop_assert_eq!(parse_message(&buf[4..]), Ok(message));
+        }
+
+        #[test]
+        fn any_bytes_should_parse_or_fail_without_panicking(
+            bytes in prop::collection::vec(any::<u8>(), 0..64)
+        ) {
+            if let Ok(message) = parse_message(&bytes) {
+                let mut buf = BytesMut::new();
+                message.write(&mut buf);
+                prop_assert_eq!(&buf[4..], &bytes[..]);
+            }
+        }
+    }
+
+    #[test]
+    fn every_message_kind_should_round_trip() {
+        let mut codec = PeerCodec;
+        for message in messages() {
+            let mut buf = BytesMut::new();
+            codec.encode(message.clone(), &mut buf).unwrap();
+            assert_eq!(
+                buf.len(),
+                4 + message.size() as usize,
+                "{:?}",
+                message.tag()
+            );
+            assert_eq!(codec.decode(&mut buf).unwrap(), Some(message));
+            assert!(buf.is_empty());
+        }
+    }
+
+    #[test]
+    fn keep_alive_should_be_an_empty_frame() {
+        let mut buf = BytesMut::new();
+        PeerCodec.encode(Message::KeepAlive, &mut buf).unwrap();
+        assert_eq!(&buf[..], &[0, 0, 0, 0]);
+
+        let mut buf = BytesMut::new();
+        PeerCodec.encode(Message::Unchoke, &mut buf).unwrap();
+        assert_eq!(&buf[..], &[0, 0, 0, 1, MessageKind::Unchoke as u8]);
+    }
+
+    #[test]
+    fn frames_should_be_decoded_only_once_complete() {
+        let mut codec = PeerCodec;
+        let mut encoded = BytesMut::new();
+        for message in messages() {
+            codec.encode(message, &mut encoded).unwrap();
+        }
+
+        // Fed in small chunks, like a slow socket would
+        let mut buf = BytesMut::new();
+        let mut decoded = Vec::new();
+        for chunk in encoded.chunks(7) {
+            buf.extend_from_slice(chunk);
+            while let Some(message) = codec.decode(&mut buf).unwrap() {
+                decoded.push(message);
+            }
+        }
+        assert_eq!(decoded, messages());
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn too_long_frame_should_be_rejected() {
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(&(MAX_MESSAGE_LEN as u32 + 1).to_be_bytes());
+        let err = PeerCodec.decode(&mut buf).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<ProtocolError>(),
+            Some(&ProtocolError::TooLong(MAX_MESSAGE_LEN + 1))
+        );
+    }
+
+    #[test]
+    fn large_bitfield_should_be_decoded() {
+        // A torrent of 1 million pieces
+        let bitfield = Message::Bitfield(BitVec::from_elem(1 << 20, true));
+        let mut buf = BytesMut::new();
+        PeerCodec.encode(bitfield.clone(), &mut buf).unwrap();
+        assert_eq!(PeerCodec.decode(&mut buf).unwrap(), Some(bitfield));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn frame_too_long_for_its_kind_should_be_rejected_early() {
+        let len = 1 + 4 + 4 + BLOCK_LENGTH as usize + 1;
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(&(len as u32).to_be_bytes());
+        buf.extend_from_slice(&[MessageKind::Piece as u8]);
+        let err = PeerCodec.decode(&mut buf).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<ProtocolError>(),
+            Some(&ProtocolError::TooLong(len))
+        );
+    }
+
+    #[test]
+    fn malformed_messages_should_be_errors() {
+        let unchoke = [MessageKind::Unchoke as u8, 0];
+        assert_eq!(parse_message(&unchoke), Err(ProtocolError::TooLong(2)));
+        let have = [MessageKind::Have as u8, 0, 0, 1];
+        assert_eq!(
+            parse_message(&have),
+            Err(ProtocolError::Truncated(MessageKind::Have))
+        );
+        let piece = [MessageKind::Piece as u8, 0, 0, 0, 1, 0, 0, 0];
+        assert_eq!(
+            parse_message(&piece),
+            Err(ProtocolError::Truncated(MessageKind::Piece))
+        );
+        let request = [MessageKind::Request as u8; 14];
+        assert_eq!(parse_message(&request), Err(ProtocolError::TooLong(14)));
+        // Fast extension, not supported
+        assert_eq!(parse_message(&[0x0e]), Err(ProtocolError::UnknownId(0x0e)));
+        assert_eq!(
+            parse_message(&[MessageKind::Extended as u8]),
+            Err(ProtocolError::Truncated(MessageKind::Extended))
+        );
+        assert_eq!(parse_message(&[]), Ok(Message::KeepAlive));
+    }
+
+    #[test]
+    fn parse_message_bitfield() -> Result<(), String> {
+        match parse_message(&[MessageKind::Bitfield as u8, 0b0000_0001, 0b1000_0010]) {
+            Ok(Message::Bitfield(bytes))
+                if bytes.eq_vec(&[
+                    false, false, false, false, false, false, false, true, true, false, false,
+                    false, false, false, true, false,
+                ]) =>
+            {
+                Ok(())
+            }
+            other => Err(format!("Got {:#?}", other)),
+        }
+    }
+
+    #[test]
+    fn parse_message_request() {
+        let mut bytes = vec![MessageKind::Request as u8];
+        bytes.extend_from_slice(&u32::to_be_bytes(0xcafe));
+        bytes.extend_from_slice(&u32::to_be_bytes(0xabcd));
+        bytes.extend_from_slice(&u32::to_be_bytes(0xef12));
+        assert_eq!(
+            parse_message(&bytes).unwrap(),
+            Message::Request {
+                index: 0xcafe,
+                begin: 0xabcd,
+                length: 0xef12,
+            }
+        );
+    }
+
+    #[test]
+    fn parse_message_piece() {
+        let mut bytes = vec![MessageKind::Piece as u8];
+        bytes.extend_from_slice(&u32::to_be_bytes(0xcafe));
+        bytes.extend_from_slice(&u32::to_be_bytes(0xabcd));
+        bytes.extend_from_slice(&[7, 8, 9, 10, 11]);
+        assert_eq!(
+            parse_message(&bytes).unwrap(),
+            Message::Piece {
+                index: 0xcafe,
+                begin: 0xabcd,
+                data: vec![7, 8, 9, 10, 11],
+            }
+        );
+    }
+
+    #[test]
+    fn parse_message_cancel() {
+        let mut bytes = vec![MessageKind::Cancel as u8];
+        bytes.extend_from_slice(&u32::to_be_bytes(0xcafe));
+        bytes.extend_from_slice(&u32::to_be_bytes(0xabcd));
+        bytes.extend_from_slice(&u32::to_be_bytes(0xef12));
+        assert_eq!(
+            parse_message(&bytes).unwrap(),
+            Message::Cancel {
+                index: 0xcafe,
+                begin: 0xabcd,
+                length: 0xef12,
+            }
+        );
+    }
+}
@@ -0,0 +1,671 @@
+use anyhow::{Context, Result};
+use futures_util::stream::{FuturesUnordered, StreamExt};
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use sha1::{Digest, Sha1};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::convert::TryInto;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+use tokio::net::{lookup_host, UdpSocket};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+use tokio::time;
+
+use crate::tracker::Peer;
+
+pub mod krpc;
+pub mod routing;
+
+use krpc::{
+    decode_compact_nodes, encode_compact_nodes, Body, Krpc, Query, Response, PROTOCOL_ERROR,
+};
+use routing::{distance, NodeId, NodeInfo, RoutingTable, K};
+
+/// Queries not answered by then fail.
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+/// Queries in flight during a lookup.
+const ALPHA: usize = 3;
+/// Tokens are valid between 5 and 10 minutes: the secret changes every 5 minutes, and the previous
+/// one is still accepted.
+const TOKEN_ROTATION: Duration = Duration::from_secs(5 * 60);
+/// Announced peers are forgotten after that, unless announced again.
+const PEER_TTL: Duration = Duration::from_secs(30 * 60);
+const MAX_PEERS_PER_TORRENT: usize = 100;
+const MAX_TORRENTS: usize = 1000;
+/// Peers in a get_peers response, to stay within a UDP packet.
+const MAX_VALUES: usize = 50;
+/// How often the peers of a torrent are looked up again.
+const LOOKUP_INTERVAL: Duration = Duration::from_secs(15 * 60);
+/// How often the bootstrap is tried again while no node answered.
+const BOOTSTRAP_RETRY_INTERVAL: Duration = Duration::from_secs(30);
+/// Well-known nodes to bootstrap from, when no node of a previous run is known.
+pub const DEFAULT_BOOTSTRAP: &[&str] = &[
+    "router.bittorrent.com:6881",
+    "dht.transmissionbt.com:6881",
+    "router.utorrent.com:6881",
+];
+const MAX_PACKET_LEN: usize = 2048;
+
+/// Secrets the tokens are derived from, so that only the nodes that asked us for peers can
+/// announce themselves, and only from their address.
+struct Tokens {
+    secret: [u8; 20],
+    previous: [u8; 20],
+    rotated: Instant,
+}
+
+impl Tokens {
+    fn new(now: Instant) -> Self {
+        Tokens {
+            secret: rand::random(),
+            previous: rand::random(),
+            rotated: now,
+        }
+    }
+
+    fn rotate(&mut self, now: Instant) {
+        if now.duration_since(self.rotated) >= TOKEN_ROTATION {
+            self.previous = self.secret;
+            self.secret = rand::random();
+            self.rotated = now;
+        }
+    }
+
+    fn token(&self, ip: &IpAddr) -> Vec<u8> {
+        token(&self.secret, ip)
+    }
+
+    fn check(&self, ip: &IpAddr, token: &[u8]) -> bool {
+        token == self::token(&self.secret, ip) || token == self::token(&self.previous, ip)
+    }
+}
+
+fn token(secret: &[u8; 20], ip: &IpAddr) -> Vec<u8> {
+    let mut hasher = Sha1::new();
+    hasher.update(secret);
+    match ip {
+        IpAddr::V4(ip) => hasher.update(ip.octets()),
+        IpAddr::V6(ip) => hasher.update(ip.octets()),
+    }
+    hasher.finalize()[..8].to_vec()
+}
+
+/// Peers announced to us, by info_hash.
+#[derive(Default)]
+struct PeerStore(HashMap<[u8; 20], HashMap<SocketAddr, Instant>>);
+
+impl PeerStore {
+    fn announce(&mut self, info_hash: [u8; 20], addr: SocketAddr, now: Instant) {
+        self.expire(now);
+        if !self.0.contains_key(&info_hash) && self.0.len() >= MAX_TORRENTS {
+            return;
+        }
+        let peers = self.0.entry(info_hash).or_default();
+        if peers.contains_key(&addr) || peers.len() < MAX_PEERS_PER_TORRENT {
+            peers.insert(addr, now);
+        }
+    }
+
+    fn peers(&mut self, info_hash: &[u8; 20], now: Instant) -> Vec<SocketAddr> {
+        self.expire(now);
+        self.0
+            .get(info_hash)
+            .map(|peers| peers.keys().take(MAX_VALUES).copied().collect())
+            .unwrap_or_default()
+    }
+
+    fn expire(&mut self, now: Instant) {
+        for peers in self.0.values_mut() {
+            peers.retain(|_, announced| now.duration_since(*announced) < PEER_TTL);
+        }
+        self.0.retain(|_, peers| !peers.is_empty());
+    }
+}
+
+/// A query waiting for its response.
+struct Pending {
+    addr: SocketAddr,
+    tx: oneshot::Sender<Result<Response>>,
+}
+
+struct State {
+    table: RoutingTable,
+    pending: HashMap<Vec<u8>, Pending>,
+    next_transaction: u16,
+    tokens: Tokens,
+    peers: PeerStore,
+}
+
+struct Inner {
+    socket: Arc<UdpSocket>,
+    state: Mutex<State>,
+    receiver: JoinHandle<()>,
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        self.receiver.abort();
+    }
+}
+
+/// The result of a lookup: the closest nodes that answered, with the token to announce to them,
+/// and the peers they know about.
+#[derive(Debug, Default)]
+struct Lookup {
+    closest: Vec<(NodeInfo, Option<Vec<u8>>)>,
+    values: Vec<SocketAddr>,
+}
+
+/// What is kept between runs: our id, to keep our place in the DHT, and the nodes to bootstrap
+/// from.
+#[derive(Debug, Serialize, Deserialize)]
+struct SavedDht {
+    id: ByteBuf,
+    nodes: ByteBuf,
+}
+
+/// A node of the mainline DHT (BEP 5), over IPv4. Answers the queries of the other nodes, and
+/// looks up the peers of torrents.
+#[derive(Clone)]
+pub struct Dht(Arc<Inner>);
+
+impl Dht {
+    pub async fn bind(addr: SocketAddr, own_id: NodeId) -> Result<Self> {
+        let socket = Arc::new(
+            UdpSocket::bind(addr)
+                .await
+                .with_context(|| format!("Failed to bind DHT socket on {}", addr))?,
+        );
+        let now = Instant::now();
+        let inner = Arc::new_cyclic(|inner: &Weak<Inner>| Inner {
+            socket: socket.clone(),
+            state: Mutex::new(State {
+                table: RoutingTable::new(own_id),
+                pending: HashMap::new(),
+                next_transaction: rand::random(),
+                tokens: Tokens::new(now),
+                peers: PeerStore::default(),
+            }),
+            receiver: tokio::spawn(receive(socket, inner.clone())),
+        });
+        Ok(Dht(inner))
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.0.socket.local_addr()?)
+    }
+
+    pub fn id(&self) -> NodeId {
+        *self.0.state.lock().unwrap().table.own_id()
+    }
+
+    /// Number of nodes in the routing table.
+    pub fn len(&self) -> usize {
+        self.0.state.lock().unwrap().table.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Our id and the nodes of a previous run.
+    pub fn load(path: &Path) -> Result<(NodeId, Vec<SocketAddr>)> {
+        let bytes = std::fs::read(path)
+            .with_context(|| format!("Failed to read DHT nodes from {}", path.display()))?;
+        let saved: SavedDht =
+            serde_bencode::from_bytes(&bytes).context("Invalid DHT nodes file")?;
+        let id = saved.id.as_slice().try_into().context("Invalid DHT id")?;
+        let nodes = decode_compact_nodes(&saved.nodes)?;
+        Ok((id, nodes.into_iter().map(|node| node.addr).collect()))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let saved = {
+            let state = self.0.state.lock().unwrap();
+            SavedDht {
+                id: ByteBuf::from(state.table.own_id().to_vec()),
+                nodes: ByteBuf::from(encode_compact_nodes(&state.table.nodes())),
+            }
+        };
+        std::fs::write(path, serde_bencode::to_bytes(&saved)?)
+            .with_context(|| format!("Failed to save DHT nodes to {}", path.display()))
+    }
+
+    async fn query(&self, addr: SocketAddr, query: Query) -> Result<Response> {
+        let (tx, rx) = oneshot::channel();
+        let (transaction, id) = {
+            let mut state = self.0.state.lock().unwrap();
+            let transaction = state.next_transaction.to_be_bytes().to_vec();
+            state.next_transaction = state.next_transaction.wrapping_add(1);
+            state
+                .pending
+                .insert(transaction.clone(), Pending { addr, tx });
+            (transaction, *state.table.own_id())
+        };
+        let message = Krpc {
+            transaction: transaction.clone(),
+            body: Body::Query { id, query },
+        };
+        let res = async {
+            self.0.socket.send_to(&message.encode(), addr).await?;
+            time::timeout(QUERY_TIMEOUT, rx)
+                .await
+                .context("Query timed out")?
+                .context("Query dropped")?
+        }
+        .await;
+        if res.is_err() {
+            let mut state = self.0.state.lock().unwrap();
+            state.pending.remove(&transaction);
+            state.table.failed(&addr);
+        }
+        res
+    }
+
+    pub async fn ping(&self, addr: SocketAddr) -> Result<NodeId> {
+        Ok(self.query(addr, Query::Ping).await?.id)
+    }
+
+    /// Fill the routing table by looking up our own id, starting from `nodes` and the nodes
+    /// already known.
+    pub async fn bootstrap(&self, nodes: &[SocketAddr]) -> Result<()> {
+        let own_id = self.id();
+        self.lookup(own_id, nodes, false).await;
+        if self.is_empty() {
+            anyhow::bail!("No DHT node answered");
+        }
+        log::info!("DHT bootstrapped: nodes={}", self.len());
+        Ok(())
+    }
+
+    /// Look up the peers of a torrent, and announce that we have it on `port` if any.
+    pub async fn get_peers(&self, info_hash: [u8; 20], port: Option<u16>) -> Vec<Peer> {
+        let lookup = self.lookup(info_hash, &[], true).await;
+        if let Some(port) = port {
+            let announces = lookup
+                .closest
+                .iter()
+                .filter_map(|(node, token)| Some((node.addr, token.clone()?)))
+                .map(|(addr, token)| {
+                    let query = Query::AnnouncePeer {
+                        info_hash,
+                        port,
+                        token,
+                        implied_port: false,
+                    };
+                    async move {
+                        if let Err(err) = self.query(addr, query).await {
+                            log::debug!("Failed to announce to DHT node {}: {:#}", addr, err);
+                        }
+                    }
+                });
+            futures_util::future::join_all(announces).await;
+        }
+        lookup
+            .values
+            .into_iter()
+            .map(|addr| Peer {
+                ip: addr.ip(),
+                port: addr.port(),
+            })
+            .collect()
+    }
+
+    /// Ping the nodes not heard of for a while, to replace the ones that left.
+    pub async fn refresh(&self) {
+        let questionable = self
+            .0
+            .state
+            .lock()
+            .unwrap()
+            .table
+            .questionable(Instant::now());
+        let pings = questionable.iter().map(|node| self.ping(node.addr));
+        futures_util::future::join_all(pings).await;
+    }
+
+    /// Iterative lookup of the `K` nodes closest to `target`, with `get_peers` or `find_node`
+    /// queries.
+    async fn lookup(&self, target: NodeId, start: &[SocketAddr], get_peers: bool) -> Lookup {
+        let query = |addr: SocketAddr| async move {
+            let query = match get_peers {
+                true => Query::GetPeers { info_hash: target },
+                false => Query::FindNode { target },
+            };
+            (addr, self.query(addr, query).await)
+        };
+        let own_id = self.id();
+        // By distance to the target
+        let mut candidates: BTreeMap<NodeId, NodeInfo> = self
+            .0
+            .state
+            .lock()
+            .unwrap()
+            .table
+            .closest(&target, K)
+            .into_iter()
+            .map(|node| (distance(&node.id, &target), node))
+            .collect();
+        let mut responded = BTreeMap::new();
+        let mut values = HashSet::new();
+        let mut queried: HashSet<SocketAddr> = start.iter().copied().collect();
+        let mut in_flight: FuturesUnordered<_> = start.iter().map(|&addr| query(addr)).collect();
+        loop {
+            while in_flight.len() < ALPHA {
+                let next = candidates
+                    .values()
+                    .take(K)
+                    .find(|node| !queried.contains(&node.addr))
+                    .copied();
+                match next {
+                    Some(node) => {
+                        queried.insert(node.addr);
+                        in_flight.push(query(node.addr));
+                    }
+                    None => break,
+                }
+            }
+            let (addr, res) = match in_flight.next().await {
+                Some(res) => res,
+                // The closest nodes all answered or failed
+                None => break,
+            };
+            match res {
+                Ok(response) => {
+                    let node = NodeInfo {
+                        id: response.id,
+                        addr,
+                    };
+                    let key = distance(&response.id, &target);
+                    candidates.insert(key, node);
+                    responded.insert(key, (node, response.token));
+                    for node in response.nodes {
+                        if node.id != own_id && !queried.contains(&node.addr) {
+                            candidates.insert(distance(&node.id, &target), node);
+                        }
+                    }
+                    values.extend(response.values);
+                }
+                Err(err) => {
+                    log::debug!("DHT query to {} failed: {:#}", addr, err);
+                    candidates.retain(|_, node| node.addr != addr);
+                }
+            }
+        }
+        Lookup {
+            closest: responded.into_values().take(K).collect(),
+            values: values.into_iter().collect(),
+        }
+    }
+}
+
+/// Handle the packets received, until the node is dropped.
+async fn receive(socket: Arc<UdpSocket>, inner: Weak<Inner>) {
+    let mut buf = [0u8; MAX_PACKET_LEN];
+    loop {
+        let (len, from) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(err) => {
+                log::debug!("Failed to receive DHT packet: {}", err);
+                continue;
+            }
+        };
+        let inner = match inner.upgrade() {
+            Some(inner) => inner,
+            None => return,
+        };
+        let message = match Krpc::decode(&buf[..len]) {
+            Ok(message) => message,
+            Err(err) => {
+                log::debug!("Invalid DHT packet from {}: {:#}", from, err);
+                continue;
+            }
+        };
+        if let Some(reply) = on_message(&inner.state, message, from) {
+            if let Err(err) = socket.send_to(&reply.encode(), from).await {
+                log::debug!("Failed to answer DHT node {}: {}", from, err);
+            }
+        }
+    }
+}
+
+/// Handle a message from a node, and return the reply to a query.
+fn on_message(state: &Mutex<State>, message: Krpc, from: SocketAddr) -> Option<Krpc> {
+    let now = Instant::now();
+    let mut state = state.lock().unwrap();
+    let (id, query) = match message.body {
+        Body::Query { id, query } => (id, query),
+        Body::Response(response) => {
+            match state.pending.remove(&message.transaction) {
+                // Only the queried node can answer
+                Some(pending) if pending.addr == from => {
+                    state.table.heard_from(
+                        NodeInfo {
+                            id: response.id,
+                            addr: from,
+                        },
+                        now,
+                    );
+                    let _ = pending.tx.send(Ok(response));
+                }
+                Some(pending) => {
+                    state.pending.insert(message.transaction, pending);
+                }
+                None => {}
+            }
+            return None;
+        }
+        Body::Error {
+            code,
+            message: text,
+        } => {
+            if let Some(pending) = state.pending.remove(&message.transaction) {
+                let _ = pending
+                    .tx
+                    .send(Err(anyhow::anyhow!("DHT error {}: {}", code, text)));
+            }
+            return None;
+        }
+    };
+
+    state.table.heard_from(NodeInfo { id, addr: from }, now);
+    state.tokens.rotate(now);
+    let own_id = *state.table.own_id();
+    let mut response = Response {
+        id: own_id,
+        ..Default::default()
+    };
+    match query {
+        Query::Ping => {}
+        Query::FindNode { target } => response.nodes = state.table.closest(&target, K),
+        Query::GetPeers { info_hash } => {
+            response.token = Some(state.tokens.token(&from.ip()));
+            response.values = state.peers.peers(&info_hash, now);
+            if response.values.is_empty() {
+                response.nodes = state.table.closest(&info_hash, K);
+            }
+        }
+        Query::AnnouncePeer {
+            info_hash,
+            port,
+            token,
+            implied_port,
+        } => {
+            let port = if implied_port { from.port() } else { port };
+            if !state.tokens.check(&from.ip(), &token) || port == 0 {
+                return Some(Krpc {
+                    transaction: message.transaction,
+                    body: Body::Error {
+                        code: PROTOCOL_ERROR,
+                        message: "Bad token".to_owned(),
+                    },
+                });
+            }
+            state
+                .peers
+                .announce(info_hash, SocketAddr::new(from.ip(), port), now);
+        }
+    }
+    Some(Krpc {
+        transaction: message.transaction,
+        body: Body::Response(response),
+    })
+}
+
+/// Look up the peers of a torrent every `LOOKUP_INTERVAL`, announcing that we have it on `port`,
+/// and hand them to the connection code. `bootstrap` has the `host:port` of nodes to start from.
+pub async fn find_peers(
+    dht: Dht,
+    info_hash: [u8; 20],
+    port: u16,
+    bootstrap: Vec<String>,
+    peers: mpsc::UnboundedSender<Vec<Peer>>,
+) {
+    let mut retry = false;
+    loop {
+        // Until a node answers, e.g. when the network was not up yet
+        while dht.is_empty() {
+            if retry {
+                time::sleep(BOOTSTRAP_RETRY_INTERVAL).await;
+            }
+            let nodes = resolve(&bootstrap).await;
+            match dht.bootstrap(&nodes).await {
+                Err(err) if !retry => log::warn!("Failed to bootstrap the DHT: {:#}", err),
+                Err(err) => log::debug!("Failed to bootstrap the DHT: {:#}", err),
+                Ok(()) => {}
+            }
+            retry = true;
+        }
+        let found = dht.get_peers(info_hash, Some(port)).await;
+        log::debug!("DHT lookup: peers={} nodes={}", found.len(), dht.len());
+        if !found.is_empty() && peers.send(found).is_err() {
+            return;
+        }
+        time::sleep(LOOKUP_INTERVAL).await;
+        dht.refresh().await;
+    }
+}
+
+/// The IPv4 addresses of the `host:port` nodes.
+async fn resolve(hosts: &[String]) -> Vec<SocketAddr> {
+    let mut nodes = Vec::new();
+    for host in hosts {
+        match lookup_host(host).await {
+            Ok(addrs) => nodes.extend(addrs.filter(SocketAddr::is_ipv4)),
+            Err(err) => log::debug!("Failed to resolve DHT node {}: {}", host, err),
+        }
+    }
+    nodes
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, SocketAddr};
+    use std::time::{Duration, Instant};
+
+    use crate::dht::krpc::{Body, Krpc, Query};
+    use crate::dht::{on_message, Dht, PeerStore, Tokens, PEER_TTL, TOKEN_ROTATION};
+    use crate::tracker::Peer;
+
+    async fn swarm(size: usize) -> Vec<Dht> {
+        let mut nodes = Vec::new();
+        for _ in 0..size {
+            let dht = Dht::bind("127.0.0.1:0".parse().unwrap(), rand::random())
+                .await
+                .unwrap();
+            nodes.push(dht);
+        }
+        let first = nodes[0].local_addr().unwrap();
+        for dht in &nodes[1..] {
+            dht.bootstrap(&[first]).await.unwrap();
+        }
+        nodes
+    }
+
+    #[actix::test]
+    async fn announced_peers_should_be_found_by_other_nodes() {
+        let nodes = swarm(12).await;
+        assert!(nodes[0].len() >= 8);
+        let info_hash = [0x42; 20];
+        assert!(nodes[3].get_peers(info_hash, Some(7000)).await.is_empty());
+
+        let peers = nodes[9].get_peers(info_hash, None).await;
+        assert_eq!(
+            peers,
+            vec![Peer {
+                ip: "127.0.0.1".parse().unwrap(),
+                port: 7000
+            }]
+        );
+    }
+
+    #[actix::test]
+    async fn routing_table_should_be_saved_and_loaded() {
+        let nodes = swarm(3).await;
+        let path = std::env::temp_dir().join("sharku_routing_table_should_be_saved_and_loaded");
+        nodes[1].save(&path).unwrap();
+        let (id, saved) = Dht::load(&path).unwrap();
+        assert_eq!(id, nodes[1].id());
+        assert_eq!(saved.len(), 2);
+
+        let restarted = Dht::bind("127.0.0.1:0".parse().unwrap(), id).await.unwrap();
+        restarted.bootstrap(&saved).await.unwrap();
+        assert_eq!(restarted.len(), 2);
+        assert!(Dht::load(&path.with_extension("missing")).is_err());
+    }
+
+    #[test]
+    fn announces_should_need_a_valid_token() {
+        let now = Instant::now();
+        let mut tokens = Tokens::new(now);
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let token = tokens.token(&ip);
+        assert!(tokens.check(&ip, &token));
+        assert!(!tokens.check(&"10.0.0.2".parse().unwrap(), &token));
+
+        tokens.rotate(now + TOKEN_ROTATION);
+        assert!(tokens.check(&ip, &token));
+        tokens.rotate(now + TOKEN_ROTATION * 2);
+        assert!(!tokens.check(&ip, &token));
+    }
+
+    #[actix::test]
+    async fn bad_announces_should_be_answered_with_an_error() {
+        let dht = Dht::bind("127.0.0.1:0".parse().unwrap(), [1; 20])
+            .await
+            .unwrap();
+        let from: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let announce = Krpc {
+            transaction: b"aa".to_vec(),
+            body: Body::Query {
+                id: [2; 20],
+                query: Query::AnnouncePeer {
+                    info_hash: [3; 20],
+                    port: 6881,
+                    token: b"wrong".to_vec(),
+                    implied_port: false,
+                },
+            },
+        };
+        let reply = on_message(&dht.0.state, announce, from).unwrap();
+        assert!(matches!(reply.body, Body::Error { code: 203, .. }));
+        // The querying node is still added
+        assert_eq!(dht.len(), 1);
+    }
+
+    #[test]
+    fn announced_peers_should_expire() {
+        let now = Instant::now();
+        let mut store = PeerStore::default();
+        let peer: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        store.announce([1; 20], peer, now);
+        assert_eq!(store.peers(&[1; 20], now), vec![peer]);
+        assert!(store.peers(&[2; 20], now).is_empty());
+        assert!(store
+            .peers(&[1; 20], now + PEER_TTL + Duration::from_secs(1))
+            .is_empty());
+    }
+}
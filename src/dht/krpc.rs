@@ -0,0 +1,381 @@
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use std::convert::TryInto;
+use std::net::SocketAddr;
+
+use crate::dht::routing::{NodeId, NodeInfo};
+
+/// Compact node info: node id, IPv4 address and port.
+pub const COMPACT_NODE_LEN: usize = 26;
+
+/// KRPC error code for malformed packets, invalid arguments or bad tokens.
+pub const PROTOCOL_ERROR: i64 = 203;
+
+/// A query, what a node asks another one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Query {
+    Ping,
+    FindNode {
+        target: NodeId,
+    },
+    GetPeers {
+        info_hash: [u8; 20],
+    },
+    AnnouncePeer {
+        info_hash: [u8; 20],
+        /// Ignored with `implied_port`, the source port of the query is used instead
+        port: u16,
+        token: Vec<u8>,
+        implied_port: bool,
+    },
+}
+
+/// The answer to any query: only the keys relevant to the query are set.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Response {
+    pub id: NodeId,
+    pub nodes: Vec<NodeInfo>,
+    pub token: Option<Vec<u8>>,
+    pub values: Vec<SocketAddr>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Body {
+    Query { id: NodeId, query: Query },
+    Response(Response),
+    Error { code: i64, message: String },
+}
+
+/// A KRPC message, with the transaction id that matches a response to its query.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Krpc {
+    pub transaction: Vec<u8>,
+    pub body: Body,
+}
+
+/// The bencoded dictionary, all message types mixed.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct RawMessage {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    a: Option<RawArguments>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    e: Option<(i64, String)>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    q: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    r: Option<RawResponse>,
+    t: ByteBuf,
+    y: String,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct RawArguments {
+    id: ByteBuf,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    implied_port: Option<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    info_hash: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    port: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    target: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    token: Option<ByteBuf>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct RawResponse {
+    id: ByteBuf,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    nodes: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    token: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    values: Option<Vec<ByteBuf>>,
+}
+
+impl Krpc {
+    pub fn decode(bytes: &[u8]) -> Result<Self> {
+        let raw: RawMessage = serde_bencode::from_bytes(bytes).context("Invalid KRPC message")?;
+        let body = match raw.y.as_str() {
+            "q" => {
+                let args = raw.a.context("Missing arguments in query")?;
+                let query = match raw.q.as_deref() {
+                    Some("ping") => Query::Ping,
+                    Some("find_node") => Query::FindNode {
+                        target: id(args.target.as_deref().context("Missing target")?)?,
+                    },
+                    Some("get_peers") => Query::GetPeers {
+                        info_hash: id(args.info_hash.as_deref().context("Missing info_hash")?)?,
+                    },
+                    Some("announce_peer") => Query::AnnouncePeer {
+                        info_hash: id(args.info_hash.as_deref().context("Missing info_hash")?)?,
+                        port: args.port.unwrap_or_default(),
+                        token: args.token.context("Missing token")?.into_vec(),
+                        implied_port: args.implied_port.unwrap_or_default() != 0,
+                    },
+                    other => bail!("Unknown query: {:?}", other),
+                };
+                Body::Query {
+                    id: id(&args.id)?,
+                    query,
+                }
+            }
+            "r" => {
+                let r = raw.r.context("Missing response")?;
+                let values = r
+                    .values
+                    .unwrap_or_default()
+                    .iter()
+                    .map(|value| decode_compact_addr(value))
+                    .collect::<Result<_>>()?;
+                Body::Response(Response {
+                    id: id(&r.id)?,
+                    nodes: decode_compact_nodes(&r.nodes.unwrap_or_default())?,
+                    token: r.token.map(ByteBuf::into_vec),
+                    values,
+                })
+            }
+            "e" => {
+                let (code, message) = raw.e.context("Missing error")?;
+                Body::Error { code, message }
+            }
+            other => bail!("Unknown message type: {:?}", other),
+        };
+        Ok(Krpc {
+            transaction: raw.t.into_vec(),
+            body,
+        })
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut raw = RawMessage {
+            t: ByteBuf::from(self.transaction.clone()),
+            ..Default::default()
+        };
+        match &self.body {
+            Body::Query { id, query } => {
+                raw.y = "q".to_owned();
+                let mut args = RawArguments {
+                    id: ByteBuf::from(id.to_vec()),
+                    ..Default::default()
+                };
+                let name = match query {
+                    Query::Ping => "ping",
+                    Query::FindNode { target } => {
+                        args.target = Some(ByteBuf::from(target.to_vec()));
+                        "find_node"
+                    }
+                    Query::GetPeers { info_hash } => {
+                        args.info_hash = Some(ByteBuf::from(info_hash.to_vec()));
+                        "get_peers"
+                    }
+                    Query::AnnouncePeer {
+                        info_hash,
+                        port,
+                        token,
+                        implied_port,
+                    } => {
+                        args.info_hash = Some(ByteBuf::from(info_hash.to_vec()));
+                        args.port = Some(*port);
+                        args.token = Some(ByteBuf::from(token.clone()));
+                        args.implied_port = Some(*implied_port as u8);
+                        "announce_peer"
+                    }
+                };
+                raw.q = Some(name.to_owned());
+                raw.a = Some(args);
+            }
+            Body::Response(response) => {
+                raw.y = "r".to_owned();
+                raw.r = Some(RawResponse {
+                    id: ByteBuf::from(response.id.to_vec()),
+                    nodes: Some(ByteBuf::from(encode_compact_nodes(&response.nodes)))
+                        .filter(|nodes| !nodes.is_empty()),
+                    token: response.token.clone().map(ByteBuf::from),
+                    values: Some(
+                        response
+                            .values
+                            .iter()
+                            .filter_map(encode_compact_addr)
+                            .map(ByteBuf::from)
+                            .collect::<Vec<_>>(),
+                    )
+                    .filter(|values| !values.is_empty()),
+                });
+            }
+            Body::Error { code, message } => {
+                raw.y = "e".to_owned();
+                raw.e = Some((*code, message.clone()));
+            }
+        }
+        serde_bencode::to_bytes(&raw).unwrap()
+    }
+}
+
+fn id(bytes: &[u8]) -> Result<[u8; 20]> {
+    bytes
+        .try_into()
+        .with_context(|| format!("Invalid node id or info_hash length: {}", bytes.len()))
+}
+
+/// IPv4 address and port, as in the compact peer lists of the trackers.
+fn decode_compact_addr(bytes: &[u8]) -> Result<SocketAddr> {
+    if bytes.len() != 6 {
+        bail!("Invalid compact address length: {}", bytes.len());
+    }
+    let ip: [u8; 4] = bytes[..4].try_into().unwrap();
+    let port = u16::from_be_bytes([bytes[4], bytes[5]]);
+    Ok(SocketAddr::from((ip, port)))
+}
+
+/// Only IPv4 addresses have a compact form here (BEP 5).
+fn encode_compact_addr(addr: &SocketAddr) -> Option<Vec<u8>> {
+    match addr {
+        SocketAddr::V4(addr) => {
+            let mut bytes = addr.ip().octets().to_vec();
+            bytes.extend_from_slice(&addr.port().to_be_bytes());
+            Some(bytes)
+        }
+        SocketAddr::V6(_) => None,
+    }
+}
+
+pub fn decode_compact_nodes(bytes: &[u8]) -> Result<Vec<NodeInfo>> {
+    if !bytes.len().is_multiple_of(COMPACT_NODE_LEN) {
+        bail!("The compact nodes list has the wrong size: {}", bytes.len());
+    }
+    bytes
+        .chunks(COMPACT_NODE_LEN)
+        .map(|bytes| {
+            Ok(NodeInfo {
+                id: id(&bytes[..20])?,
+                addr: decode_compact_addr(&bytes[20..])?,
+            })
+        })
+        .collect()
+}
+
+pub fn encode_compact_nodes(nodes: &[NodeInfo]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(nodes.len() * COMPACT_NODE_LEN);
+    for node in nodes {
+        if let Some(addr) = encode_compact_addr(&node.addr) {
+            bytes.extend_from_slice(&node.id);
+            bytes.extend_from_slice(&addr);
+        }
+    }
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dht::krpc::{Body, Krpc, Query, Response, PROTOCOL_ERROR};
+    use crate::dht::routing::NodeInfo;
+
+    #[test]
+    fn bep5_examples_should_be_decoded() {
+        let ping =
+            Krpc::decode(b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe").unwrap();
+        assert_eq!(
+            ping,
+            Krpc {
+                transaction: b"aa".to_vec(),
+                body: Body::Query {
+                    id: *b"abcdefghij0123456789",
+                    query: Query::Ping
+                }
+            }
+        );
+        assert_eq!(
+            ping.encode(),
+            b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe".to_vec()
+        );
+
+        let announce = b"d1:ad2:id20:abcdefghij012345678912:implied_porti1e9:info_hash20:mnopqrstuvwxyz1234564:porti6881e5:token8:aoeusnthe1:q13:announce_peer1:t2:aa1:y1:qe";
+        let decoded = Krpc::decode(announce).unwrap();
+        assert_eq!(
+            decoded.body,
+            Body::Query {
+                id: *b"abcdefghij0123456789",
+                query: Query::AnnouncePeer {
+                    info_hash: *b"mnopqrstuvwxyz123456",
+                    port: 6881,
+                    token: b"aoeusnth".to_vec(),
+                    implied_port: true
+                }
+            }
+        );
+        assert_eq!(decoded.encode(), announce.to_vec());
+
+        let peers =
+            Krpc::decode(b"d1:rd2:id20:abcdefghij01234567895:token8:aoeusnth6:valuesl6:axje.u6:idhtnmee1:t2:aa1:y1:re")
+                .unwrap();
+        let response = match peers.body {
+            Body::Response(response) => response,
+            other => panic!("Got {:?}", other),
+        };
+        assert_eq!(response.token, Some(b"aoeusnth".to_vec()));
+        assert_eq!(
+            response.values,
+            vec![
+                "97.120.106.101:11893".parse().unwrap(),
+                "105.100.104.116:28269".parse().unwrap()
+            ]
+        );
+
+        let error = Krpc::decode(b"d1:eli201e23:A Generic Error Ocurrede1:t2:aa1:y1:ee").unwrap();
+        assert_eq!(
+            error.body,
+            Body::Error {
+                code: 201,
+                message: "A Generic Error Ocurred".to_owned()
+            }
+        );
+    }
+
+    #[test]
+    fn nodes_should_round_trip() {
+        let response = Krpc {
+            transaction: vec![0, 1],
+            body: Body::Response(Response {
+                id: [1; 20],
+                nodes: vec![
+                    NodeInfo {
+                        id: [2; 20],
+                        addr: "10.0.0.2:6881".parse().unwrap(),
+                    },
+                    NodeInfo {
+                        id: [3; 20],
+                        addr: "10.0.0.3:6882".parse().unwrap(),
+                    },
+                ],
+                token: None,
+                values: Vec::new(),
+            }),
+        };
+        assert_eq!(Krpc::decode(&response.encode()).unwrap(), response);
+    }
+
+    #[test]
+    fn malformed_messages_should_be_rejected() {
+        for bytes in [
+            &b"not bencode"[..],
+            b"d1:t2:aa1:y1:qe",
+            b"d1:ad2:id3:abce1:q4:ping1:t2:aa1:y1:qe",
+            b"d1:ad2:id20:abcdefghij0123456789e1:q4:nope1:t2:aa1:y1:qe",
+            b"d1:rd2:id20:abcdefghij01234567895:nodes3:abce1:t2:aa1:y1:re",
+            b"d1:t2:aa1:y1:xe",
+        ] {
+            assert!(Krpc::decode(bytes).is_err(), "{:?}", bytes);
+        }
+        let error = Krpc {
+            transaction: b"aa".to_vec(),
+            body: Body::Error {
+                code: PROTOCOL_ERROR,
+                message: "Bad token".to_owned(),
+            },
+        };
+        assert_eq!(Krpc::decode(&error.encode()).unwrap(), error);
+    }
+}
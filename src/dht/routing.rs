@@ -0,0 +1,213 @@
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+pub type NodeId = [u8; 20];
+
+/// Nodes per bucket.
+pub const K: usize = 8;
+/// A node that stopped answering that many times in a row is replaced by the next one we hear of.
+const MAX_FAILURES: u32 = 2;
+/// A node heard of more recently is good, older ones are questionable (BEP 5).
+const GOOD_FOR: Duration = Duration::from_secs(15 * 60);
+
+/// A node as sent in the compact node lists.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NodeInfo {
+    pub id: NodeId,
+    pub addr: SocketAddr,
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    node: NodeInfo,
+    last_seen: Instant,
+    failures: u32,
+}
+
+/// The nodes we know, in one bucket of `K` nodes per length of the prefix they share with our
+/// own id: we know more of the nodes close to us.
+#[derive(Debug, Clone)]
+pub struct RoutingTable {
+    own_id: NodeId,
+    buckets: Vec<Vec<Entry>>,
+}
+
+/// XOR metric between two ids, compared as big-endian numbers.
+pub fn distance(a: &NodeId, b: &NodeId) -> NodeId {
+    let mut distance = [0u8; 20];
+    for i in 0..20 {
+        distance[i] = a[i] ^ b[i];
+    }
+    distance
+}
+
+impl RoutingTable {
+    pub fn new(own_id: NodeId) -> Self {
+        RoutingTable {
+            own_id,
+            buckets: vec![Vec::new(); 160],
+        }
+    }
+
+    pub fn own_id(&self) -> &NodeId {
+        &self.own_id
+    }
+
+    /// Index of the bucket of `id`: the number of leading bits it shares with our own id.
+    fn bucket(&self, id: &NodeId) -> Option<usize> {
+        let distance = distance(&self.own_id, id);
+        let zeros = match distance.iter().position(|&b| b != 0) {
+            Some(i) => i * 8 + distance[i].leading_zeros() as usize,
+            // Ourselves
+            None => return None,
+        };
+        Some(zeros)
+    }
+
+    /// A node answered us or queried us. Returns whether it is in the table.
+    pub fn heard_from(&mut self, node: NodeInfo, now: Instant) -> bool {
+        let index = match self.bucket(&node.id) {
+            Some(index) => index,
+            None => return false,
+        };
+        let bucket = &mut self.buckets[index];
+        if let Some(entry) = bucket.iter_mut().find(|entry| entry.node.id == node.id) {
+            entry.node.addr = node.addr;
+            entry.last_seen = now;
+            entry.failures = 0;
+            return true;
+        }
+        let entry = Entry {
+            node,
+            last_seen: now,
+            failures: 0,
+        };
+        if bucket.len() < K {
+            bucket.push(entry);
+            return true;
+        }
+        match bucket
+            .iter_mut()
+            .find(|entry| entry.failures >= MAX_FAILURES)
+        {
+            Some(bad) => {
+                *bad = entry;
+                true
+            }
+            // Long-lived nodes are the most likely to stay
+            None => false,
+        }
+    }
+
+    /// A node did not answer a query.
+    pub fn failed(&mut self, addr: &SocketAddr) {
+        for entry in self.buckets.iter_mut().flatten() {
+            if &entry.node.addr == addr {
+                entry.failures += 1;
+            }
+        }
+    }
+
+    /// The `count` known nodes closest to `target`, the closest first. The nodes that stopped
+    /// answering are left out.
+    pub fn closest(&self, target: &NodeId, count: usize) -> Vec<NodeInfo> {
+        let mut nodes: Vec<NodeInfo> = self
+            .buckets
+            .iter()
+            .flatten()
+            .filter(|entry| entry.failures < MAX_FAILURES)
+            .map(|entry| entry.node)
+            .collect();
+        nodes.sort_by_key(|node| distance(&node.id, target));
+        nodes.truncate(count);
+        nodes
+    }
+
+    /// Nodes not heard of for a while, to ping before they are considered bad.
+    pub fn questionable(&self, now: Instant) -> Vec<NodeInfo> {
+        self.buckets
+            .iter()
+            .flatten()
+            .filter(|entry| now.duration_since(entry.last_seen) > GOOD_FOR)
+            .map(|entry| entry.node)
+            .collect()
+    }
+
+    pub fn nodes(&self) -> Vec<NodeInfo> {
+        self.buckets
+            .iter()
+            .flatten()
+            .map(|entry| entry.node)
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(Vec::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::time::{Duration, Instant};
+
+    use crate::dht::routing::{NodeInfo, RoutingTable, K};
+
+    fn node(id: [u8; 20], port: u16) -> NodeInfo {
+        NodeInfo {
+            id,
+            addr: SocketAddr::from(([10, 0, 0, 1], port)),
+        }
+    }
+
+    fn id(first: u8, last: u8) -> [u8; 20] {
+        let mut id = [0; 20];
+        id[0] = first;
+        id[19] = last;
+        id
+    }
+
+    #[test]
+    fn buckets_should_be_limited_to_k_nodes() {
+        let now = Instant::now();
+        let mut table = RoutingTable::new([0; 20]);
+        assert!(!table.heard_from(node([0; 20], 1), now));
+
+        // All in the bucket of the first bit
+        for i in 0..K as u8 {
+            assert!(table.heard_from(node(id(0x80, i), i as u16), now));
+        }
+        assert!(!table.heard_from(node(id(0x80, 100), 100), now));
+        // Known nodes are refreshed, closer ones go to other buckets
+        assert!(table.heard_from(node(id(0x80, 0), 1000), now));
+        assert!(table.heard_from(node(id(0x01, 0), 1), now));
+        assert_eq!(table.len(), K + 1);
+
+        // A node that stopped answering makes room
+        let failing = SocketAddr::from(([10, 0, 0, 1], 3));
+        table.failed(&failing);
+        assert!(!table.heard_from(node(id(0x80, 100), 100), now));
+        table.failed(&failing);
+        assert!(table.heard_from(node(id(0x80, 100), 100), now));
+        assert_eq!(table.len(), K + 1);
+    }
+
+    #[test]
+    fn closest_nodes_should_be_sorted_by_distance() {
+        let now = Instant::now();
+        let mut table = RoutingTable::new([0; 20]);
+        for first in [0x80, 0x40, 0x20, 0x10, 0x08] {
+            table.heard_from(node(id(first, 0), first as u16), now);
+        }
+        let closest = table.closest(&id(0x30, 0), 3);
+        let ids: Vec<u8> = closest.iter().map(|node| node.id[0]).collect();
+        assert_eq!(ids, vec![0x20, 0x10, 0x08]);
+
+        assert!(table.questionable(now).is_empty());
+        assert_eq!(table.questionable(now + Duration::from_secs(3600)).len(), 5);
+    }
+}
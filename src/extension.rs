@@ -0,0 +1,356 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_bencode::value::Value;
+use serde_bytes::ByteBuf;
+use std::collections::BTreeMap;
+use std::convert::{TryFrom, TryInto};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::codec::ProtocolError;
+use crate::message::Message;
+
+/// Extended message id of the extension handshake.
+pub const HANDSHAKE_ID: u8 = 0;
+/// How often `Extension::tick` is called.
+pub const TICK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// First message of the extension protocol, sent by both sides. Only `m` has to be well-formed,
+/// the other keys are ignored when of the wrong type or out of range.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExtensionHandshake {
+    /// Supported extensions, with the message id the sender wants to receive them with, 0 to
+    /// disable one
+    #[serde(default)]
+    pub m: BTreeMap<String, i64>,
+    /// Size of the info dictionary, for the metadata exchange
+    #[serde(
+        default,
+        deserialize_with = "lenient_int",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub metadata_size: Option<u64>,
+    /// Port the sender listens on
+    #[serde(
+        default,
+        deserialize_with = "lenient_int",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub p: Option<u16>,
+    /// How many requests the sender queues without dropping them
+    #[serde(
+        default,
+        deserialize_with = "lenient_int",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub reqq: Option<u32>,
+    /// Client name and version
+    #[serde(
+        default,
+        deserialize_with = "lenient_string",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub v: Option<String>,
+    /// Our address as the sender sees it, 4 or 16 bytes
+    #[serde(
+        default,
+        deserialize_with = "lenient_bytes",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub yourip: Option<ByteBuf>,
+}
+
+impl ExtensionHandshake {
+    pub fn decode(payload: &[u8]) -> Result<Self, ProtocolError> {
+        serde_bencode::from_bytes(payload)
+            .map_err(|err| ProtocolError::InvalidExtended(format!("handshake: {}", err)))
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        serde_bencode::to_bytes(self).unwrap()
+    }
+
+    /// Message id the sender wants for an extension, none if it does not support it.
+    pub fn id(&self, name: &str) -> Option<u8> {
+        self.m
+            .get(name)
+            .and_then(|&id| id.try_into().ok())
+            .filter(|&id| id != HANDSHAKE_ID)
+    }
+
+    pub fn your_ip(&self) -> Option<IpAddr> {
+        let bytes: &[u8] = self.yourip.as_ref()?;
+        if let Ok(octets) = TryInto::<[u8; 4]>::try_into(bytes) {
+            return Some(IpAddr::from(octets));
+        }
+        let octets: [u8; 16] = bytes.try_into().ok()?;
+        Some(IpAddr::from(octets))
+    }
+}
+
+fn lenient_int<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: TryFrom<i64>,
+{
+    Ok(match Value::deserialize(deserializer)? {
+        Value::Int(int) => T::try_from(int).ok(),
+        _ => None,
+    })
+}
+
+fn lenient_string<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<String>, D::Error> {
+    let bytes = lenient_bytes(deserializer)?;
+    Ok(bytes.map(|bytes| String::from_utf8_lossy(&bytes).into_owned()))
+}
+
+fn lenient_bytes<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<ByteBuf>, D::Error> {
+    Ok(match Value::deserialize(deserializer)? {
+        Value::Bytes(bytes) => Some(ByteBuf::from(bytes)),
+        _ => None,
+    })
+}
+
+/// A protocol extension over the extended messages, e.g. the metadata exchange or PEX. One
+/// instance per connection.
+pub trait Extension: Send {
+    /// Key in the `m` dictionary of the extension handshake.
+    fn name(&self) -> &'static str;
+
+    /// Add our own keys to the extension handshake we send.
+    fn extend_handshake(&self, _handshake: &mut ExtensionHandshake) {}
+
+    /// The peer supports the extension. Returns the payloads to send it.
+    fn on_handshake(&mut self, _handshake: &ExtensionHandshake) -> Result<Vec<Vec<u8>>> {
+        Ok(Vec::new())
+    }
+
+    /// A message of the extension from the peer. Returns the payloads to send back.
+    fn on_message(&mut self, payload: &[u8]) -> Result<Vec<Vec<u8>>>;
+
+    /// Called every `TICK_INTERVAL` once the peer supports the extension. Returns the payloads
+    /// to send it.
+    fn tick(&mut self) -> Result<Vec<Vec<u8>>> {
+        Ok(Vec::new())
+    }
+}
+
+/// Creates the extensions of a torrent for each new connection, given the peer address and
+/// whether the peer connected to us.
+pub type ExtensionFactory = Arc<dyn Fn(SocketAddr, bool) -> Box<dyn Extension> + Send + Sync>;
+
+/// Extensions of a connection, and the message ids negotiated with the peer.
+pub struct ExtensionRegistry {
+    /// We receive the messages of the extension `i` with the id `i + 1`
+    extensions: Vec<Box<dyn Extension>>,
+    /// Their handshake, once received
+    theirs: Option<ExtensionHandshake>,
+}
+
+impl ExtensionRegistry {
+    pub fn new(extensions: Vec<Box<dyn Extension>>) -> Self {
+        assert!(extensions.len() < u8::MAX as usize);
+        ExtensionRegistry {
+            extensions,
+            theirs: None,
+        }
+    }
+
+    /// Our extension handshake, for a peer at `peer_ip`.
+    pub fn handshake(&self, port: u16, reqq: u32, peer_ip: IpAddr) -> Message {
+        let mut handshake = ExtensionHandshake {
+            p: Some(port),
+            reqq: Some(reqq),
+            v: Some(format!("sharku {}", env!("CARGO_PKG_VERSION"))),
+            yourip: Some(ByteBuf::from(match peer_ip {
+                IpAddr::V4(ip) => ip.octets().to_vec(),
+                IpAddr::V6(ip) => ip.octets().to_vec(),
+            })),
+            ..Default::default()
+        };
+        for (i, extension) in self.extensions.iter().enumerate() {
+            handshake
+                .m
+                .insert(extension.name().to_owned(), i as i64 + 1);
+            extension.extend_handshake(&mut handshake);
+        }
+        Message::Extended {
+            id: HANDSHAKE_ID,
+            payload: handshake.encode(),
+        }
+    }
+
+    /// The peer's extension handshake, once received.
+    pub fn theirs(&self) -> Option<&ExtensionHandshake> {
+        self.theirs.as_ref()
+    }
+
+    /// Handle an extended message and return the messages to send back.
+    pub fn on_message(&mut self, id: u8, payload: &[u8]) -> Result<Vec<Message>> {
+        if id == HANDSHAKE_ID {
+            let theirs = ExtensionHandshake::decode(payload)?;
+            let mut messages = Vec::new();
+            for extension in self.extensions.iter_mut() {
+                if let Some(their_id) = theirs.id(extension.name()) {
+                    let name = extension.name();
+                    let payloads = extension
+                        .on_handshake(&theirs)
+                        .with_context(|| format!("Extension {} failed", name))?;
+                    messages.extend(extended(their_id, payloads));
+                }
+            }
+            self.theirs = Some(theirs);
+            return Ok(messages);
+        }
+
+        let theirs = self
+            .theirs
+            .as_ref()
+            .ok_or_else(|| ProtocolError::InvalidExtended("message before handshake".into()))?;
+        let extension = self
+            .extensions
+            .get_mut(id as usize - 1)
+            .ok_or_else(|| ProtocolError::InvalidExtended(format!("unknown id {}", id)))?;
+        let name = extension.name();
+        let their_id = match theirs.id(name) {
+            Some(their_id) => their_id,
+            // Disabled by the peer, it should not use it either
+            None => return Ok(Vec::new()),
+        };
+        let payloads = extension
+            .on_message(payload)
+            .with_context(|| format!("Extension {} failed", name))?;
+        Ok(extended(their_id, payloads).collect())
+    }
+
+    /// Tick the extensions the peer supports and return the messages to send.
+    pub fn tick(&mut self) -> Result<Vec<Message>> {
+        let theirs = match &self.theirs {
+            Some(theirs) => theirs,
+            None => return Ok(Vec::new()),
+        };
+        let mut messages = Vec::new();
+        for extension in self.extensions.iter_mut() {
+            if let Some(their_id) = theirs.id(extension.name()) {
+                let name = extension.name();
+                let payloads = extension
+                    .tick()
+                    .with_context(|| format!("Extension {} failed", name))?;
+                messages.extend(extended(their_id, payloads));
+            }
+        }
+        Ok(messages)
+    }
+}
+
+fn extended(id: u8, payloads: Vec<Vec<u8>>) -> impl Iterator<Item = Message> {
+    payloads
+        .into_iter()
+        .map(move |payload| Message::Extended { id, payload })
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use crate::codec::ProtocolError;
+    use crate::extension::{Extension, ExtensionHandshake, ExtensionRegistry};
+    use crate::message::Message;
+
+    /// Answers each message with its payload reversed.
+    struct Echo;
+
+    impl Extension for Echo {
+        fn name(&self) -> &'static str {
+            "echo"
+        }
+
+        fn extend_handshake(&self, handshake: &mut ExtensionHandshake) {
+            handshake.metadata_size = Some(42);
+        }
+
+        fn on_handshake(&mut self, _: &ExtensionHandshake) -> Result<Vec<Vec<u8>>> {
+            Ok(vec![b"hello".to_vec()])
+        }
+
+        fn on_message(&mut self, payload: &[u8]) -> Result<Vec<Vec<u8>>> {
+            Ok(vec![payload.iter().rev().copied().collect()])
+        }
+    }
+
+    fn payload(message: Message) -> Vec<u8> {
+        match message {
+            Message::Extended { id: 0, payload } => payload,
+            other => panic!("Got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn handshake_should_advertise_extensions() {
+        let registry = ExtensionRegistry::new(vec![Box::new(Echo)]);
+        let ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let bytes = payload(registry.handshake(6881, 250, ip));
+        assert_eq!(
+            bytes,
+            b"d1:md4:echoi1ee13:metadata_sizei42e1:pi6881e4:reqqi250e1:v12:sharku 0.1.06:yourip4:\x0a\x00\x00\x01e"
+                .to_vec()
+        );
+        let handshake = ExtensionHandshake::decode(&bytes).unwrap();
+        assert_eq!(handshake.id("echo"), Some(1));
+        assert_eq!(handshake.your_ip(), Some(ip));
+    }
+
+    #[test]
+    fn messages_should_use_the_ids_of_the_peer() {
+        let mut registry = ExtensionRegistry::new(vec![Box::new(Echo)]);
+        assert!(registry.on_message(1, b"abc").is_err());
+
+        // Unknown keys are ignored
+        let messages = registry
+            .on_message(0, b"d1:md4:echoi3e5:otheri1ee3:fooi1ee")
+            .unwrap();
+        assert_eq!(
+            messages,
+            vec![Message::Extended {
+                id: 3,
+                payload: b"hello".to_vec()
+            }]
+        );
+        assert_eq!(registry.theirs().unwrap().id("other"), Some(1));
+        assert_eq!(
+            registry.on_message(1, b"abc").unwrap(),
+            vec![Message::Extended {
+                id: 3,
+                payload: b"cba".to_vec()
+            }]
+        );
+        let err = registry.on_message(2, b"abc").unwrap_err();
+        assert!(err.downcast_ref::<ProtocolError>().is_some());
+
+        // Disabled by the peer
+        registry.on_message(0, b"d1:md4:echoi0eee").unwrap();
+        assert!(registry.on_message(1, b"abc").unwrap().is_empty());
+        assert!(registry.on_message(0, b"not bencode").is_err());
+    }
+
+    #[test]
+    fn malformed_optional_keys_should_be_ignored() {
+        let handshake =
+            ExtensionHandshake::decode(b"d1:md4:echoi1ee1:pi-1e4:reqqi99999999999e1:v2:\xffae")
+                .unwrap();
+        assert_eq!(handshake.id("echo"), Some(1));
+        assert_eq!(handshake.p, None);
+        assert_eq!(handshake.reqq, None);
+        assert_eq!(handshake.v.as_deref(), Some("\u{fffd}a"));
+
+        let handshake =
+            ExtensionHandshake::decode(b"d13:metadata_size1:x1:pli1ee1:vi1e6:youripi0ee").unwrap();
+        assert_eq!(handshake, ExtensionHandshake::default());
+
+        // Unlike a malformed `m`
+        assert!(ExtensionHandshake::decode(b"d1:mi1ee").is_err());
+        assert!(ExtensionHandshake::decode(b"d1:md4:echo1:xee").is_err());
+    }
+}
@@ -2,7 +2,8 @@ use actix::prelude::*;
 use anyhow::{Context as Ctx, Result};
 use memmap::MmapMut;
 use std::fs::OpenOptions;
-use std::path::Path;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
 
 use crate::message::Message as M;
 
@@ -10,8 +11,25 @@ impl Message for M {
     type Result = ();
 }
 
+/// Read a block of a piece from disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Message)]
+#[rtype(result = "Result<Vec<u8>>")]
+pub struct ReadBlock {
+    pub index: u32,
+    pub begin: u32,
+    pub length: u32,
+}
+
+/// A file of the torrent mapped in memory, and its position in the torrent content.
+struct MappedFile {
+    offset: u64,
+    length: u64,
+    // Empty files cannot be mapped
+    mmap: Option<MmapMut>,
+}
+
 pub struct FileActor {
-    mmap: MmapMut,
+    files: Vec<MappedFile>,
     piece_length: u32,
 }
 
@@ -23,54 +41,149 @@ impl Handler<M> for FileActor {
     type Result = ();
 
     fn handle(&mut self, msg: M, _: &mut Context<Self>) -> Self::Result {
-        println!("Msg={:?}", msg);
         match msg {
             M::Piece { index, begin, data } => {
-                // TODO: checks
-
-                let start = index as usize * self.piece_length as usize + begin as usize;
-                let end = start as usize + data.len();
-                self.mmap[start..end].copy_from_slice(data.as_slice());
+                log::debug!("Write: index={} begin={} len={}", index, begin, data.len());
+                let offset = index as u64 * self.piece_length as u64 + begin as u64;
                 let _ = self
-                    .mmap
-                    .flush_range(start, data.len())
-                    .map_err(|err| log::warn!("Failed to flush mmapped file: {}", err));
+                    .write_at(offset, &data)
+                    .map_err(|err| log::warn!("Failed to write block: {}", err));
             }
-            _ => todo!(),
+            msg => log::warn!("Ignoring message: {:?}", msg.tag()),
         }
     }
 }
 
+impl Handler<ReadBlock> for FileActor {
+    type Result = Result<Vec<u8>>;
+
+    fn handle(&mut self, msg: ReadBlock, _: &mut Context<Self>) -> Self::Result {
+        let offset = msg.index as u64 * self.piece_length as u64 + msg.begin as u64;
+        self.read_at(offset, msg.length as usize)
+    }
+}
+
 impl FileActor {
     pub fn new(path: &Path, file_length: u64, piece_length: u32) -> Result<Self> {
-        let file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(&path)
-            .with_context(|| {
-                format!(
-                    "Failed to open file as RW: path={} len={}",
-                    path.to_string_lossy(),
-                    file_length
-                )
-            })?;
-
-        file.set_len(file_length).with_context(|| {
+        Self::with_files(&[(path.to_owned(), file_length)], piece_length)
+    }
+
+    /// Map the files of a torrent in order, so that the content spans all of them.
+    pub fn with_files(paths: &[(PathBuf, u64)], piece_length: u32) -> Result<Self> {
+        let mut files = Vec::with_capacity(paths.len());
+        let mut offset = 0;
+        for (path, file_length) in paths {
+            files.push(MappedFile {
+                offset,
+                length: *file_length,
+                mmap: map_file(path, *file_length)?,
+            });
+            offset += file_length;
+        }
+
+        Ok(FileActor {
+            piece_length,
+            files,
+        })
+    }
+
+    /// Call `f` with each mapped file overlapping `[offset, offset + len)`, the part of the file
+    /// in this range, and the matching range relative to `offset`.
+    fn for_each_span<F>(&mut self, offset: u64, len: usize, mut f: F) -> Result<()>
+    where
+        F: FnMut(&mut MmapMut, Range<usize>, Range<usize>),
+    {
+        let total_length = self.files.last().map_or(0, |f| f.offset + f.length);
+        let end = offset + len as u64;
+        if end > total_length {
+            anyhow::bail!(
+                "Out of bounds: offset={} len={} total_length={}",
+                offset,
+                len,
+                total_length
+            );
+        }
+
+        for file in &mut self.files {
+            let file_end = file.offset + file.length;
+            if file_end <= offset || file.offset >= end {
+                continue;
+            }
+            let mmap = match &mut file.mmap {
+                Some(mmap) => mmap,
+                None => continue,
+            };
+
+            let start = offset.max(file.offset);
+            let stop = end.min(file_end);
+            let file_start = (start - file.offset) as usize;
+            let file_stop = (stop - file.offset) as usize;
+            f(
+                mmap,
+                file_start..file_stop,
+                (start - offset) as usize..(stop - offset) as usize,
+            );
+        }
+        Ok(())
+    }
+
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> Result<()> {
+        self.for_each_span(offset, data.len(), |mmap, file_range, data_range| {
+            let len = file_range.len();
+            mmap[file_range.clone()].copy_from_slice(&data[data_range]);
+            let _ = mmap
+                .flush_range(file_range.start, len)
+                .map_err(|err| log::warn!("Failed to flush mmapped file: {}", err));
+        })
+    }
+
+    fn read_at(&mut self, offset: u64, len: usize) -> Result<Vec<u8>> {
+        let mut data = vec![0; len];
+        self.for_each_span(offset, len, |mmap, file_range, data_range| {
+            data[data_range].copy_from_slice(&mmap[file_range]);
+        })?;
+        Ok(data)
+    }
+}
+
+fn map_file(path: &Path, file_length: u64) -> Result<Option<MmapMut>> {
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        std::fs::create_dir_all(dir).with_context(|| {
+            format!("Failed to create directory: path={}", dir.to_string_lossy())
+        })?;
+    }
+
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+        .with_context(|| {
             format!(
-                "Failed to set file length: path={} len={}",
+                "Failed to open file as RW: path={} len={}",
                 path.to_string_lossy(),
                 file_length
             )
         })?;
 
-        let mmap = unsafe {
-            MmapMut::map_mut(&file)
-                .with_context(|| format!("Failed to mmap: path={}", path.to_string_lossy()))?
-        };
+    file.set_len(file_length).with_context(|| {
+        format!(
+            "Failed to set file length: path={} len={}",
+            path.to_string_lossy(),
+            file_length
+        )
+    })?;
 
-        Ok(FileActor { piece_length, mmap })
+    if file_length == 0 {
+        return Ok(None);
     }
+
+    let mmap = unsafe {
+        MmapMut::map_mut(&file)
+            .with_context(|| format!("Failed to mmap: path={}", path.to_string_lossy()))?
+    };
+    Ok(Some(mmap))
 }
 #[cfg(test)]
 mod tests {
@@ -80,13 +193,13 @@ mod tests {
         fs::*,
         message::{Message, BLOCK_LENGTH},
     };
+    use std::time::Duration;
     use std::{env, io::Read};
     use std::{fs::File, io::Seek, io::SeekFrom};
-    use std::{path::PathBuf, time::Duration};
 
     #[actix::test]
     async fn file_should_be_written_to_on_piece_message() {
-        let mut tmp_path = PathBuf::from(env::temp_dir());
+        let mut tmp_path = env::temp_dir();
         tmp_path.push("sharku_file_should_be_written_to_on_piece_message");
 
         let file = OpenOptions::new()
@@ -115,11 +228,33 @@ mod tests {
             let mut f = File::open(&tmp_path).unwrap();
             f.seek(SeekFrom::Start(BLOCK_LENGTH as u64)).unwrap();
             f.read_exact(&mut buf).unwrap();
-            if buf.len() == data.len() && &buf == &data {
+            if buf.len() == data.len() && buf == data {
                 return;
             }
             sleep(Duration::from_millis(10)).await;
         }
-        assert!(false);
+        panic!("File was not written");
+    }
+
+    #[test]
+    fn block_spanning_files_should_be_split() {
+        let mut dir = env::temp_dir();
+        dir.push("sharku_block_spanning_files_should_be_split");
+        let _ = std::fs::remove_dir_all(&dir);
+        let paths = vec![
+            (dir.join("a"), 3),
+            (dir.join("empty"), 0),
+            (dir.join("sub").join("b"), 4),
+        ];
+
+        let mut file_actor = FileActor::with_files(&paths, 4).unwrap();
+        file_actor.write_at(1, &[1, 2, 3, 4, 5]).unwrap();
+        assert!(file_actor.write_at(4, &[0; 4]).is_err());
+        assert_eq!(file_actor.read_at(2, 3).unwrap(), vec![2, 3, 4]);
+        assert!(file_actor.read_at(6, 2).is_err());
+
+        assert_eq!(std::fs::read(&paths[0].0).unwrap(), vec![0, 1, 2]);
+        assert_eq!(std::fs::read(&paths[1].0).unwrap(), Vec::<u8>::new());
+        assert_eq!(std::fs::read(&paths[2].0).unwrap(), vec![3, 4, 5, 0]);
     }
 }
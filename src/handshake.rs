@@ -0,0 +1,235 @@
+use std::collections::HashSet;
+use std::convert::TryInto;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use crate::message::{HANDSHAKE, PEER_ID};
+
+/// Protocol string, reserved bytes, info_hash and peer_id.
+pub const HANDSHAKE_LEN: usize = HANDSHAKE.len() + 20 + 20;
+
+/// Why a peer was rejected during the handshake.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HandshakeError {
+    /// Not the BitTorrent protocol string
+    WrongProtocol,
+    /// The peer serves another torrent
+    InfoHashMismatch,
+    /// We connected to ourselves
+    OwnPeerId,
+    /// Already connected to that peer, maybe over another address
+    DuplicatePeerId,
+}
+
+impl fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HandshakeError::WrongProtocol => write!(f, "Wrong protocol in handshake"),
+            HandshakeError::InfoHashMismatch => write!(f, "Wrong info_hash in handshake"),
+            HandshakeError::OwnPeerId => write!(f, "Connected to ourselves"),
+            HandshakeError::DuplicatePeerId => write!(f, "Already connected to that peer"),
+        }
+    }
+}
+
+impl std::error::Error for HandshakeError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerHandshake {
+    /// Bits advertising protocol extensions
+    pub reserved: [u8; 8],
+    pub info_hash: [u8; 20],
+    pub peer_id: [u8; 20],
+}
+
+impl PeerHandshake {
+    /// The handshake we send for a torrent.
+    pub fn ours(info_hash: [u8; 20]) -> Self {
+        PeerHandshake {
+            reserved: HANDSHAKE[20..].try_into().unwrap(),
+            info_hash,
+            peer_id: *PEER_ID,
+        }
+    }
+
+    pub fn parse(buf: &[u8; HANDSHAKE_LEN]) -> Result<Self, HandshakeError> {
+        if buf[..20] != HANDSHAKE[..20] {
+            return Err(HandshakeError::WrongProtocol);
+        }
+        Ok(PeerHandshake {
+            reserved: buf[20..28].try_into().unwrap(),
+            info_hash: buf[28..48].try_into().unwrap(),
+            peer_id: buf[48..].try_into().unwrap(),
+        })
+    }
+
+    pub fn to_bytes(&self) -> [u8; HANDSHAKE_LEN] {
+        let mut buf = [0u8; HANDSHAKE_LEN];
+        buf[..20].copy_from_slice(&HANDSHAKE[..20]);
+        buf[20..28].copy_from_slice(&self.reserved);
+        buf[28..48].copy_from_slice(&self.info_hash);
+        buf[48..].copy_from_slice(&self.peer_id);
+        buf
+    }
+
+    /// The peer supports the extension protocol (BEP 10).
+    pub fn supports_extensions(&self) -> bool {
+        self.reserved[5] & 0x10 != 0
+    }
+
+    /// Check the handshake of a peer we talk to about `info_hash`.
+    pub fn check(&self, info_hash: &[u8; 20]) -> Result<(), HandshakeError> {
+        if &self.info_hash != info_hash {
+            return Err(HandshakeError::InfoHashMismatch);
+        }
+        if &self.peer_id == PEER_ID {
+            return Err(HandshakeError::OwnPeerId);
+        }
+        Ok(())
+    }
+
+    /// Client and version, decoded from the peer_id if it follows a known convention.
+    pub fn client_name(&self) -> String {
+        let id = &self.peer_id;
+        // Azureus style: `-TR3000-` followed by random bytes
+        if id[0] == b'-' && id[7] == b'-' && id[1..7].iter().all(u8::is_ascii_alphanumeric) {
+            let code = std::str::from_utf8(&id[1..3]).unwrap();
+            let version: Vec<String> = id[3..7].iter().map(|&c| (c as char).to_string()).collect();
+            return format!("{} {}", azureus_client(code), version.join("."));
+        }
+        // Mainline style: `M7-2-2--` followed by random bytes
+        if id[0] == b'M' {
+            if let Some(version) = id[1..8]
+                .split(|&c| c == b'-')
+                .filter(|part| !part.is_empty())
+                .map(|part| {
+                    std::str::from_utf8(part)
+                        .ok()
+                        .filter(|v| v.parse::<u8>().is_ok())
+                })
+                .collect::<Option<Vec<_>>>()
+                .filter(|parts| parts.len() == 3)
+            {
+                return format!("BitTorrent {}", version.join("."));
+            }
+        }
+        "Unknown".to_owned()
+    }
+}
+
+fn azureus_client(code: &str) -> &str {
+    match code {
+        "AZ" => "Vuze",
+        "BC" => "BitComet",
+        "BT" => "BitTorrent",
+        "DE" => "Deluge",
+        "KT" => "KTorrent",
+        "LT" => "libtorrent",
+        "lt" => "rTorrent",
+        "qB" => "qBittorrent",
+        "TR" => "Transmission",
+        "UT" => "µTorrent",
+        "WW" => "WebTorrent",
+        _ => code,
+    }
+}
+
+/// Peer ids of the peers connected for a torrent, shared by the incoming and outgoing connections.
+#[derive(Debug, Clone, Default)]
+pub struct ConnectedPeerIds(Arc<Mutex<HashSet<[u8; 20]>>>);
+
+impl ConnectedPeerIds {
+    /// Record a connection to `peer_id` until the guard is dropped, or fail if there is already
+    /// one.
+    pub fn register(&self, peer_id: [u8; 20]) -> Result<PeerIdGuard, HandshakeError> {
+        if !self.0.lock().unwrap().insert(peer_id) {
+            return Err(HandshakeError::DuplicatePeerId);
+        }
+        Ok(PeerIdGuard {
+            peer_ids: self.clone(),
+            peer_id,
+        })
+    }
+}
+
+pub struct PeerIdGuard {
+    peer_ids: ConnectedPeerIds,
+    peer_id: [u8; 20],
+}
+
+impl Drop for PeerIdGuard {
+    fn drop(&mut self) {
+        self.peer_ids.0.lock().unwrap().remove(&self.peer_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::handshake::{ConnectedPeerIds, HandshakeError, PeerHandshake};
+    use crate::message::{HANDSHAKE, PEER_ID};
+
+    fn theirs(peer_id: &[u8; 20]) -> PeerHandshake {
+        PeerHandshake {
+            reserved: [0, 0, 0, 0, 0, 0x10, 0, 0x01],
+            info_hash: [7; 20],
+            peer_id: *peer_id,
+        }
+    }
+
+    #[test]
+    fn handshake_should_round_trip() {
+        let handshake = theirs(b"-TR3000-123456789012");
+        let bytes = handshake.to_bytes();
+        assert_eq!(&bytes[..20], &HANDSHAKE[..20]);
+        assert_eq!(PeerHandshake::parse(&bytes), Ok(handshake));
+
+        let mut bytes = bytes;
+        bytes[1] = b'b';
+        assert_eq!(
+            PeerHandshake::parse(&bytes),
+            Err(HandshakeError::WrongProtocol)
+        );
+    }
+
+    #[test]
+    fn handshake_should_be_rejected_for_other_torrent_or_ourselves() {
+        let handshake = theirs(b"-TR3000-123456789012");
+        assert_eq!(handshake.check(&[7; 20]), Ok(()));
+        assert_eq!(
+            handshake.check(&[8; 20]),
+            Err(HandshakeError::InfoHashMismatch)
+        );
+        assert_eq!(
+            theirs(PEER_ID).check(&[7; 20]),
+            Err(HandshakeError::OwnPeerId)
+        );
+    }
+
+    #[test]
+    fn client_name_should_be_decoded() {
+        assert_eq!(
+            theirs(b"-TR3000-123456789012").client_name(),
+            "Transmission 3.0.0.0"
+        );
+        assert_eq!(theirs(b"-XX12ab-123456789012").client_name(), "XX 1.2.a.b");
+        assert_eq!(
+            theirs(b"M7-10-2--12345678901").client_name(),
+            "BitTorrent 7.10.2"
+        );
+        assert_eq!(theirs(PEER_ID).client_name(), "Unknown");
+        assert_eq!(theirs(&[0xff; 20]).client_name(), "Unknown");
+    }
+
+    #[test]
+    fn peer_id_should_only_be_connected_once() {
+        let peer_ids = ConnectedPeerIds::default();
+        let guard = peer_ids.register([1; 20]).unwrap();
+        assert_eq!(
+            peer_ids.register([1; 20]).err(),
+            Some(HandshakeError::DuplicatePeerId)
+        );
+        assert!(peer_ids.register([2; 20]).is_ok());
+        drop(guard);
+        assert!(peer_ids.register([1; 20]).is_ok());
+    }
+}
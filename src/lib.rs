@@ -1,8 +1,18 @@
+pub mod choker;
+pub mod codec;
+pub mod dht;
+pub mod extension;
 pub mod fs;
+pub mod handshake;
+pub mod lsd;
+pub mod magnet;
 pub mod message;
+pub mod metadata;
 pub mod net;
 pub mod peer;
+pub mod pex;
 pub mod pieces;
+pub mod pipeline;
 pub mod state;
 pub mod torrent_file;
 pub mod tracker;
@@ -0,0 +1,257 @@
+use anyhow::{bail, Context, Result};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio::time;
+
+use crate::magnet::decode_info_hash;
+use crate::tracker::Peer;
+
+/// Multicast groups of the announces (BEP 14).
+pub const LSD_GROUP_V4: SocketAddr =
+    SocketAddr::new(IpAddr::V4(Ipv4Addr::new(239, 192, 152, 143)), 6771);
+pub const LSD_GROUP_V6: SocketAddr = SocketAddr::new(
+    IpAddr::V6(Ipv6Addr::new(0xff15, 0, 0, 0, 0, 0, 0xefc0, 0x988f)),
+    6771,
+);
+/// We announce each torrent that often, below the once a minute limit of BEP 14.
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+const MAX_PACKET_LEN: usize = 1500;
+
+/// A BT-SEARCH message, sent to the LAN by a client looking for peers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LsdAnnounce {
+    /// Port the sender listens on for peer connections
+    pub port: u16,
+    pub info_hashes: Vec<[u8; 20]>,
+    /// Lets the sender recognize its own announces
+    pub cookie: Option<String>,
+}
+
+impl LsdAnnounce {
+    pub fn parse(packet: &[u8]) -> Result<Self> {
+        let text = std::str::from_utf8(packet).context("Announce is not text")?;
+        let mut lines = text.lines().map(|line| line.trim_end_matches('\r'));
+        if lines.next() != Some("BT-SEARCH * HTTP/1.1") {
+            bail!("Not a BT-SEARCH announce");
+        }
+        let mut port = None;
+        let mut info_hashes = Vec::new();
+        let mut cookie = None;
+        for line in lines.take_while(|line| !line.is_empty()) {
+            let (name, value) = line.split_once(':').context("Invalid header")?;
+            let value = value.trim();
+            match name.trim().to_ascii_lowercase().as_str() {
+                "port" => port = Some(value.parse::<u16>().context("Invalid port")?),
+                // BitTorrent v2 hashes are longer
+                "infohash" if value.len() == 40 => info_hashes.push(decode_info_hash(value)?),
+                "cookie" => cookie = Some(value.to_owned()),
+                _ => {}
+            }
+        }
+        Ok(LsdAnnounce {
+            port: port.filter(|&port| port != 0).context("Missing port")?,
+            info_hashes,
+            cookie,
+        })
+    }
+
+    /// The announce, for the multicast `group` it is sent to.
+    pub fn encode(&self, group: &SocketAddr) -> Vec<u8> {
+        let mut text = format!(
+            "BT-SEARCH * HTTP/1.1\r\nHost: {}\r\nPort: {}\r\n",
+            group, self.port
+        );
+        for info_hash in &self.info_hashes {
+            let hex: String = info_hash.iter().map(|b| format!("{:02x}", b)).collect();
+            text.push_str(&format!("Infohash: {}\r\n", hex));
+        }
+        if let Some(cookie) = &self.cookie {
+            text.push_str(&format!("cookie: {}\r\n", cookie));
+        }
+        text.push_str("\r\n\r\n");
+        text.into_bytes()
+    }
+
+    /// The peer that sent the announce from `from`, if it is about `info_hash` and not ours.
+    fn peer(&self, from: &SocketAddr, info_hash: &[u8; 20], cookie: &str) -> Option<Peer> {
+        if self.cookie.as_deref() == Some(cookie) || !self.info_hashes.contains(info_hash) {
+            return None;
+        }
+        Some(Peer {
+            ip: from.ip(),
+            port: self.port,
+        })
+    }
+}
+
+/// Announce a torrent to the LAN over IPv4 and IPv6, and hand the peers that announce it too to
+/// the connection code (BEP 14).
+pub async fn local_discovery(
+    info_hash: [u8; 20],
+    port: u16,
+    peers: mpsc::UnboundedSender<Vec<Peer>>,
+) {
+    let cookie = format!("sharku-{:08x}", rand::random::<u32>());
+    let discoveries = [LSD_GROUP_V4, LSD_GROUP_V6].map(|group| {
+        let peers = peers.clone();
+        let cookie = cookie.clone();
+        async move {
+            let res = match bind_multicast(&group) {
+                Ok(socket) => discover(socket, group, info_hash, port, &cookie, peers).await,
+                Err(err) => Err(err),
+            };
+            if let Err(err) = res {
+                log::warn!("Local service discovery failed on {}: {:#}", group, err);
+            }
+        }
+    });
+    futures_util::future::join_all(discoveries).await;
+}
+
+/// A socket on the port of the group, joined to it. Other clients on the machine can use it too.
+fn bind_multicast(group: &SocketAddr) -> Result<UdpSocket> {
+    let socket = Socket::new(
+        Domain::for_address(*group),
+        Type::DGRAM,
+        Some(Protocol::UDP),
+    )?;
+    socket.set_reuse_address(true)?;
+    let addr = match group.ip() {
+        IpAddr::V4(ip) => {
+            socket.join_multicast_v4(&ip, &Ipv4Addr::UNSPECIFIED)?;
+            socket.set_multicast_loop_v4(true)?;
+            SocketAddr::from((Ipv4Addr::UNSPECIFIED, group.port()))
+        }
+        IpAddr::V6(ip) => {
+            socket.set_only_v6(true)?;
+            socket.join_multicast_v6(&ip, 0)?;
+            socket.set_multicast_loop_v6(true)?;
+            SocketAddr::from((Ipv6Addr::UNSPECIFIED, group.port()))
+        }
+    };
+    socket
+        .bind(&addr.into())
+        .with_context(|| format!("Failed to bind {}", addr))?;
+    socket.set_nonblocking(true)?;
+    Ok(UdpSocket::from_std(socket.into())?)
+}
+
+async fn discover(
+    socket: UdpSocket,
+    group: SocketAddr,
+    info_hash: [u8; 20],
+    port: u16,
+    cookie: &str,
+    peers: mpsc::UnboundedSender<Vec<Peer>>,
+) -> Result<()> {
+    let announce = LsdAnnounce {
+        port,
+        info_hashes: vec![info_hash],
+        cookie: Some(cookie.to_owned()),
+    }
+    .encode(&group);
+    let mut interval = time::interval(ANNOUNCE_INTERVAL);
+    let mut buf = [0u8; MAX_PACKET_LEN];
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                // E.g. no network yet: try again next time
+                if let Err(err) = socket.send_to(&announce, group).await {
+                    log::debug!("Failed to send announce to {}: {}", group, err);
+                }
+            }
+            received = socket.recv_from(&mut buf) => {
+                let (len, from) = received.with_context(|| "Failed to receive announce")?;
+                let peer = match LsdAnnounce::parse(&buf[..len]) {
+                    Ok(announce) => announce.peer(&from, &info_hash, cookie),
+                    Err(err) => {
+                        log::debug!("Invalid announce from {}: {:#}", from, err);
+                        continue;
+                    }
+                };
+                if let Some(peer) = peer {
+                    log::debug!("Local peer: {:?}", peer);
+                    if peers.send(vec![peer]).is_err() {
+                        return Ok(());
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use crate::lsd::{LsdAnnounce, LSD_GROUP_V4, LSD_GROUP_V6};
+    use crate::tracker::Peer;
+
+    #[test]
+    fn announce_should_round_trip() {
+        let announce = LsdAnnounce {
+            port: 6881,
+            info_hashes: vec![[0xab; 20], [0x01; 20]],
+            cookie: Some("sharku-1234".to_owned()),
+        };
+        let bytes = announce.encode(&LSD_GROUP_V4);
+        assert!(bytes.starts_with(
+            b"BT-SEARCH * HTTP/1.1\r\nHost: 239.192.152.143:6771\r\nPort: 6881\r\nInfohash: abab"
+        ));
+        assert_eq!(LsdAnnounce::parse(&bytes).unwrap(), announce);
+        assert!(std::str::from_utf8(&announce.encode(&LSD_GROUP_V6))
+            .unwrap()
+            .contains("Host: [ff15::efc0:988f]:6771\r\n"));
+    }
+
+    #[test]
+    fn announces_of_other_clients_should_be_parsed() {
+        let announce = LsdAnnounce::parse(
+            b"BT-SEARCH * HTTP/1.1\r\nHost: 239.192.152.143:6771\r\nport: 51413\r\n\
+              INFOHASH: C9E15763F722F23E98A29DECDFAE341B98D53056\r\n\
+              Infohash: 00112233445566778899aabbccddeeff00112233445566778899aabbccddeeff\r\n\r\n\r\n",
+        )
+        .unwrap();
+        assert_eq!(announce.port, 51413);
+        assert_eq!(announce.info_hashes.len(), 1);
+        assert_eq!(announce.info_hashes[0][0], 0xc9);
+        assert_eq!(announce.cookie, None);
+
+        assert!(LsdAnnounce::parse(b"M-SEARCH * HTTP/1.1\r\n\r\n").is_err());
+        assert!(LsdAnnounce::parse(b"BT-SEARCH * HTTP/1.1\r\nPort: 0\r\n\r\n").is_err());
+        assert!(LsdAnnounce::parse(
+            b"BT-SEARCH * HTTP/1.1\r\nPort: 6881\r\n\
+              Infohash: zzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzz\r\n\r\n"
+        )
+        .is_err());
+        // 40 bytes, not 40 characters
+        assert!(LsdAnnounce::parse(
+            "BT-SEARCH * HTTP/1.1\r\nPort: 6881\r\n\
+             Infohash: a\u{e9}aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa\r\n\r\n"
+                .as_bytes()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn only_other_peers_of_the_torrent_should_be_kept() {
+        let from: SocketAddr = "192.168.1.10:6771".parse().unwrap();
+        let announce = LsdAnnounce {
+            port: 51413,
+            info_hashes: vec![[1; 20]],
+            cookie: Some("theirs".to_owned()),
+        };
+        assert_eq!(
+            announce.peer(&from, &[1; 20], "ours"),
+            Some(Peer {
+                ip: "192.168.1.10".parse().unwrap(),
+                port: 51413
+            })
+        );
+        assert_eq!(announce.peer(&from, &[2; 20], "ours"), None);
+        assert_eq!(announce.peer(&from, &[1; 20], "theirs"), None);
+    }
+}
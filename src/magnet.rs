@@ -0,0 +1,240 @@
+use anyhow::{Context, Result};
+use std::convert::TryInto;
+use std::net::SocketAddr;
+
+use crate::torrent_file::{decode_torrent, Torrent};
+
+/// What a `magnet:` URI tells about a torrent: enough to find peers and ask them for the info
+/// dictionary.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Magnet {
+    pub info_hash: [u8; 20],
+    /// Display name (`dn`)
+    pub name: Option<String>,
+    /// Tracker URLs (`tr`)
+    pub trackers: Vec<String>,
+    /// Peer addresses (`x.pe`)
+    pub peers: Vec<SocketAddr>,
+}
+
+impl Magnet {
+    pub fn parse(uri: &str) -> Result<Self> {
+        let query = uri.strip_prefix("magnet:?").context("Not a magnet URI")?;
+        let mut info_hash = None;
+        let mut name = None;
+        let mut trackers = Vec::new();
+        let mut peers = Vec::new();
+        for param in query.split('&').filter(|param| !param.is_empty()) {
+            let (key, value) = param.split_once('=').unwrap_or((param, ""));
+            let value = percent_decode(value)
+                .with_context(|| format!("Invalid value in magnet URI: {}", key))?;
+            match key {
+                // Other hashes (e.g. `urn:btmh:` for v2) are not supported
+                "xt" if info_hash.is_none() => {
+                    if let Some(hash) = value.strip_prefix("urn:btih:") {
+                        info_hash = Some(decode_info_hash(hash)?);
+                    }
+                }
+                "dn" => name = Some(value),
+                "tr" => trackers.push(value),
+                "x.pe" => match value.parse() {
+                    Ok(addr) => peers.push(addr),
+                    Err(_) => log::warn!("Ignoring invalid peer in magnet URI: {}", value),
+                },
+                _ => {}
+            }
+        }
+        Ok(Magnet {
+            info_hash: info_hash.context("Missing urn:btih: info_hash in magnet URI")?,
+            name,
+            trackers,
+            peers,
+        })
+    }
+
+    /// Build the torrent from the info dictionary received from peers, with the trackers of the
+    /// magnet.
+    pub fn torrent(&self, info: &[u8]) -> Result<Torrent> {
+        let mut content = b"d".to_vec();
+        if let Some(first) = self.trackers.first() {
+            content.extend_from_slice(b"8:announce");
+            push_string(&mut content, first);
+            content.extend_from_slice(b"13:announce-listl");
+            for tracker in &self.trackers {
+                content.push(b'l');
+                push_string(&mut content, tracker);
+                content.push(b'e');
+            }
+            content.push(b'e');
+        }
+        content.extend_from_slice(b"4:info");
+        content.extend_from_slice(info);
+        content.push(b'e');
+        decode_torrent(&content)
+    }
+}
+
+fn push_string(content: &mut Vec<u8>, s: &str) {
+    content.extend_from_slice(format!("{}:{}", s.len(), s).as_bytes());
+}
+
+/// 40 hexadecimal or 32 base32 characters.
+pub(crate) fn decode_info_hash(hash: &str) -> Result<[u8; 20]> {
+    let bytes = match hash.len() {
+        40 => hash
+            .as_bytes()
+            .chunks(2)
+            .map(|pair| Some(hex_digit(pair[0])? << 4 | hex_digit(pair[1])?))
+            .collect::<Option<Vec<u8>>>(),
+        32 => decode_base32(hash),
+        _ => None,
+    };
+    bytes
+        .and_then(|bytes| bytes.try_into().ok())
+        .with_context(|| format!("Invalid info_hash in magnet URI: {}", hash))
+}
+
+fn hex_digit(c: u8) -> Option<u8> {
+    (c as char).to_digit(16).map(|digit| digit as u8)
+}
+
+/// RFC 4648 base32, without padding.
+fn decode_base32(s: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(s.len() * 5 / 8);
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    for c in s.bytes() {
+        let value = match c.to_ascii_uppercase() {
+            c @ b'A'..=b'Z' => c - b'A',
+            c @ b'2'..=b'7' => c - b'2' + 26,
+            _ => return None,
+        };
+        buffer = (buffer << 5) | value as u32;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+        }
+    }
+    Some(bytes)
+}
+
+fn percent_decode(s: &str) -> Result<String> {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut rest = s.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        match b {
+            b'%' if tail.len() >= 2 => {
+                let (high, low) = hex_digit(tail[0])
+                    .zip(hex_digit(tail[1]))
+                    .context("Invalid percent-encoding")?;
+                bytes.push(high << 4 | low);
+                rest = &tail[2..];
+            }
+            b'%' => anyhow::bail!("Truncated percent-encoding"),
+            b'+' => {
+                bytes.push(b' ');
+                rest = tail;
+            }
+            _ => {
+                bytes.push(b);
+                rest = tail;
+            }
+        }
+    }
+    Ok(String::from_utf8(bytes)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use crate::magnet::Magnet;
+    use crate::torrent_file::decode_torrent_from_file;
+    use crate::tracker::info_hash;
+
+    const HASH: [u8; 20] = [
+        0xc9, 0xe1, 0x57, 0x63, 0xf7, 0x22, 0xf2, 0x3e, 0x98, 0xa2, 0x9d, 0xec, 0xdf, 0xae, 0x34,
+        0x1b, 0x98, 0xd5, 0x30, 0x56,
+    ];
+
+    #[test]
+    fn magnet_should_be_parsed() {
+        let magnet = Magnet::parse(
+            "magnet:?xt=urn:btih:c9e15763f722f23e98a29decdfae341b98d53056&dn=Cosmos+Laundromat\
+             &tr=udp%3A%2F%2Fexplodie.org%3A6969&tr=http%3A%2F%2Ftracker.example%2Fannounce\
+             &x.pe=10.0.0.1:6881&x.pe=%5B%3A%3A1%5D%3A51413&x.pe=not-an-address&ws=ignored",
+        )
+        .unwrap();
+        assert_eq!(
+            magnet,
+            Magnet {
+                info_hash: HASH,
+                name: Some("Cosmos Laundromat".to_owned()),
+                trackers: vec![
+                    "udp://explodie.org:6969".to_owned(),
+                    "http://tracker.example/announce".to_owned()
+                ],
+                peers: vec![
+                    "10.0.0.1:6881".parse::<SocketAddr>().unwrap(),
+                    "[::1]:51413".parse::<SocketAddr>().unwrap()
+                ],
+            }
+        );
+    }
+
+    #[test]
+    fn base32_info_hash_should_be_decoded() {
+        let magnet = Magnet::parse("magnet:?xt=urn:btih:ZHQVOY7XELZD5GFCTXWN7LRUDOMNKMCW").unwrap();
+        assert_eq!(magnet.info_hash, HASH);
+        assert!(magnet.trackers.is_empty());
+
+        assert!(Magnet::parse("magnet:?dn=nohash").is_err());
+        assert!(Magnet::parse("magnet:?xt=urn:btih:c9e157").is_err());
+        assert!(Magnet::parse("magnet:?xt=urn:btih:ZHQVOY7XELZD5GFCTXWN7LRUDOMNKM01").is_err());
+        assert!(Magnet::parse("http://example.com").is_err());
+    }
+
+    #[test]
+    fn invalid_percent_encoding_should_be_rejected() {
+        let uri = "magnet:?xt=urn:btih:c9e15763f722f23e98a29decdfae341b98d53056&dn=";
+        assert_eq!(
+            Magnet::parse(&format!("{}%41%2b", uri)).unwrap().name,
+            Some("A+".to_owned())
+        );
+        for name in ["%+f", "%f", "%\u{e9}", "%zz"] {
+            assert!(
+                Magnet::parse(&format!("{}{}", uri, name)).is_err(),
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn info_hash_of_40_bytes_but_not_hexadecimal_should_be_rejected() {
+        // 40 bytes once decoded, but only 39 characters
+        let uri = format!("magnet:?xt=urn:btih:a%C3%A9{}", "a".repeat(37));
+        assert!(Magnet::parse(&uri).is_err());
+        let uri = format!("magnet:?xt=urn:btih:+a{}", "a".repeat(38));
+        assert!(Magnet::parse(&uri).is_err());
+    }
+
+    #[test]
+    fn torrent_should_be_built_from_the_info_dictionary() {
+        let original = decode_torrent_from_file(std::path::Path::new("debian.torrent")).unwrap();
+        let magnet = Magnet {
+            info_hash: info_hash(&original),
+            name: None,
+            trackers: vec!["http://tracker.example/announce".to_owned()],
+            peers: Vec::new(),
+        };
+        let torrent = magnet.torrent(&original.info_bytes).unwrap();
+        assert_eq!(info_hash(&torrent), magnet.info_hash);
+        assert_eq!(torrent.info.name, original.info.name);
+        assert_eq!(
+            torrent.announce_tiers(),
+            vec![vec!["http://tracker.example/announce".to_owned()]]
+        );
+    }
+}
@@ -1,61 +1,219 @@
 use actix::prelude::*;
-use anyhow::bail;
+use sharku::choker::*;
+use sharku::dht::*;
+use sharku::extension::*;
 use sharku::fs::*;
+use sharku::handshake::*;
+use sharku::lsd::*;
+use sharku::magnet::*;
+use sharku::metadata::*;
 use sharku::net::*;
+use sharku::pex::*;
 use sharku::pieces::*;
-use sharku::state::*;
+use sharku::pipeline::*;
 use sharku::torrent_file::*;
 use sharku::tracker::*;
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddr};
 use std::sync::Arc;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::task::JoinHandle;
 
 use anyhow::{Context, Result};
-use std::path::{Path, PathBuf};
+use std::path::PathBuf;
 
 #[actix::main]
 async fn main() -> Result<()> {
     env_logger::init();
 
-    let torrent_file_path = PathBuf::from("debian.torrent");
-    let torrent = Arc::from(decode_torrent_from_file(&torrent_file_path)?);
+    let port: u16 = 6881;
+    let (peers_tx, mut peers_rx) = tokio::sync::mpsc::unbounded_channel();
+    // Our DHT id and nodes are kept across runs
+    let dht_path = PathBuf::from("dht.dat");
+    let (dht_id, dht_nodes) = Dht::load(&dht_path).unwrap_or_else(|err| {
+        log::debug!("Starting a new DHT node: {:#}", err);
+        (rand::random(), Vec::new())
+    });
+    // Bound only when the torrent is public
+    let mut dht = None;
+    let mut dht_bootstrap: Vec<String> = dht_nodes.iter().map(ToString::to_string).collect();
+    if dht_bootstrap.is_empty() {
+        dht_bootstrap.extend(DEFAULT_BOOTSTRAP.iter().map(ToString::to_string));
+    }
+    // A torrent file or a magnet link
+    let source = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| "debian.torrent".to_owned());
+    let torrent = if source.starts_with("magnet:") {
+        let magnet = Magnet::parse(&source)?;
+        log::info!("Fetching metadata: name={:?}", magnet.name);
+        let peers = magnet.peers.iter().map(|addr| Peer {
+            ip: addr.ip(),
+            port: addr.port(),
+        });
+        peers_tx.send(peers.collect())?;
+        if !magnet.trackers.is_empty() {
+            let mut tiers = TrackerTiers::from_tiers(
+                magnet
+                    .trackers
+                    .iter()
+                    .map(|url| vec![url.clone()])
+                    .collect(),
+            )?;
+            let peers_tx = peers_tx.clone();
+            let info_hash = magnet.info_hash;
+            tokio::spawn(async move {
+                let clients = TrackerClients::default();
+                match announce_info_hash(&clients, &mut tiers, port, &info_hash).await {
+                    Ok(peers) => {
+                        let _ = peers_tx.send(peers);
+                    }
+                    Err(err) => log::warn!("Failed to announce: {:#}", err),
+                }
+            });
+        }
+        let discovery =
+            discover_peers(dht_id, magnet.info_hash, port, dht_bootstrap, &peers_tx).await;
+        let metadata = SharedMetadata::fetching(magnet.info_hash);
+        let (info, peers) = fetch_metadata(&mut peers_rx, metadata, port, 8).await?;
+        // Connected to again for the download
+        peers_tx.send(peers)?;
+        let torrent = magnet.torrent(&info)?;
+        // Only known to be private now: its peers must come from its trackers (BEP 27)
+        if torrent.info.is_private() {
+            log::info!("Private torrent, stopping the DHT and local service discovery");
+            discovery.stop();
+        } else {
+            dht = discovery.dht;
+        }
+        torrent
+    } else {
+        let torrent = decode_torrent_from_file(&PathBuf::from(source))?;
+        if !torrent.info.is_private() {
+            dht_bootstrap.extend(torrent.nodes());
+            let info_hash = info_hash(&torrent);
+            dht = discover_peers(dht_id, info_hash, port, dht_bootstrap, &peers_tx)
+                .await
+                .dht;
+        }
+        torrent
+    };
+    let torrent = Arc::new(torrent);
     log::debug!("Torrent: {:#?}", torrent);
 
-    let _pieces_actor_addr =
-        PiecesActor::new(torrent.info.pieces_count(), torrent.info.piece_length).start();
+    let file_paths = torrent
+        .info
+        .file_paths()
+        .context("Invalid files in torrent file")?;
+    let file_actor_addr = FileActor::with_files(&file_paths, torrent.info.piece_length)?.start();
 
-    let file_path = Path::new(&torrent.info.name);
+    let pieces_actor_addr = PiecesActor::new(&torrent.info, file_actor_addr).start();
+    let choker_addr = ChokerActor::new(4, pieces_actor_addr.clone()).start();
 
-    let file_length: u64 = match torrent.info.length {
-        Some(length) => length as u64,
-        None => bail!("Missing file length in torrent file"),
-    };
+    let info_hash = info_hash(&torrent);
+    let metadata = SharedMetadata::known(info_hash, torrent.info_bytes.clone());
+    let ut_metadata: ExtensionFactory =
+        Arc::new(move |_, _| Box::new(UtMetadata::new(metadata.clone())) as Box<dyn Extension>);
+    let mut extensions = vec![ut_metadata];
+    // Peers of a private torrent only come from its trackers
+    if !torrent.info.is_private() {
+        let swarm = PexSwarm::default();
+        let pex_peers = peers_tx.clone();
+        let ut_pex: ExtensionFactory = Arc::new(move |addr, incoming| {
+            let pex = UtPex::new(swarm.clone(), addr, incoming, pex_peers.clone());
+            Box::new(pex) as Box<dyn Extension>
+        });
+        extensions.push(ut_pex);
+    }
 
-    let _file_actor_addr =
-        FileActor::new(&file_path, file_length, torrent.info.piece_length)?.start();
+    let listeners = bind_listeners(port)?;
+    let served = ServedTorrent {
+        torrent: torrent.clone(),
+        pieces_actor: pieces_actor_addr.clone(),
+        choker: choker_addr,
+        pipeline: PipelineConfig::default(),
+        peer_ids: ConnectedPeerIds::default(),
+        port,
+        extensions,
+    };
+    let mut served_torrents = HashMap::new();
+    served_torrents.insert(info_hash, served.clone());
+    let served_torrents = Arc::new(served_torrents);
+    for listener in listeners {
+        tokio::spawn(accept_peers(listener, served_torrents.clone()));
+    }
 
-    let client = reqwest::Client::new();
-    let download_state = DownloadState {
-        left: torrent.info.length.unwrap_or(0) as usize,
-        ..DownloadState::default()
+    tokio::spawn(connect_peers(peers_rx, served, info_hash, 8));
+    // A magnet link may come without trackers
+    let tracker_addr = if torrent.announce_tiers().is_empty() {
+        log::warn!("No trackers, only the known peers are used");
+        None
+    } else {
+        Some(
+            TrackerActor::new(
+                &torrent,
+                info_hash,
+                port,
+                pieces_actor_addr,
+                peers_tx.clone(),
+            )?
+            .start(),
+        )
     };
-    let port: u16 = 6881;
-    let info_hash = info_hash(&torrent)?;
-    let peers = tracker_start(client, &torrent, &download_state, port, &info_hash)
+
+    tokio::signal::ctrl_c()
         .await
-        .context("Failed to start download with tracker")?;
-
-    // FIXME
-    for (i, peer) in peers.into_iter().take(8).enumerate() {
-        let torrent = torrent.clone();
-        tokio::spawn(async move {
-            let addr = Arc::new(format!("{}:{}", peer.ip, peer.port));
-            let _ = peer_talk(torrent, i, info_hash, addr.clone())
-                .await
-                .map_err(|err| {
-                    log::warn!("{}: Err: {}", &addr, err);
-                });
-        });
+        .context("Failed to wait for Ctrl-C")?;
+    if let Some(tracker_addr) = tracker_addr {
+        tracker_addr.send(StopAnnouncing).await?;
+    }
+    if let Some(dht) = dht {
+        if let Err(err) = dht.save(&dht_path) {
+            log::warn!("{:#}", err);
+        }
     }
-    let notify = tokio::sync::Notify::new();
-    notify.notified().await;
     Ok(())
 }
+
+/// Peers looked up without trackers, on the DHT and on the LAN.
+struct Discovery {
+    /// Our DHT node, none if it could not be bound
+    dht: Option<Dht>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl Discovery {
+    /// Stop looking up and announcing, and close the DHT node.
+    fn stop(self) {
+        self.tasks.iter().for_each(JoinHandle::abort);
+    }
+}
+
+/// Find peers without trackers, on the DHT and on the LAN.
+async fn discover_peers(
+    dht_id: [u8; 20],
+    info_hash: [u8; 20],
+    port: u16,
+    dht_bootstrap: Vec<String>,
+    peers_tx: &UnboundedSender<Vec<Peer>>,
+) -> Discovery {
+    let lsd = tokio::spawn(local_discovery(info_hash, port, peers_tx.clone()));
+    let mut tasks = vec![lsd];
+    let dht = match Dht::bind(SocketAddr::from((Ipv4Addr::UNSPECIFIED, port)), dht_id).await {
+        Ok(dht) => {
+            tasks.push(tokio::spawn(find_peers(
+                dht.clone(),
+                info_hash,
+                port,
+                dht_bootstrap,
+                peers_tx.clone(),
+            )));
+            Some(dht)
+        }
+        Err(err) => {
+            log::warn!("Continuing without the DHT: {:#}", err);
+            None
+        }
+    };
+    Discovery { dht, tasks }
+}
@@ -1,9 +1,18 @@
 use bit_vec::BitVec;
 
 pub const PEER_ID: &[u8; 20] = b"unpetitnuagebleuvert";
-pub const HANDSHAKE: &[u8; 28] = b"\x13BitTorrent protocol\x00\x00\x00\x00\x00\x00\x00\x00";
+/// Protocol string and reserved bytes, with the bit of the extension protocol set.
+pub const HANDSHAKE: &[u8; 28] = b"\x13BitTorrent protocol\x00\x00\x00\x00\x00\x10\x00\x00";
 pub const BLOCK_LENGTH: u32 = 16384;
 
+/// A block of a piece, as requested from a peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Block {
+    pub index: u32,
+    pub begin: u32,
+    pub length: u32,
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum MessageKind {
     Choke = 0,
@@ -15,10 +24,13 @@ pub enum MessageKind {
     Request = 6,
     Piece = 7,
     Cancel = 8,
+    Extended = 20,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Message {
+    /// Empty message, sent so that an idle connection is not dropped
+    KeepAlive,
     Choke,
     Unchoke,
     Interested,
@@ -40,11 +52,18 @@ pub enum Message {
         begin: u32,
         length: u32,
     },
+    /// Extension protocol message, `id` 0 being the extension handshake
+    Extended {
+        id: u8,
+        payload: Vec<u8>,
+    },
 }
 
 impl Message {
-    pub fn tag(&self) -> MessageKind {
-        match &self {
+    /// The message id, none for a keep-alive.
+    pub fn tag(&self) -> Option<MessageKind> {
+        let kind = match &self {
+            Message::KeepAlive => return None,
             Message::Choke => MessageKind::Choke,
             Message::Unchoke => MessageKind::Unchoke,
             Message::Interested => MessageKind::Interested,
@@ -54,7 +73,9 @@ impl Message {
             Message::Request { .. } => MessageKind::Request,
             Message::Piece { .. } => MessageKind::Piece,
             Message::Cancel { .. } => MessageKind::Cancel,
-        }
+            Message::Extended { .. } => MessageKind::Extended,
+        };
+        Some(kind)
     }
 }
 
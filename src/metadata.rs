@@ -0,0 +1,524 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+use crate::extension::{Extension, ExtensionHandshake};
+use crate::torrent_file::skip_bencode_value;
+
+/// The info dictionary is exchanged in pieces of that size, the last one being shorter.
+pub const METADATA_PIECE_LEN: usize = 16 * 1024;
+/// Bigger info dictionaries are refused.
+const MAX_METADATA_SIZE: usize = 16 * 1024 * 1024;
+/// A piece not received after that is requested again, possibly from another peer.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+/// Pieces requested from a peer at the same time.
+const MAX_REQUESTS_PER_PEER: usize = 2;
+
+const REQUEST: u8 = 0;
+const DATA: u8 = 1;
+const REJECT: u8 = 2;
+
+/// Header of a ut_metadata message, followed by the piece for `DATA`.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct MetadataMessage {
+    msg_type: u8,
+    piece: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    total_size: Option<usize>,
+}
+
+impl MetadataMessage {
+    fn encode(&self, data: &[u8]) -> Vec<u8> {
+        let mut payload = serde_bencode::to_bytes(self).unwrap();
+        payload.extend_from_slice(data);
+        payload
+    }
+}
+
+enum MetadataState {
+    Known(Arc<Vec<u8>>),
+    Fetching {
+        /// Size the pieces are fetched for, from the peers that announced it
+        size: Option<usize>,
+        /// Connected peers that can send the metadata, by the size they announced
+        sizes: HashMap<usize, usize>,
+        pieces: Vec<Option<Vec<u8>>>,
+        requested: Vec<Option<Instant>>,
+    },
+}
+
+/// The info dictionary of a torrent, shared by the connections: they serve it once it is known,
+/// and fetch it piece by piece until then (BEP 9).
+#[derive(Clone)]
+pub struct SharedMetadata {
+    info_hash: [u8; 20],
+    state: Arc<Mutex<MetadataState>>,
+    completed: Arc<Notify>,
+}
+
+impl SharedMetadata {
+    pub fn known(info_hash: [u8; 20], info: Vec<u8>) -> Self {
+        Self::with_state(info_hash, MetadataState::Known(Arc::new(info)))
+    }
+
+    pub fn fetching(info_hash: [u8; 20]) -> Self {
+        Self::with_state(info_hash, Self::fetching_state(HashMap::new()))
+    }
+
+    fn with_state(info_hash: [u8; 20], state: MetadataState) -> Self {
+        SharedMetadata {
+            info_hash,
+            state: Arc::new(Mutex::new(state)),
+            completed: Arc::new(Notify::new()),
+        }
+    }
+
+    pub fn info_hash(&self) -> [u8; 20] {
+        self.info_hash
+    }
+
+    /// The info dictionary, once known.
+    pub fn info(&self) -> Option<Arc<Vec<u8>>> {
+        match &*self.state.lock().unwrap() {
+            MetadataState::Known(info) => Some(info.clone()),
+            MetadataState::Fetching { .. } => None,
+        }
+    }
+
+    /// Wait until all the pieces are received and checked against the info_hash.
+    pub async fn completed(&self) -> Arc<Vec<u8>> {
+        loop {
+            if let Some(info) = self.info() {
+                // Let the other waiters know too
+                self.completed.notify_one();
+                return info;
+            }
+            self.completed.notified().await;
+        }
+    }
+
+    /// A peer announced the size of the metadata and can send it.
+    fn add_peer(&self, size: usize) -> Result<()> {
+        if size == 0 || size > MAX_METADATA_SIZE {
+            anyhow::bail!("Invalid metadata size: {}", size);
+        }
+        if let MetadataState::Fetching { sizes, .. } = &mut *self.state.lock().unwrap() {
+            *sizes.entry(size).or_insert(0) += 1;
+        }
+        Ok(())
+    }
+
+    /// A peer that announced `size` cannot send the metadata anymore. Once no peer agrees with
+    /// the size being fetched, it is given up on: it was likely wrong.
+    fn remove_peer(&self, size: usize) {
+        let mut state = self.state.lock().unwrap();
+        if let MetadataState::Fetching {
+            size: current,
+            sizes,
+            ..
+        } = &mut *state
+        {
+            if let Some(count) = sizes.get_mut(&size) {
+                *count -= 1;
+                if *count == 0 {
+                    sizes.remove(&size);
+                }
+            }
+            if *current == Some(size) && !sizes.contains_key(&size) {
+                log::debug!("No peer left with the metadata size {}", size);
+                *state = Self::fetching_state(std::mem::take(sizes));
+            }
+        }
+    }
+
+    /// A piece nobody has sent nor is expected to send soon, marked as requested. Only asked to
+    /// the peers that agree on the size being fetched, chosen by most of the peers.
+    fn next_request(&self, now: Instant, peer_size: usize) -> Option<u32> {
+        match &mut *self.state.lock().unwrap() {
+            MetadataState::Fetching {
+                size,
+                sizes,
+                pieces,
+                requested,
+            } => {
+                if size.is_none() {
+                    let (&chosen, _) = sizes
+                        .iter()
+                        .max_by_key(|&(&size, &count)| (count, size == peer_size))?;
+                    let count = chosen.div_ceil(METADATA_PIECE_LEN);
+                    *size = Some(chosen);
+                    *pieces = vec![None; count];
+                    *requested = vec![None; count];
+                }
+                if *size != Some(peer_size) {
+                    return None;
+                }
+                let index = (0..pieces.len()).find(|&i| {
+                    pieces[i].is_none()
+                        && requested[i].is_none_or(|at| now.duration_since(at) > REQUEST_TIMEOUT)
+                })?;
+                requested[index] = Some(now);
+                Some(index as u32)
+            }
+            MetadataState::Known(_) => None,
+        }
+    }
+
+    fn release(&self, index: u32) {
+        if let MetadataState::Fetching { requested, .. } = &mut *self.state.lock().unwrap() {
+            if let Some(requested) = requested.get_mut(index as usize) {
+                *requested = None;
+            }
+        }
+    }
+
+    fn add_piece(&self, index: u32, data: &[u8]) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let (size, sizes, pieces) = match &mut *state {
+            MetadataState::Fetching {
+                size: Some(size),
+                sizes,
+                pieces,
+                ..
+            } => (*size, sizes, pieces),
+            _ => return Ok(()),
+        };
+        let index = index as usize;
+        let start = index * METADATA_PIECE_LEN;
+        if index >= pieces.len() || data.len() != METADATA_PIECE_LEN.min(size - start) {
+            anyhow::bail!("Invalid metadata piece: index={} len={}", index, data.len());
+        }
+        pieces[index] = Some(data.to_vec());
+        if pieces.iter().any(Option::is_none) {
+            return Ok(());
+        }
+
+        let info: Vec<u8> = pieces.iter_mut().flat_map(|p| p.take().unwrap()).collect();
+        let hash: [u8; 20] = Sha1::digest(&info).into();
+        if hash != self.info_hash {
+            // Some peer lied, about the size or a piece: start over
+            log::warn!("Metadata does not match the info_hash, fetching it again");
+            *state = Self::fetching_state(std::mem::take(sizes));
+            return Ok(());
+        }
+        log::info!("Received metadata: size={}", size);
+        *state = MetadataState::Known(Arc::new(info));
+        self.completed.notify_one();
+        Ok(())
+    }
+
+    /// No piece fetched yet and no size chosen, with the peers that can send the metadata.
+    fn fetching_state(sizes: HashMap<usize, usize>) -> MetadataState {
+        MetadataState::Fetching {
+            size: None,
+            sizes,
+            pieces: Vec::new(),
+            requested: Vec::new(),
+        }
+    }
+}
+
+/// The ut_metadata extension for one connection.
+pub struct UtMetadata {
+    metadata: SharedMetadata,
+    /// Size announced by the peer, while it can send the metadata
+    size: Option<usize>,
+    /// Pieces requested from the peer and not answered yet, and when
+    requested: Vec<(u32, Instant)>,
+    /// The peer rejected a request or did not answer it: it does not have the metadata
+    rejected: bool,
+}
+
+impl UtMetadata {
+    pub fn new(metadata: SharedMetadata) -> Self {
+        UtMetadata {
+            metadata,
+            size: None,
+            requested: Vec::new(),
+            rejected: false,
+        }
+    }
+
+    fn request_more(&mut self) -> Vec<Vec<u8>> {
+        let mut payloads = Vec::new();
+        let now = Instant::now();
+        let size = match self.size {
+            Some(size) if !self.rejected => size,
+            _ => return payloads,
+        };
+        while self.requested.len() < MAX_REQUESTS_PER_PEER {
+            let piece = match self.metadata.next_request(now, size) {
+                Some(piece) => piece,
+                None => break,
+            };
+            self.requested.push((piece, now));
+            let request = MetadataMessage {
+                msg_type: REQUEST,
+                piece,
+                total_size: None,
+            };
+            payloads.push(request.encode(&[]));
+        }
+        payloads
+    }
+
+    /// The peer cannot send the metadata: its size does not count anymore.
+    fn reject(&mut self) {
+        self.rejected = true;
+        if let Some(size) = self.size.take() {
+            self.metadata.remove_peer(size);
+        }
+    }
+
+    /// Whether `piece` was requested, forgetting the request.
+    fn answered(&mut self, piece: u32) -> bool {
+        let requested = self.requested.len();
+        self.requested.retain(|&(requested, _)| requested != piece);
+        self.requested.len() < requested
+    }
+}
+
+impl Drop for UtMetadata {
+    fn drop(&mut self) {
+        self.reject();
+    }
+}
+
+impl Extension for UtMetadata {
+    fn name(&self) -> &'static str {
+        "ut_metadata"
+    }
+
+    fn extend_handshake(&self, handshake: &mut ExtensionHandshake) {
+        handshake.metadata_size = self.metadata.info().map(|info| info.len() as u64);
+    }
+
+    fn on_handshake(&mut self, handshake: &ExtensionHandshake) -> Result<Vec<Vec<u8>>> {
+        if self.metadata.info().is_some() {
+            return Ok(Vec::new());
+        }
+        match handshake.metadata_size {
+            Some(size) => {
+                self.metadata.add_peer(size as usize)?;
+                self.size = Some(size as usize);
+            }
+            // It cannot give us the metadata
+            None => self.rejected = true,
+        }
+        Ok(self.request_more())
+    }
+
+    fn on_message(&mut self, payload: &[u8]) -> Result<Vec<Vec<u8>>> {
+        let header_end = skip_bencode_value(payload, 0)?;
+        let message: MetadataMessage = serde_bencode::from_bytes(&payload[..header_end])
+            .context("Invalid ut_metadata message")?;
+        let data = &payload[header_end..];
+        match message.msg_type {
+            REQUEST => {
+                let piece = message.piece as usize;
+                let answer = match self.metadata.info() {
+                    Some(info) if piece * METADATA_PIECE_LEN < info.len() => {
+                        let start = piece * METADATA_PIECE_LEN;
+                        let end = info.len().min(start + METADATA_PIECE_LEN);
+                        let data = MetadataMessage {
+                            msg_type: DATA,
+                            piece: message.piece,
+                            total_size: Some(info.len()),
+                        };
+                        data.encode(&info[start..end])
+                    }
+                    _ => MetadataMessage {
+                        msg_type: REJECT,
+                        piece: message.piece,
+                        total_size: None,
+                    }
+                    .encode(&[]),
+                };
+                Ok(vec![answer])
+            }
+            DATA => {
+                if !self.answered(message.piece) {
+                    log::debug!("Ignoring metadata piece not requested: {}", message.piece);
+                    return Ok(Vec::new());
+                }
+                self.metadata.add_piece(message.piece, data)?;
+                Ok(self.request_more())
+            }
+            REJECT => {
+                if !self.answered(message.piece) {
+                    return Ok(Vec::new());
+                }
+                self.metadata.release(message.piece);
+                self.reject();
+                Ok(Vec::new())
+            }
+            // Unknown message types must be ignored
+            _ => Ok(Vec::new()),
+        }
+    }
+
+    fn tick(&mut self) -> Result<Vec<Vec<u8>>> {
+        let now = Instant::now();
+        if self
+            .requested
+            .iter()
+            .any(|&(_, at)| now.duration_since(at) > REQUEST_TIMEOUT)
+        {
+            self.reject();
+        }
+        if self.rejected && self.metadata.info().is_none() {
+            anyhow::bail!("The peer does not have the metadata");
+        }
+        // A piece that timed out may have been handed to us
+        Ok(self.request_more())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sha1::{Digest, Sha1};
+
+    use crate::extension::{Extension, ExtensionHandshake};
+    use crate::metadata::{MetadataMessage, SharedMetadata, UtMetadata, METADATA_PIECE_LEN};
+
+    fn info() -> Vec<u8> {
+        let mut info = b"d6:lengthi10e4:name4:test12:piece lengthi16384e6:pieces40000:".to_vec();
+        info.extend((0..40_000).map(|i| i as u8));
+        info.push(b'e');
+        info
+    }
+
+    fn handshake(metadata_size: Option<u64>) -> ExtensionHandshake {
+        ExtensionHandshake {
+            metadata_size,
+            ..Default::default()
+        }
+    }
+
+    fn header(payload: &[u8]) -> MetadataMessage {
+        let end = crate::torrent_file::skip_bencode_value(payload, 0).unwrap();
+        serde_bencode::from_bytes(&payload[..end]).unwrap()
+    }
+
+    #[actix::test]
+    async fn metadata_should_be_fetched_from_a_peer_that_has_it() {
+        let info = info();
+        let info_hash: [u8; 20] = Sha1::digest(&info).into();
+        let mut seeder = UtMetadata::new(SharedMetadata::known(info_hash, info.clone()));
+        let fetching = SharedMetadata::fetching(info_hash);
+        let mut leecher = UtMetadata::new(fetching.clone());
+
+        let mut ours = ExtensionHandshake::default();
+        seeder.extend_handshake(&mut ours);
+        assert_eq!(ours.metadata_size, Some(info.len() as u64));
+        assert!(seeder.on_handshake(&handshake(None)).unwrap().is_empty());
+
+        // 3 pieces, 2 requested at a time
+        let mut requests = leecher.on_handshake(&ours).unwrap();
+        assert_eq!(requests.len(), 2);
+        let mut received = 0;
+        while let Some(request) = requests.pop() {
+            let answers = seeder.on_message(&request).unwrap();
+            assert_eq!(answers.len(), 1);
+            let data = header(&answers[0]);
+            assert_eq!(data.total_size, Some(info.len()));
+            received += 1;
+            requests.extend(leecher.on_message(&answers[0]).unwrap());
+        }
+        assert_eq!(received, info.len().div_ceil(METADATA_PIECE_LEN));
+        assert_eq!(*fetching.completed().await, info);
+    }
+
+    #[actix::test]
+    async fn wrong_metadata_should_be_fetched_again() {
+        let info = info();
+        let info_hash: [u8; 20] = Sha1::digest(&info).into();
+        let fetching = SharedMetadata::fetching(info_hash);
+        let mut leecher = UtMetadata::new(fetching.clone());
+        let requests = leecher.on_handshake(&handshake(Some(10))).unwrap();
+        assert_eq!(header(&requests[0]).piece, 0);
+
+        // A single piece, of the right size but wrong
+        let data = MetadataMessage {
+            msg_type: 1,
+            piece: 0,
+            total_size: Some(10),
+        };
+        let requests = leecher.on_message(&data.encode(&[0; 10])).unwrap();
+        assert!(fetching.info().is_none());
+        assert_eq!(header(&requests[0]).piece, 0);
+        // Another size, used once no peer is left with the first one
+        let mut other = UtMetadata::new(fetching.clone());
+        assert!(other
+            .on_handshake(&handshake(Some(info.len() as u64)))
+            .unwrap()
+            .is_empty());
+        drop(leecher);
+        assert_eq!(other.tick().unwrap().len(), 2);
+
+        // Too big, wrong piece size
+        assert!(UtMetadata::new(SharedMetadata::fetching(info_hash))
+            .on_handshake(&handshake(Some(1 << 30)))
+            .is_err());
+        assert!(other.on_message(&data.encode(&[0; 10])).is_err());
+    }
+
+    #[actix::test]
+    async fn size_announced_by_a_lying_peer_should_be_given_up() {
+        let info = info();
+        let info_hash: [u8; 20] = Sha1::digest(&info).into();
+        let mut seeder = UtMetadata::new(SharedMetadata::known(info_hash, info.clone()));
+        let fetching = SharedMetadata::fetching(info_hash);
+        // One more piece than there is, that the peer cannot send
+        let mut liar = UtMetadata::new(fetching.clone());
+        let lie = handshake(Some((info.len() + METADATA_PIECE_LEN) as u64));
+        let lies = liar.on_handshake(&lie).unwrap();
+        assert_eq!(lies.len(), 2);
+        let mut honest = UtMetadata::new(fetching.clone());
+        let truth = handshake(Some(info.len() as u64));
+        assert!(honest.on_handshake(&truth).unwrap().is_empty());
+
+        let reject = MetadataMessage {
+            msg_type: 2,
+            piece: header(&lies[0]).piece,
+            total_size: None,
+        };
+        assert!(liar.on_message(&reject.encode(&[])).unwrap().is_empty());
+        let mut requests = honest.tick().unwrap();
+        assert_eq!(requests.len(), 2);
+        while let Some(request) = requests.pop() {
+            for answer in seeder.on_message(&request).unwrap() {
+                requests.extend(honest.on_message(&answer).unwrap());
+            }
+        }
+        assert_eq!(*fetching.completed().await, info);
+    }
+
+    #[test]
+    fn requests_should_be_rejected_without_metadata() {
+        let mut peer = UtMetadata::new(SharedMetadata::fetching([0; 20]));
+        let request = MetadataMessage {
+            msg_type: 0,
+            piece: 0,
+            total_size: None,
+        };
+        let answers = peer.on_message(&request.encode(&[])).unwrap();
+        assert_eq!(answers, vec![b"d8:msg_typei2e5:piecei0ee".to_vec()]);
+
+        // After a reject, nothing more is asked to that peer
+        let mut leecher = UtMetadata::new(SharedMetadata::fetching([0; 20]));
+        let requests = leecher.on_handshake(&handshake(Some(100))).unwrap();
+        assert_eq!(requests, vec![b"d8:msg_typei0e5:piecei0ee".to_vec()]);
+        let reject = MetadataMessage {
+            msg_type: 2,
+            piece: 0,
+            total_size: None,
+        };
+        assert!(leecher.on_message(&reject.encode(&[])).unwrap().is_empty());
+        assert!(leecher.tick().is_err());
+    }
+}
@@ -1,356 +1,1105 @@
+use crate::choker::ChokerActor;
+use crate::codec::{PeerCodec, ProtocolError};
+use crate::extension::{ExtensionFactory, ExtensionRegistry, HANDSHAKE_ID, TICK_INTERVAL};
+use crate::fs::ReadBlock;
+use crate::handshake::{ConnectedPeerIds, HandshakeError, PeerHandshake, HANDSHAKE_LEN};
 use crate::message::*;
+use crate::metadata::{SharedMetadata, UtMetadata};
+use crate::peer::{PeerCommand, PeerConnected, PeerGone, PeerStats};
+use crate::pieces::{
+    BlockReceived, HavePieces, NextBlocks, PeerBitfield, PeerHave, PiecesActor, ReleaseBlocks,
+};
+use crate::pipeline::{PipelineConfig, RequestPipeline};
 use crate::torrent_file::*;
+use crate::tracker::Peer;
+use actix::Addr;
 use anyhow::{Context, Result};
 use bit_vec::BitVec;
-use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use futures_util::{SinkExt, StreamExt};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::convert::TryInto;
-use std::io::Cursor;
-use std::ops::Deref;
-use std::sync::Arc;
-use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, Notify};
+use tokio::time;
+use tokio_util::codec::{Framed, FramedRead, FramedWrite};
 
-const MAX_MESSAGE_LEN: usize = BLOCK_LENGTH as usize + 1 + 4 + 4;
+const MAX_QUEUED_REQUESTS: usize = 250;
+/// Peers waiting for a connection, the others are dropped until some connections end.
+const MAX_WAITING_PEERS: usize = 1000;
+/// Peers drop connections silent for 2 minutes.
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(110);
+/// We do the same, keep-alives included: the connection slot goes to another peer.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(120);
+/// Peers that take longer to accept the connection or to answer the handshake are given up.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
 
-async fn handshake(socket: &mut TcpStream, info_hash: &[u8; 20], addr: &str) -> Result<()> {
+/// Identifies a peer connection across actors.
+static NEXT_PEER_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Outgoing handshake: ours is sent first, then the peer's is read.
+pub async fn handshake<S>(socket: &mut S, info_hash: &[u8; 20], addr: &str) -> Result<PeerHandshake>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
     socket
-        .write_all(HANDSHAKE)
+        .write_all(&PeerHandshake::ours(*info_hash).to_bytes())
         .await
         .with_context(|| "Failed to write handshake to peer")?;
     log::debug!("{}: Sent handshake", &addr);
 
-    socket
-        .write_all(info_hash)
-        .await
-        .with_context(|| "Failed to write info_hash to peer")?;
-    log::debug!("{}: Sent info_hash", &addr);
-
-    assert_eq!(HANDSHAKE.len(), 28);
-    let mut buf = [0u8; HANDSHAKE.len()];
+    let mut buf = [0u8; HANDSHAKE_LEN];
     socket
         .read_exact(&mut buf)
         .await
-        .with_context(|| "Failed to read from peer")?;
+        .with_context(|| "Failed to read handshake from peer")?;
+    let theirs = PeerHandshake::parse(&buf)?;
+    theirs.check(info_hash)?;
+    log::debug!(
+        "{}: Validated handshake: client={} reserved={:?}",
+        &addr,
+        theirs.client_name(),
+        &theirs.reserved
+    );
 
-    if buf[..20] != HANDSHAKE[..20] {
-        anyhow::bail!(
-            "{}: Received wrong handshake:\nexpected=\t{:?}\ngot=\t{:?}",
+    Ok(theirs)
+}
+
+/// Block requests received from the peer and not answered yet.
+#[derive(Default)]
+struct UploadQueue {
+    requests: Mutex<VecDeque<(u32, u32, u32)>>,
+    notify: Notify,
+}
+
+/// Answer the queued block requests of the peer, one at a time, with blocks read from disk.
+async fn upload(
+    queue: Arc<UploadQueue>,
+    pieces_actor: Addr<PiecesActor>,
+    tx: mpsc::Sender<Message>,
+    stats: Arc<PeerStats>,
+    addr: Arc<String>,
+) -> Result<()> {
+    loop {
+        let request = queue.requests.lock().unwrap().pop_front();
+        let (index, begin, length) = match request {
+            Some(request) => request,
+            None => {
+                queue.notify.notified().await;
+                continue;
+            }
+        };
+
+        let read = pieces_actor
+            .send(ReadBlock {
+                index,
+                begin,
+                length,
+            })
+            .await?;
+        // The next requests may still be served
+        let data = match read {
+            Ok(data) => data,
+            Err(err) => {
+                log::warn!(
+                    "{}: Failed to read block: index={} begin={}: {:#}",
+                    &addr,
+                    index,
+                    begin,
+                    err
+                );
+                continue;
+            }
+        };
+        log::debug!(
+            "{}: Uploading block: index={} begin={}",
             &addr,
-            &HANDSHAKE[..20],
-            &buf[..20]
+            index,
+            begin
         );
+        let len = data.len();
+        tx.send(Message::Piece { index, begin, data })
+            .await
+            .with_context(|| "Failed to queue Message::Piece")?;
+        stats.add_uploaded(len);
     }
-    log::debug!("{}: Validated handshake", &addr);
+}
 
-    socket
-        .read_exact(&mut buf[..info_hash.len()])
-        .await
-        .with_context(|| "Failed to read info_hash")?;
-    log::debug!(
-        "{}: Received info_hash:{:?}",
-        &addr,
-        &buf[..info_hash.len()],
-    );
+pub async fn peer_talk(
+    served: ServedTorrent,
+    info_hash: [u8; 20],
+    socket_addr: SocketAddr,
+) -> Result<()> {
+    let addr = Arc::new(socket_addr.to_string());
+    let (socket, theirs) = connect(socket_addr, &info_hash, &addr).await?;
+    let _registered = served.peer_ids.register(theirs.peer_id)?;
 
-    socket
-        .write_all(PEER_ID)
+    peer_session(socket, &served, &theirs, false, addr).await
+}
+
+/// Connect to a peer and exchange the handshakes, within the time limits.
+async fn connect(
+    socket_addr: SocketAddr,
+    info_hash: &[u8; 20],
+    addr: &str,
+) -> Result<(TcpStream, PeerHandshake)> {
+    log::debug!("{}: Trying to connect", &addr);
+    let mut socket = time::timeout(CONNECT_TIMEOUT, TcpStream::connect(socket_addr))
         .await
-        .with_context(|| "Failed to write peer id")?;
-    log::debug!("{}: Sent peer id", &addr);
+        .with_context(|| "Timed out connecting to peer")??;
+    log::debug!("{}: Connected", &addr);
 
-    socket
-        .read_exact(&mut buf[..PEER_ID.len()])
+    let theirs = time::timeout(HANDSHAKE_TIMEOUT, handshake(&mut socket, info_hash, addr))
         .await
-        .with_context(|| "Failed to read peer id")?;
-    log::debug!("{}: Received peer id:{:?}", &addr, &buf[..PEER_ID.len()],);
+        .with_context(|| "Timed out waiting for the handshake")??;
+    Ok((socket, theirs))
+}
 
-    Ok(())
+/// Connect to the peers found by the trackers or the other peers, to at most `max_peers` at the
+/// same time. The others wait for a connection to end.
+pub async fn connect_peers(
+    mut peers: mpsc::UnboundedReceiver<Vec<Peer>>,
+    served: ServedTorrent,
+    info_hash: [u8; 20],
+    max_peers: usize,
+) {
+    let mut connected: HashSet<SocketAddr> = HashSet::new();
+    // Peers that broke the protocol are not connected to again
+    let mut banned: HashSet<SocketAddr> = HashSet::new();
+    let mut waiting: VecDeque<SocketAddr> = VecDeque::new();
+    let (done_tx, mut done_rx) = mpsc::unbounded_channel();
+    loop {
+        tokio::select! {
+            new_peers = peers.recv() => match new_peers {
+                Some(new_peers) => {
+                    for peer in new_peers {
+                        let addr = SocketAddr::new(peer.ip, peer.port);
+                        if !connected.contains(&addr)
+                            && !waiting.contains(&addr)
+                            && !banned.contains(&addr)
+                            && waiting.len() < MAX_WAITING_PEERS
+                        {
+                            waiting.push_back(addr);
+                        }
+                    }
+                }
+                None => return,
+            },
+            Some((addr, ban)) = done_rx.recv() => {
+                connected.remove(&addr);
+                if ban {
+                    banned.insert(addr);
+                }
+            }
+        }
+
+        while connected.len() < max_peers {
+            let addr = match waiting.pop_front() {
+                Some(addr) => addr,
+                None => break,
+            };
+            connected.insert(addr);
+            let served = served.clone();
+            let done_tx = done_tx.clone();
+            tokio::spawn(async move {
+                let res = peer_talk(served, info_hash, addr).await;
+                let ban = match res {
+                    Ok(()) => false,
+                    Err(err) => {
+                        log::warn!("{}: Err: {}", &addr, err);
+                        err.downcast_ref::<ProtocolError>().is_some()
+                            || matches!(
+                                err.downcast_ref::<HandshakeError>(),
+                                Some(err) if *err != HandshakeError::DuplicatePeerId
+                            )
+                    }
+                };
+                let _ = done_tx.send((addr, ban));
+            });
+        }
+    }
 }
 
-impl Message {
-    fn size(&self) -> u32 {
-        match &self {
-            Message::Have(_) => 4,
-            Message::Bitfield(bytes) => bytes.len() as u32,
-            Message::Request { .. } => 4 + 4 + 4,
-            Message::Piece { data, .. } => 4 + 4 + data.len() as u32,
-            Message::Cancel { .. } => 4 + 4 + 4,
-            _ => 0,
+/// Fetch the metadata of a torrent known by its info_hash only, from the peers found meanwhile,
+/// connecting to at most `max_peers` at the same time. The peers are returned too, to connect to
+/// them again for the download.
+pub async fn fetch_metadata(
+    peers: &mut mpsc::UnboundedReceiver<Vec<Peer>>,
+    metadata: SharedMetadata,
+    port: u16,
+    max_peers: usize,
+) -> Result<(Arc<Vec<u8>>, Vec<Peer>)> {
+    let mut seen: Vec<Peer> = Vec::new();
+    let mut waiting: VecDeque<SocketAddr> = VecDeque::new();
+    let mut sessions = HashMap::new();
+    let (done_tx, mut done_rx) = mpsc::unbounded_channel();
+    let completed = metadata.completed();
+    tokio::pin!(completed);
+    let info = loop {
+        tokio::select! {
+            info = &mut completed => break info,
+            new_peers = peers.recv() => match new_peers {
+                Some(new_peers) => {
+                    for peer in new_peers {
+                        if !seen.contains(&peer) {
+                            waiting.push_back(SocketAddr::new(peer.ip, peer.port));
+                            seen.push(peer);
+                        }
+                    }
+                }
+                None => anyhow::bail!("No more peers to fetch the metadata from"),
+            },
+            Some(addr) = done_rx.recv() => {
+                sessions.remove(&addr);
+            }
         }
+
+        while sessions.len() < max_peers {
+            let addr = match waiting.pop_front() {
+                Some(addr) => addr,
+                None => break,
+            };
+            let metadata = metadata.clone();
+            let done_tx = done_tx.clone();
+            let session = tokio::spawn(async move {
+                if let Err(err) = metadata_session(metadata, port, addr).await {
+                    log::debug!("{}: Err: {:#}", &addr, err);
+                }
+                let _ = done_tx.send(addr);
+            });
+            sessions.insert(addr, session);
+        }
+    };
+
+    for session in sessions.values() {
+        session.abort();
     }
+    Ok((info, seen))
+}
 
-    fn write(&self, buf: &mut [u8]) -> Result<()> {
-        let mut cursor = Cursor::new(buf);
-        WriteBytesExt::write_u32::<BigEndian>(&mut cursor, self.size())?;
-        WriteBytesExt::write_u8(&mut cursor, self.tag() as u8)?;
+/// Exchange nothing but the metadata with a peer until we have it.
+async fn metadata_session(
+    metadata: SharedMetadata,
+    port: u16,
+    socket_addr: SocketAddr,
+) -> Result<()> {
+    let addr = socket_addr.to_string();
+    let (socket, theirs) = connect(socket_addr, &metadata.info_hash(), &addr).await?;
+    if !theirs.supports_extensions() {
+        anyhow::bail!("The peer does not support the extension protocol");
+    }
 
-        match &self {
-            Message::Have(piece) => {
-                WriteBytesExt::write_u32::<BigEndian>(&mut cursor, *piece)?;
-            }
-            Message::Bitfield(bytes) => {
-                std::io::Write::write_all(&mut cursor, &bytes.to_bytes())?;
-            }
-            Message::Request {
-                index,
-                begin,
-                length,
-            } => {
-                WriteBytesExt::write_u32::<BigEndian>(&mut cursor, *index)?;
-                WriteBytesExt::write_u32::<BigEndian>(&mut cursor, *begin)?;
-                WriteBytesExt::write_u32::<BigEndian>(&mut cursor, *length)?;
-            }
-            Message::Piece { index, begin, data } => {
-                WriteBytesExt::write_u32::<BigEndian>(&mut cursor, *index)?;
-                WriteBytesExt::write_u32::<BigEndian>(&mut cursor, *begin)?;
-                std::io::Write::write_all(&mut cursor, data)?;
-            }
-            Message::Cancel {
-                index,
-                begin,
-                length,
-            } => {
-                WriteBytesExt::write_u32::<BigEndian>(&mut cursor, *index)?;
-                WriteBytesExt::write_u32::<BigEndian>(&mut cursor, *begin)?;
-                WriteBytesExt::write_u32::<BigEndian>(&mut cursor, *length)?;
+    let mut framed = Framed::new(socket, PeerCodec);
+    let mut extensions = ExtensionRegistry::new(vec![Box::new(UtMetadata::new(metadata.clone()))]);
+    framed
+        .send(extensions.handshake(port, MAX_QUEUED_REQUESTS as u32, socket_addr.ip()))
+        .await
+        .with_context(|| "Failed to send the extension handshake")?;
+    let mut tick = time::interval_at(time::Instant::now() + TICK_INTERVAL, TICK_INTERVAL);
+    let idle = time::sleep(IDLE_TIMEOUT);
+    tokio::pin!(idle);
+    loop {
+        let messages = tokio::select! {
+            message = framed.next() => {
+                idle.as_mut().reset(time::Instant::now() + IDLE_TIMEOUT);
+                match message {
+                    Some(message) => match message? {
+                        Message::Extended { id, payload } => {
+                            let messages = extensions.on_message(id, &payload)?;
+                            if let (HANDSHAKE_ID, Some(handshake)) = (id, extensions.theirs()) {
+                                if handshake.id("ut_metadata").is_none() {
+                                    anyhow::bail!("The peer does not support ut_metadata");
+                                }
+                            }
+                            messages
+                        }
+                        // Nothing else matters before we know the torrent
+                        _ => continue,
+                    },
+                    None => return Ok(()),
+                }
+            },
+            _ = tick.tick() => extensions.tick()?,
+            _ = &mut idle => anyhow::bail!("No message from the peer for {:?}", IDLE_TIMEOUT),
+        };
+        for message in messages {
+            framed
+                .send(message)
+                .await
+                .with_context(|| "Failed to send Message::Extended")?;
+        }
+    }
+}
+
+/// A torrent we download or seed, to match incoming connections against.
+#[derive(Clone)]
+pub struct ServedTorrent {
+    pub torrent: Arc<Torrent>,
+    pub pieces_actor: Addr<PiecesActor>,
+    pub choker: Addr<ChokerActor>,
+    pub pipeline: PipelineConfig,
+    pub peer_ids: ConnectedPeerIds,
+    /// Port we listen on
+    pub port: u16,
+    /// Extension protocol extensions we offer to the peers
+    pub extensions: Vec<ExtensionFactory>,
+}
+
+/// Listen on `port` over IPv4 and, if the system has it, over IPv6.
+pub fn bind_listeners(port: u16) -> Result<Vec<TcpListener>> {
+    let mut listeners = vec![
+        bind_listener(SocketAddr::from((Ipv4Addr::UNSPECIFIED, port)))
+            .with_context(|| format!("Failed to listen on port {} over IPv4", port))?,
+    ];
+    match bind_listener(SocketAddr::from((Ipv6Addr::UNSPECIFIED, port))) {
+        Ok(listener) => listeners.push(listener),
+        Err(err) => log::warn!("Failed to listen on port {} over IPv6: {:#}", port, err),
+    }
+    Ok(listeners)
+}
+
+fn bind_listener(addr: SocketAddr) -> Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    // Otherwise the IPv6 socket also takes the IPv4 connections, and the port is already in use
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(128)?;
+    socket.set_nonblocking(true)?;
+    Ok(TcpListener::from_std(socket.into())?)
+}
+
+/// Accept incoming connections and talk to the peers asking for one of the served torrents.
+pub async fn accept_peers(
+    listener: TcpListener,
+    torrents: Arc<HashMap<[u8; 20], ServedTorrent>>,
+) -> Result<()> {
+    log::info!("Listening on {}", listener.local_addr()?);
+    loop {
+        let (socket, peer_addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                log::warn!("Failed to accept connection: {}", err);
+                continue;
             }
-            _ => {}
         };
-        Ok(())
+        let torrents = torrents.clone();
+        tokio::spawn(async move {
+            let addr = Arc::new(peer_addr.to_string());
+            log::debug!("{}: Accepted", &addr);
+            let _ = incoming_peer_talk(socket, &torrents, addr.clone())
+                .await
+                .map_err(|err| {
+                    log::warn!("{}: Err: {}", &addr, err);
+                });
+        });
     }
 }
 
-pub async fn peer_talk(
-    torrent: Arc<Torrent>,
-    _peer_id: usize,
-    info_hash: [u8; 20],
+async fn incoming_peer_talk(
+    mut socket: TcpStream,
+    torrents: &HashMap<[u8; 20], ServedTorrent>,
     addr: Arc<String>,
 ) -> Result<()> {
-    log::debug!("{}: Trying to connect", &addr);
-    let mut socket = TcpStream::connect(addr.deref()).await?;
-    log::debug!("{}: Connected", &addr);
+    let theirs = time::timeout(
+        HANDSHAKE_TIMEOUT,
+        incoming_handshake(&mut socket, torrents, &addr),
+    )
+    .await
+    .with_context(|| "Timed out waiting for the handshake")??;
+    let served = &torrents[&theirs.info_hash];
+    let _registered = served.peer_ids.register(theirs.peer_id)?;
 
-    handshake(&mut socket, &info_hash, &addr).await?;
-
-    // Interested
-    let mut buf_writer = vec![0; MAX_MESSAGE_LEN];
-    Message::Interested
-        .write(&mut buf_writer)
-        .with_context(|| "Failed to serialize Message::Interested")?;
+    peer_session(socket, served, &theirs, true, addr).await
+}
 
+/// The peer sends its handshake first: we only answer if we serve the torrent it asks for.
+async fn incoming_handshake(
+    socket: &mut TcpStream,
+    torrents: &HashMap<[u8; 20], ServedTorrent>,
+    addr: &str,
+) -> Result<PeerHandshake> {
+    // Their peer_id only comes after our handshake
+    let mut buf = [0u8; HANDSHAKE_LEN];
+    let (start, peer_id) = buf.split_at_mut(HANDSHAKE_LEN - PEER_ID.len());
     socket
-        .write_all(&buf_writer)
+        .read_exact(start)
         .await
-        .with_context(|| "Failed to send Message::Interested")?;
-    log::debug!("{}: Sent Message::Interested", &addr);
+        .with_context(|| "Failed to read handshake from peer")?;
+    let info_hash: [u8; 20] = start[HANDSHAKE.len()..].try_into().unwrap();
+    if start[..20] != HANDSHAKE[..20] {
+        return Err(HandshakeError::WrongProtocol.into());
+    }
+    log::debug!("{}: Received info_hash:{:?}", &addr, &info_hash);
+    if !torrents.contains_key(&info_hash) {
+        anyhow::bail!("Unknown info_hash: {:?}", &info_hash);
+    }
 
-    // Choke
-    Message::Choke
-        .write(&mut buf_writer)
-        .with_context(|| "Failed to serialize Message::Choke")?;
+    socket
+        .write_all(&PeerHandshake::ours(info_hash).to_bytes())
+        .await
+        .with_context(|| "Failed to write handshake to peer")?;
+    log::debug!("{}: Sent handshake", &addr);
 
     socket
-        .write_all(&buf_writer)
+        .read_exact(peer_id)
         .await
-        .with_context(|| "Failed to send Message::Choke")?;
-    log::debug!("{}: Sent Message::Choke", &addr);
+        .with_context(|| "Failed to read peer id")?;
+    let theirs = PeerHandshake::parse(&buf)?;
+    theirs.check(&info_hash)?;
+    log::debug!(
+        "{}: Validated handshake: client={} reserved={:?}",
+        &addr,
+        theirs.client_name(),
+        &theirs.reserved
+    );
 
-    let (mut rd, mut _wr) = io::split(socket);
+    Ok(theirs)
+}
 
-    let _addr_writer = addr.clone();
-    tokio::spawn(async move {
-        loop {
-            todo!();
+/// The peer state machine, the same for outgoing and `incoming` connections once the handshake is
+/// done.
+async fn peer_session(
+    socket: TcpStream,
+    served: &ServedTorrent,
+    theirs: &PeerHandshake,
+    incoming: bool,
+    addr: Arc<String>,
+) -> Result<()> {
+    let torrent = &served.torrent;
+    let pieces_actor = &served.pieces_actor;
+    let peer_addr = socket.peer_addr()?;
+    let (rd, wr) = socket.into_split();
+    let mut reader = FramedRead::new(rd, PeerCodec);
 
-            // msg.message
-            //     .write(&mut buf_writer)
-            //     .with_context(|| "Failed to serialize message")?;
+    let (tx, mut rx) = mpsc::channel::<Message>(MAX_QUEUED_REQUESTS);
+    let stats = Arc::new(PeerStats::default());
+    // Before the bitfield, so that the pieces verified after it are announced with Have
+    let peer_id = NEXT_PEER_ID.fetch_add(1, Ordering::Relaxed);
+    let (commands_tx, mut commands_rx) = mpsc::unbounded_channel();
+    let connected = PeerConnected {
+        peer: peer_id,
+        commands: commands_tx,
+        stats: stats.clone(),
+    };
+    pieces_actor.do_send(connected.clone());
+    // Choked until the choker decides otherwise
+    served.choker.do_send(connected);
 
-            // wr.write_all(&buf_writer)
-            //     .await
-            //     .with_context(|| "Failed to send message")?;
-            // log::debug!("{}: Sent message {:?}", &addr_writer, &msg);
+    // Bitfield, only allowed as the first message
+    let mut have_pieces = pieces_actor.send(HavePieces).await?;
+    if have_pieces.any() {
+        tx.send(Message::Bitfield(have_pieces.clone()))
+            .await
+            .with_context(|| "Failed to queue Message::Bitfield")?;
+    }
+    let mut extensions = ExtensionRegistry::new(
+        served
+            .extensions
+            .iter()
+            .map(|create| create(peer_addr, incoming))
+            .collect(),
+    );
+    if theirs.supports_extensions() {
+        tx.send(extensions.handshake(served.port, MAX_QUEUED_REQUESTS as u32, peer_addr.ip()))
+            .await
+            .with_context(|| "Failed to queue the extension handshake")?;
+    }
+    tx.send(Message::Interested)
+        .await
+        .with_context(|| "Failed to queue Message::Interested")?;
+
+    let addr_writer = addr.clone();
+    tokio::spawn(async move {
+        let mut writer = FramedWrite::new(wr, PeerCodec);
+        while let Some(msg) = rx.recv().await {
+            let tag = msg.tag();
+            writer
+                .send(msg)
+                .await
+                .with_context(|| "Failed to send message")?;
+            log::debug!("{}: Sent message {:?}", &addr_writer, tag);
         }
-        #[allow(unreachable_code)]
         Ok::<_, anyhow::Error>(()) // Needed for type inference
     });
 
-    let mut buf = vec![0; MAX_MESSAGE_LEN];
-    // TODO: expose this state to the write coroutine
-    let mut choked = true;
-    let mut interested = false;
-    let mut have = None;
-    loop {
-        rd.read_exact(&mut buf[..4])
-            .await
-            .with_context(|| "Failed to read from peer")?;
+    let upload_queue = Arc::new(UploadQueue::default());
+    let uploader = tokio::spawn(upload(
+        upload_queue.clone(),
+        pieces_actor.clone(),
+        tx.clone(),
+        stats.clone(),
+        addr.clone(),
+    ));
 
-        log::debug!("{}: Received: data={:?}", &addr, &buf[..4]);
+    let mut pipeline = RequestPipeline::new(served.pipeline, Instant::now());
+    let res: Result<()> = async {
+        let mut choked = true;
+        let mut choking = true;
+        let mut interested = false;
+        let mut keep_alive = time::interval_at(
+            time::Instant::now() + KEEP_ALIVE_INTERVAL,
+            KEEP_ALIVE_INTERVAL,
+        );
+        let mut extension_tick =
+            time::interval_at(time::Instant::now() + TICK_INTERVAL, TICK_INTERVAL);
+        let idle = time::sleep(IDLE_TIMEOUT);
+        tokio::pin!(idle);
+        loop {
+            let message = tokio::select! {
+                message = reader.next() => {
+                    idle.as_mut().reset(time::Instant::now() + IDLE_TIMEOUT);
+                    match message {
+                        Some(message) => message?,
+                        // The peer closed the connection
+                        None => return Ok(()),
+                    }
+                },
+                _ = &mut idle => {
+                    anyhow::bail!("No message from the peer for {:?}", IDLE_TIMEOUT);
+                },
+                _ = keep_alive.tick() => {
+                    tx.send(Message::KeepAlive)
+                        .await
+                        .with_context(|| "Failed to queue Message::KeepAlive")?;
+                    continue;
+                },
+                _ = extension_tick.tick() => {
+                    for message in extensions.tick()? {
+                        tx.send(message)
+                            .await
+                            .with_context(|| "Failed to queue Message::Extended")?;
+                    }
+                    continue;
+                },
+                Some(command) = commands_rx.recv() => {
+                    match command {
+                        PeerCommand::Cancel(block) => {
+                            if pipeline.cancel(&block) {
+                                tx.send(Message::Cancel {
+                                    index: block.index,
+                                    begin: block.begin,
+                                    length: block.length,
+                                })
+                                .await
+                                .with_context(|| "Failed to queue Message::Cancel")?;
+                            }
+                        }
+                        PeerCommand::Choke => {
+                            choking = true;
+                            // The requests we did not answer yet are discarded
+                            upload_queue.requests.lock().unwrap().clear();
+                            tx.send(Message::Choke)
+                                .await
+                                .with_context(|| "Failed to queue Message::Choke")?;
+                        }
+                        PeerCommand::Unchoke => {
+                            choking = false;
+                            tx.send(Message::Unchoke)
+                                .await
+                                .with_context(|| "Failed to queue Message::Unchoke")?;
+                        }
+                        PeerCommand::Have(index) => {
+                            have_pieces.set(index as usize, true);
+                            tx.send(Message::Have(index))
+                                .await
+                                .with_context(|| "Failed to queue Message::Have")?;
+                        }
+                    }
+                    if !choked {
+                        request_blocks(&mut pipeline, peer_id, pieces_actor, &tx).await?;
+                    }
+                    continue;
+                }
+            };
 
-        let advisory_length: usize = u32::from_be_bytes(buf[..4].try_into().unwrap()) as usize;
-        log::debug!("{}: advisory_length={}", &addr, advisory_length);
-        if advisory_length > buf.len() {
-            anyhow::bail!(
-                "Advisory length is bigger than buffer size: advisory_length={}",
-                advisory_length
-            );
+            log::debug!("{}: msg={:?}", &addr, message.tag());
+            match message {
+                Message::KeepAlive => {}
+                Message::Choke => {
+                    choked = true;
+                    // The peer discards the requests it has not answered yet
+                    let released = pipeline.drain();
+                    if !released.is_empty() {
+                        pieces_actor.do_send(ReleaseBlocks {
+                            peer: peer_id,
+                            blocks: released,
+                        });
+                    }
+                }
+                Message::Unchoke => {
+                    choked = false;
+                    request_blocks(&mut pipeline, peer_id, pieces_actor, &tx).await?;
+                }
+                Message::Interested => {
+                    interested = true;
+                    stats.interested.store(true, Ordering::Relaxed);
+                }
+                Message::NotInterested => {
+                    interested = false;
+                    stats.interested.store(false, Ordering::Relaxed);
+                }
+                Message::Have(index) => {
+                    if index as usize >= torrent.info.pieces_count() {
+                        anyhow::bail!("Invalid Message::Have, unknown piece: {}", index);
+                    }
+                    pieces_actor.do_send(PeerHave {
+                        peer: peer_id,
+                        index,
+                    });
+                    if !choked {
+                        request_blocks(&mut pipeline, peer_id, pieces_actor, &tx).await?;
+                    }
+                }
+                Message::Bitfield(bytes) => {
+                    pieces_actor.do_send(PeerBitfield {
+                        peer: peer_id,
+                        have: check_bitfield(bytes, &torrent.info)?,
+                    });
+                }
+                Message::Piece { index, begin, data } => {
+                    if pipeline.on_block(index, begin, data.len(), Instant::now()) {
+                        stats.add_downloaded(data.len());
+                        pieces_actor.do_send(BlockReceived {
+                            peer: peer_id,
+                            index,
+                            begin,
+                            data,
+                        });
+                    } else {
+                        log::debug!(
+                            "{}: Ignoring block not requested: index={} begin={}",
+                            &addr,
+                            index,
+                            begin
+                        );
+                    }
+                    if !choked {
+                        request_blocks(&mut pipeline, peer_id, pieces_actor, &tx).await?;
+                    }
+                }
+                Message::Request {
+                    index,
+                    begin,
+                    length,
+                } => {
+                    if choking || !interested {
+                        log::debug!("{}: Ignoring request from choked peer", &addr);
+                        continue;
+                    }
+                    check_request(index, begin, length, &torrent.info)?;
+                    if !have_pieces.get(index as usize).unwrap_or(false) {
+                        log::debug!(
+                            "{}: Ignoring request for a piece we don't have: index={}",
+                            &addr,
+                            index
+                        );
+                        continue;
+                    }
+                    let mut requests = upload_queue.requests.lock().unwrap();
+                    if requests.len() >= MAX_QUEUED_REQUESTS {
+                        anyhow::bail!("Too many queued requests");
+                    }
+                    requests.push_back((index, begin, length));
+                    upload_queue.notify.notify_one();
+                }
+                Message::Cancel {
+                    index,
+                    begin,
+                    length,
+                } => {
+                    upload_queue
+                        .requests
+                        .lock()
+                        .unwrap()
+                        .retain(|request| *request != (index, begin, length));
+                }
+                Message::Extended { id, payload } => {
+                    if !theirs.supports_extensions() {
+                        return Err(ProtocolError::InvalidExtended(
+                            "extension protocol not advertised".to_owned(),
+                        )
+                        .into());
+                    }
+                    for message in extensions.on_message(id, &payload)? {
+                        tx.send(message)
+                            .await
+                            .with_context(|| "Failed to queue Message::Extended")?;
+                    }
+                    if let (HANDSHAKE_ID, Some(handshake)) = (id, extensions.theirs()) {
+                        log::debug!(
+                            "{}: Extension handshake: client={:?} extensions={:?}",
+                            &addr,
+                            handshake.v,
+                            handshake.m
+                        );
+                    }
+                }
+            };
         }
+    }
+    .await;
 
-        // Keep-alive, ignore
-        if advisory_length == 0 {
-            continue;
-        }
+    uploader.abort();
 
-        rd.read_exact(&mut buf[..advisory_length])
-            .await
-            .with_context(|| "Failed to read from peer")?;
-        let message = parse_message(&mut buf[..advisory_length])?;
-        log::debug!("{}: msg={:?}", &addr, &message);
+    let released = pipeline.drain();
+    if !released.is_empty() {
+        pieces_actor.do_send(ReleaseBlocks {
+            peer: peer_id,
+            blocks: released,
+        });
+    }
+    pieces_actor.do_send(PeerGone { peer: peer_id });
+    served.choker.do_send(PeerGone { peer: peer_id });
+    res
+}
 
-        match message {
-            Message::Choke => {
-                choked = true;
-            }
-            Message::Unchoke => {
-                choked = false;
-            }
-            Message::Interested => {
-                interested = true;
-            }
-            Message::NotInterested => {
-                interested = false;
-            }
-            Message::Bitfield(bytes) => {
-                if bytes.len() != torrent.info.pieces_count() {
-                    anyhow::bail!(
-                        "Invalid Message::Bitfield, wrong length: expected={} got={}",
-                        torrent.info.pieces_count(),
-                        bytes.len()
-                    );
-                }
-                have = Some(bytes);
-            }
-            _ => {
-                todo!();
-            }
-        };
+/// Fill the pipeline with requests for blocks the peer has.
+async fn request_blocks(
+    pipeline: &mut RequestPipeline,
+    peer_id: usize,
+    pieces_actor: &Addr<PiecesActor>,
+    tx: &mpsc::Sender<Message>,
+) -> Result<()> {
+    let count = pipeline.capacity();
+    if count == 0 {
+        return Ok(());
+    }
+
+    let blocks = pieces_actor
+        .send(NextBlocks {
+            peer: peer_id,
+            count,
+        })
+        .await?;
+    for block in blocks {
+        pipeline.push(block);
+        tx.send(Message::Request {
+            index: block.index,
+            begin: block.begin,
+            length: block.length,
+        })
+        .await
+        .with_context(|| "Failed to queue Message::Request")?;
     }
+    Ok(())
 }
 
-fn parse_message(buf: &mut [u8]) -> Result<Message> {
-    assert!(!buf.is_empty());
-    assert!(buf.len() < MAX_MESSAGE_LEN);
-    match buf {
-        [] => unreachable!(),
-        [k, ..] if *k == MessageKind::Choke as u8 => Ok(Message::Choke),
-        [k, ..] if *k == MessageKind::Unchoke as u8 => Ok(Message::Unchoke),
-        [k, ..] if *k == MessageKind::Interested as u8 => Ok(Message::Interested),
-        [k, ..] if *k == MessageKind::NotInterested as u8 => Ok(Message::NotInterested),
-        [k, ..] if *k == MessageKind::Have as u8 => {
-            let mut cursor = Cursor::new(&buf[1..]); // Skip tag
-            Ok(Message::Have(ReadBytesExt::read_u32::<BigEndian>(
-                &mut cursor,
-            )?))
-        }
-        [k, ..] if *k == MessageKind::Bitfield as u8 => {
-            Ok(Message::Bitfield(BitVec::from_bytes(&buf[1..])))
-        }
-        [k, ..] if *k == MessageKind::Request as u8 => {
-            let mut cursor = Cursor::new(&buf[1..]); // Skip tag
-            Ok(Message::Request {
-                index: ReadBytesExt::read_u32::<BigEndian>(&mut cursor)?,
-                begin: ReadBytesExt::read_u32::<BigEndian>(&mut cursor)?,
-                length: ReadBytesExt::read_u32::<BigEndian>(&mut cursor)?,
-            })
-        }
-        [k, ..] if *k == MessageKind::Piece as u8 => {
-            let len = buf.len();
-            let mut cursor = Cursor::new(&buf[1..]); // Skip tag
-            let index = ReadBytesExt::read_u32::<BigEndian>(&mut cursor)?;
-            let begin = ReadBytesExt::read_u32::<BigEndian>(&mut cursor)?;
-            let position = cursor.position().min(len as u64) as usize;
-            Ok(Message::Piece {
-                index,
-                begin,
-                data: cursor.into_inner()[position..].to_owned(),
-            })
-        }
-        [k, ..] if *k == MessageKind::Cancel as u8 => {
-            let mut cursor = Cursor::new(&buf[1..]); // Skip tag
-            Ok(Message::Cancel {
-                index: ReadBytesExt::read_u32::<BigEndian>(&mut cursor)?,
-                begin: ReadBytesExt::read_u32::<BigEndian>(&mut cursor)?,
-                length: ReadBytesExt::read_u32::<BigEndian>(&mut cursor)?,
-            })
-        }
-        _ => anyhow::bail!("Unkown message: {:?}", buf),
+/// Check that a requested block is within a piece of the torrent.
+fn check_request(index: u32, begin: u32, length: u32, info: &Info) -> Result<()> {
+    if length == 0 || length > BLOCK_LENGTH {
+        anyhow::bail!("Invalid Message::Request, wrong length: {}", length);
+    }
+    if index as usize >= info.pieces_count()
+        || begin as u64 + length as u64 > info.piece_len(index) as u64
+    {
+        anyhow::bail!(
+            "Invalid Message::Request, out of bounds: index={} begin={} length={}",
+            index,
+            begin,
+            length
+        );
+    }
+    Ok(())
+}
+
+/// Check the length and the spare bits of a received bitfield, and strip the padding.
+fn check_bitfield(mut bitfield: BitVec, info: &Info) -> Result<BitVec, ProtocolError> {
+    if bitfield.len() != info.bitfield_len() * 8 {
+        return Err(ProtocolError::InvalidBitfield(format!(
+            "wrong length: expected={} got={}",
+            info.bitfield_len(),
+            bitfield.len() / 8
+        )));
     }
+    let pieces_count = info.pieces_count();
+    if bitfield.iter().skip(pieces_count).any(|bit| bit) {
+        return Err(ProtocolError::InvalidBitfield(
+            "spare bits are set".to_owned(),
+        ));
+    }
+    bitfield.truncate(pieces_count);
+    Ok(bitfield)
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::{message::Message, message::MessageKind, net::parse_message};
+    use actix::Actor;
+    use bit_vec::BitVec;
+    use futures_util::{SinkExt, StreamExt};
+    use sha1::{Digest, Sha1};
+    use std::collections::HashMap;
+    use std::env;
+    use std::net::{Ipv6Addr, SocketAddr};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::sync::mpsc;
+    use tokio_util::codec::Framed;
+
+    use crate::{
+        choker::ChokerActor,
+        codec::PeerCodec,
+        extension::ExtensionRegistry,
+        fs::FileActor,
+        handshake::{ConnectedPeerIds, HandshakeError, PeerHandshake},
+        message::{Message, HANDSHAKE, PEER_ID},
+        metadata::{SharedMetadata, UtMetadata, METADATA_PIECE_LEN},
+        net::{
+            accept_peers, bind_listener, check_bitfield, check_request, fetch_metadata, handshake,
+            ServedTorrent,
+        },
+        pieces::PiecesActor,
+        pipeline::PipelineConfig,
+        torrent_file::{decode_torrent, Info},
+        tracker::{info_hash, Peer},
+    };
+
+    fn info(length: usize, piece_length: u32) -> Info {
+        let bytes = format!(
+            "d6:lengthi{}e4:name4:test12:piece lengthi{}e6:pieces0:e",
+            length, piece_length
+        );
+        serde_bencode::from_bytes(bytes.as_bytes()).unwrap()
+    }
 
     #[test]
-    fn parse_message_bitfield() -> Result<(), String> {
-        match parse_message(&mut [MessageKind::Bitfield as u8, 0b0000_0001, 0b1000_0010]) {
-            Ok(Message::Bitfield(bytes))
-                if bytes.eq_vec(&[
-                    false, false, false, false, false, false, false, true, true, false, false,
-                    false, false, false, true, false,
-                ]) =>
-            {
-                Ok(())
-            }
-            other => Err(format!("Got {:#?}", other)),
-        }
+    fn check_bitfield_should_strip_padding() {
+        let info = info(10 * 16384, 16384);
+        let bitfield = check_bitfield(BitVec::from_bytes(&[0xff, 0b1100_0000]), &info).unwrap();
+        assert_eq!(bitfield.len(), 10);
+        assert!(bitfield.all());
     }
 
     #[test]
-    fn parse_message_request() {
-        let mut bytes = vec![MessageKind::Request as u8];
-        bytes.extend_from_slice(&u32::to_be_bytes(0xcafe));
-        bytes.extend_from_slice(&u32::to_be_bytes(0xabcd));
-        bytes.extend_from_slice(&u32::to_be_bytes(0xef12));
-        assert_eq!(
-            parse_message(&mut bytes).unwrap(),
-            Message::Request {
-                index: 0xcafe,
-                begin: 0xabcd,
-                length: 0xef12,
-            }
-        );
+    fn check_bitfield_should_reject_spare_bits_and_wrong_length() {
+        let info = info(10 * 16384, 16384);
+        assert!(check_bitfield(BitVec::from_bytes(&[0xff, 0b1110_0000]), &info).is_err());
+        assert!(check_bitfield(BitVec::from_bytes(&[0xff]), &info).is_err());
+        assert!(check_bitfield(BitVec::from_bytes(&[0xff, 0, 0]), &info).is_err());
     }
 
     #[test]
-    fn parse_message_piece() {
-        let mut bytes = vec![MessageKind::Piece as u8];
-        bytes.extend_from_slice(&u32::to_be_bytes(0xcafe));
-        bytes.extend_from_slice(&u32::to_be_bytes(0xabcd));
-        bytes.extend_from_slice(&[7, 8, 9, 10, 11]);
-        assert_eq!(
-            parse_message(&mut bytes).unwrap(),
-            Message::Piece {
-                index: 0xcafe,
-                begin: 0xabcd,
-                data: vec![7, 8, 9, 10, 11],
-            }
+    fn check_request_should_reject_blocks_out_of_the_pieces() {
+        let info = info(2 * 16384 + 10, 2 * 16384);
+        assert!(check_request(0, 16384, 16384, &info).is_ok());
+        assert!(check_request(1, 0, 10, &info).is_ok());
+        assert!(check_request(0, 16384, 16385, &info).is_err());
+        assert!(check_request(0, 0, 0, &info).is_err());
+        assert!(check_request(1, 8, 3, &info).is_err());
+        assert!(check_request(2, 0, 1, &info).is_err());
+        assert!(check_request(0, u32::MAX, 16384, &info).is_err());
+    }
+
+    fn served_torrents(name: &str) -> ([u8; 20], Arc<HashMap<[u8; 20], ServedTorrent>>) {
+        let mut content =
+            b"d4:infod6:lengthi10e4:name4:test12:piece lengthi16384e6:pieces20:".to_vec();
+        content.extend_from_slice(&[0u8; 20]);
+        content.extend_from_slice(b"ee");
+        let torrent = Arc::new(decode_torrent(&content).unwrap());
+        let info_hash = info_hash(&torrent);
+
+        let mut path = env::temp_dir();
+        path.push(name);
+        let file_actor = FileActor::new(&path, 10, 16384).unwrap().start();
+        let pieces_actor = PiecesActor::new(&torrent.info, file_actor).start();
+        let choker = ChokerActor::new(4, pieces_actor.clone()).start();
+
+        let mut torrents = HashMap::new();
+        torrents.insert(
+            info_hash,
+            ServedTorrent {
+                torrent,
+                pieces_actor,
+                choker,
+                pipeline: PipelineConfig::default(),
+                peer_ids: ConnectedPeerIds::default(),
+                port: 6881,
+                extensions: Vec::new(),
+            },
         );
+        (info_hash, Arc::new(torrents))
     }
 
-    #[test]
-    fn parse_message_cancel() {
-        let mut bytes = vec![MessageKind::Cancel as u8];
-        bytes.extend_from_slice(&u32::to_be_bytes(0xcafe));
-        bytes.extend_from_slice(&u32::to_be_bytes(0xabcd));
-        bytes.extend_from_slice(&u32::to_be_bytes(0xef12));
+    #[actix::test]
+    async fn incoming_handshake_should_be_answered_for_served_torrent() {
+        let (info_hash, torrents) =
+            served_torrents("sharku_incoming_handshake_should_be_answered_for_served_torrent");
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let listen_addr = listener.local_addr().unwrap();
+        tokio::spawn(accept_peers(listener, torrents));
+
+        let mut socket = TcpStream::connect(listen_addr).await.unwrap();
+        socket.write_all(HANDSHAKE).await.unwrap();
+        socket.write_all(&info_hash).await.unwrap();
+        socket.write_all(b"-XX0000-000000000000").await.unwrap();
+
+        let mut buf = [0u8; 68];
+        socket.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf[..20], &HANDSHAKE[..20]);
+        assert_eq!(&buf[28..48], &info_hash);
+        assert_eq!(&buf[48..], PEER_ID);
+    }
+
+    #[actix::test]
+    async fn incoming_handshake_should_be_rejected_for_unknown_torrent() {
+        let (_, torrents) =
+            served_torrents("sharku_incoming_handshake_should_be_rejected_for_unknown_torrent");
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let listen_addr = listener.local_addr().unwrap();
+        tokio::spawn(accept_peers(listener, torrents));
+
+        let mut socket = TcpStream::connect(listen_addr).await.unwrap();
+        socket.write_all(HANDSHAKE).await.unwrap();
+        socket.write_all(&[0xab; 20]).await.unwrap();
+
+        let mut buf = Vec::new();
+        socket.read_to_end(&mut buf).await.unwrap();
+        assert!(buf.is_empty());
+    }
+
+    #[actix::test]
+    async fn incoming_handshake_should_reject_already_connected_peer_id() {
+        let (info_hash, torrents) =
+            served_torrents("sharku_incoming_handshake_should_reject_already_connected_peer_id");
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let listen_addr = listener.local_addr().unwrap();
+        tokio::spawn(accept_peers(listener, torrents));
+
+        let theirs = PeerHandshake {
+            peer_id: *b"-XX0000-000000000000",
+            ..PeerHandshake::ours(info_hash)
+        };
+        let mut first = TcpStream::connect(listen_addr).await.unwrap();
+        first.write_all(&theirs.to_bytes()).await.unwrap();
+        let mut buf = [0u8; 68];
+        first.read_exact(&mut buf).await.unwrap();
+
+        // Answered, then closed once the peer_id is known
+        let mut second = TcpStream::connect(listen_addr).await.unwrap();
+        second.write_all(&theirs.to_bytes()).await.unwrap();
+        let mut buf = Vec::new();
+        second.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf.len(), 68);
+    }
+
+    #[actix::test]
+    async fn outgoing_handshake_should_check_the_peer() {
+        let info_hash = [7; 20];
+        let answer = |theirs: PeerHandshake| async move {
+            let (mut ours, mut peer) = tokio::io::duplex(1024);
+            peer.write_all(&theirs.to_bytes()).await.unwrap();
+            let res = handshake(&mut ours, &info_hash, "test").await;
+            let mut buf = [0u8; 68];
+            peer.read_exact(&mut buf).await.unwrap();
+            assert_eq!(buf, PeerHandshake::ours(info_hash).to_bytes());
+            res
+        };
+
+        let theirs = PeerHandshake {
+            reserved: [0, 0, 0, 0, 0, 0x10, 0, 0],
+            info_hash,
+            peer_id: *b"-TR3000-000000000000",
+        };
+        assert_eq!(answer(theirs.clone()).await.unwrap(), theirs);
+
+        let other_torrent = PeerHandshake {
+            info_hash: [8; 20],
+            ..theirs.clone()
+        };
+        let err = answer(other_torrent).await.unwrap_err();
         assert_eq!(
-            parse_message(&mut bytes).unwrap(),
-            Message::Cancel {
-                index: 0xcafe,
-                begin: 0xabcd,
-                length: 0xef12,
-            }
+            err.downcast_ref::<HandshakeError>(),
+            Some(&HandshakeError::InfoHashMismatch)
         );
+
+        let err = answer(PeerHandshake::ours(info_hash)).await.unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<HandshakeError>(),
+            Some(&HandshakeError::OwnPeerId)
+        );
+    }
+
+    #[actix::test]
+    async fn ipv6_listener_should_share_the_port_with_ipv4() {
+        let v4 = bind_listener("0.0.0.0:0".parse().unwrap()).unwrap();
+        let port = v4.local_addr().unwrap().port();
+        let v6 = bind_listener(SocketAddr::from((Ipv6Addr::UNSPECIFIED, port))).unwrap();
+
+        let connect = TcpStream::connect(SocketAddr::from((Ipv6Addr::LOCALHOST, port)));
+        let (connected, accepted) = tokio::join!(connect, v6.accept());
+        connected.unwrap();
+        assert!(accepted.unwrap().1.is_ipv6());
+    }
+
+    #[actix::test]
+    async fn metadata_should_be_fetched_from_peers() {
+        let mut info = b"d6:lengthi10e4:name4:test12:piece lengthi16384e6:pieces20:".to_vec();
+        info.extend_from_slice(&[0u8; 20]);
+        info.push(b'e');
+        fetch_from_seeder(info).await;
+    }
+
+    #[actix::test]
+    async fn metadata_of_several_pieces_should_be_fetched() {
+        let mut info =
+            b"d6:lengthi40960000e4:name4:test12:piece lengthi16384e6:pieces50000:".to_vec();
+        info.extend_from_slice(&[7u8; 50000]);
+        info.push(b'e');
+        assert!(info.len() > 3 * METADATA_PIECE_LEN);
+        fetch_from_seeder(info).await;
+    }
+
+    /// Fetch `info` from a seeder that only knows about the metadata.
+    async fn fetch_from_seeder(info: Vec<u8>) {
+        let info_hash: [u8; 20] = Sha1::digest(&info).into();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let seeder_addr = listener.local_addr().unwrap();
+        let seeder = SharedMetadata::known(info_hash, info.clone());
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 68];
+            socket.read_exact(&mut buf).await.unwrap();
+            let theirs = PeerHandshake {
+                peer_id: *b"-XX0000-000000000000",
+                ..PeerHandshake::ours(info_hash)
+            };
+            socket.write_all(&theirs.to_bytes()).await.unwrap();
+            let mut framed = Framed::new(socket, PeerCodec);
+            let mut extensions = ExtensionRegistry::new(vec![Box::new(UtMetadata::new(seeder))]);
+            framed
+                .send(extensions.handshake(6881, 250, seeder_addr.ip()))
+                .await
+                .unwrap();
+            while let Some(Ok(Message::Extended { id, payload })) = framed.next().await {
+                for message in extensions.on_message(id, &payload).unwrap() {
+                    framed.send(message).await.unwrap();
+                }
+            }
+        });
+
+        let (peers_tx, mut peers_rx) = mpsc::unbounded_channel();
+        let peer = Peer {
+            ip: seeder_addr.ip(),
+            port: seeder_addr.port(),
+        };
+        peers_tx.send(vec![peer.clone(), peer.clone()]).unwrap();
+        let fetching = fetch_metadata(&mut peers_rx, SharedMetadata::fetching(info_hash), 6881, 8);
+        // A rejected message gets the seeder banned, and the fetch waits for other peers
+        let (fetched, peers) = tokio::time::timeout(Duration::from_secs(10), fetching)
+            .await
+            .expect("Timed out fetching the metadata")
+            .unwrap();
+        assert_eq!(*fetched, info);
+        assert_eq!(peers, vec![peer]);
     }
 }
@@ -1,7 +1,59 @@
 use actix::prelude::*;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::sync::mpsc;
 
-use crate::message::Message as M;
+use crate::message::{Block, Message as M};
 
+/// Sent to a peer connection by the other actors.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PeerCommand {
+    /// The block was received from another peer: cancel its request.
+    Cancel(Block),
+    Choke,
+    Unchoke,
+    /// We verified the piece: tell the peer we have it.
+    Have(u32),
+}
+
+/// Counters of a peer connection, updated by the connection and read by the choker.
+#[derive(Debug, Default)]
+pub struct PeerStats {
+    /// Bytes of the requested blocks received from the peer
+    pub downloaded: AtomicU64,
+    /// Bytes of blocks sent to the peer
+    pub uploaded: AtomicU64,
+    /// The peer is interested in our pieces
+    pub interested: AtomicBool,
+}
+
+impl PeerStats {
+    pub fn add_downloaded(&self, bytes: usize) {
+        self.downloaded.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn add_uploaded(&self, bytes: usize) {
+        self.uploaded.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+}
+
+/// A peer connected: `commands` is how to reach it.
+#[derive(Debug, Clone, Message)]
+#[rtype(result = "()")]
+pub struct PeerConnected {
+    pub peer: usize,
+    pub commands: mpsc::UnboundedSender<PeerCommand>,
+    pub stats: Arc<PeerStats>,
+}
+
+/// A peer disconnected.
+#[derive(Debug, Clone, Copy, Message)]
+#[rtype(result = "()")]
+pub struct PeerGone {
+    pub peer: usize,
+}
+
+#[allow(dead_code)]
 pub struct PeerActor {
     choked: bool,
     interested: bool,
@@ -17,17 +69,22 @@ impl Handler<M> for PeerActor {
     type Result = ();
 
     fn handle(&mut self, msg: M, _: &mut Context<Self>) -> Self::Result {
-        println!("Msg={:?}", msg);
         match msg {
             M::Choke => self.peer_choked = true,
             M::Unchoke => self.peer_choked = false,
             M::Interested => self.peer_interested = true,
             M::NotInterested => self.peer_interested = false,
-            _ => todo!(),
+            _ => log::debug!("Ignoring message: {:?}", msg.tag()),
         }
     }
 }
 
+impl Default for PeerActor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl PeerActor {
     pub fn new() -> Self {
         PeerActor {
@@ -0,0 +1,354 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use std::collections::HashSet;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+use crate::extension::{Extension, ExtensionHandshake};
+use crate::tracker::{decode_compact_peers, decode_compact_peers6, Peer};
+
+/// We send at most one message per minute to each peer.
+const PEX_INTERVAL: Duration = Duration::from_secs(60);
+/// Messages from a peer closer than that are ignored, with some slack for timers.
+const MIN_RECEIVE_INTERVAL: Duration = Duration::from_secs(45);
+/// Added and dropped peers per message, in each direction.
+const MAX_PEERS_PER_MESSAGE: usize = 50;
+/// New peers accepted from one connection, over its lifetime.
+const MAX_PEERS_PER_CONNECTION: usize = 500;
+
+/// A ut_pex message. The `.f` flags have one byte per added peer.
+#[derive(Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+struct PexMessage {
+    #[serde(default)]
+    added: ByteBuf,
+    #[serde(default, rename = "added.f")]
+    added_f: ByteBuf,
+    #[serde(default)]
+    added6: ByteBuf,
+    #[serde(default, rename = "added6.f")]
+    added6_f: ByteBuf,
+    #[serde(default)]
+    dropped: ByteBuf,
+    #[serde(default)]
+    dropped6: ByteBuf,
+}
+
+/// The peers connected for a torrent, by the address they listen on.
+#[derive(Debug, Clone, Default)]
+pub struct PexSwarm(Arc<Mutex<HashSet<SocketAddr>>>);
+
+impl PexSwarm {
+    fn peers(&self) -> HashSet<SocketAddr> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+/// The ut_pex extension for one connection (BEP 11): tells the peer about the other connected
+/// peers, and hands the ones it tells us about to the connection code.
+pub struct UtPex {
+    swarm: PexSwarm,
+    peer_addr: SocketAddr,
+    /// The peer connected to us, from a port it does not listen on
+    incoming: bool,
+    /// Where the peer listens, once it sent its extension handshake. Only the peers that support
+    /// ut_pex are advertised, as the others may not tell their port.
+    listen_addr: Option<SocketAddr>,
+    /// Peers the peer knows about from us
+    sent: HashSet<SocketAddr>,
+    last_sent: Option<Instant>,
+    last_received: Option<Instant>,
+    /// Peers learned from the peer, to only count the new ones against the limit
+    learned: HashSet<SocketAddr>,
+    peers: mpsc::UnboundedSender<Vec<Peer>>,
+}
+
+impl UtPex {
+    pub fn new(
+        swarm: PexSwarm,
+        peer_addr: SocketAddr,
+        incoming: bool,
+        peers: mpsc::UnboundedSender<Vec<Peer>>,
+    ) -> Self {
+        UtPex {
+            swarm,
+            peer_addr,
+            incoming,
+            listen_addr: None,
+            sent: HashSet::new(),
+            last_sent: None,
+            last_received: None,
+            learned: HashSet::new(),
+            peers,
+        }
+    }
+
+    fn on_message_at(&mut self, payload: &[u8], now: Instant) -> Result<()> {
+        let message: PexMessage =
+            serde_bencode::from_bytes(payload).context("Invalid ut_pex message")?;
+        let mut added = decode_compact_peers(&message.added)?;
+        added.extend(decode_compact_peers6(&message.added6)?);
+        if let Some(last) = self.last_received {
+            if now.duration_since(last) < MIN_RECEIVE_INTERVAL {
+                log::debug!("{}: Ignoring ut_pex message sent too soon", self.peer_addr);
+                return Ok(());
+            }
+        }
+        self.last_received = Some(now);
+        if added.len() > MAX_PEERS_PER_MESSAGE {
+            log::debug!(
+                "{}: Too many peers in ut_pex message: {}",
+                self.peer_addr,
+                added.len()
+            );
+            added.truncate(MAX_PEERS_PER_MESSAGE);
+        }
+        // The dropped peers may still be reachable: leave them to the connection code
+        let mut new_peers = Vec::new();
+        for peer in added {
+            let addr = SocketAddr::new(peer.ip, peer.port);
+            if self.learned.len() >= MAX_PEERS_PER_CONNECTION {
+                log::debug!("{}: Ignoring further ut_pex peers", self.peer_addr);
+                break;
+            }
+            if self.is_plausible(&addr) && self.learned.insert(addr) {
+                new_peers.push(peer);
+            }
+        }
+        if !new_peers.is_empty() {
+            log::debug!("{}: ut_pex peers: {}", self.peer_addr, new_peers.len());
+            let _ = self.peers.send(new_peers);
+        }
+        Ok(())
+    }
+
+    /// Not an address a peer can listen on, or one only the peer could make us connect to.
+    fn is_plausible(&self, addr: &SocketAddr) -> bool {
+        let ip = addr.ip();
+        let special = match ip {
+            IpAddr::V4(ip) => ip.is_broadcast() || ip.is_unspecified() || ip.is_multicast(),
+            IpAddr::V6(ip) => ip.is_unspecified() || ip.is_multicast(),
+        };
+        addr.port() != 0
+            && !special
+            && (!ip.is_loopback() || self.peer_addr.ip().is_loopback())
+            && Some(*addr) != self.listen_addr
+    }
+
+    fn tick_at(&mut self, now: Instant) -> Vec<Vec<u8>> {
+        if matches!(self.last_sent, Some(last) if now.duration_since(last) < PEX_INTERVAL) {
+            return Vec::new();
+        }
+        let mut current = self.swarm.peers();
+        if let Some(listen_addr) = &self.listen_addr {
+            current.remove(listen_addr);
+        }
+        let added: Vec<SocketAddr> = current
+            .difference(&self.sent)
+            .copied()
+            .take(MAX_PEERS_PER_MESSAGE)
+            .collect();
+        let dropped: Vec<SocketAddr> = self
+            .sent
+            .difference(&current)
+            .copied()
+            .take(MAX_PEERS_PER_MESSAGE)
+            .collect();
+        if added.is_empty() && dropped.is_empty() {
+            return Vec::new();
+        }
+
+        let mut message = PexMessage::default();
+        for addr in &added {
+            let (compact, flags) = match addr {
+                SocketAddr::V4(_) => (&mut message.added, &mut message.added_f),
+                SocketAddr::V6(_) => (&mut message.added6, &mut message.added6_f),
+            };
+            push_compact(compact, addr);
+            // We do not know about encryption, seeding or uTP
+            flags.push(0);
+            self.sent.insert(*addr);
+        }
+        for addr in &dropped {
+            let compact = match addr {
+                SocketAddr::V4(_) => &mut message.dropped,
+                SocketAddr::V6(_) => &mut message.dropped6,
+            };
+            push_compact(compact, addr);
+            self.sent.remove(addr);
+        }
+        self.last_sent = Some(now);
+        vec![serde_bencode::to_bytes(&message).unwrap()]
+    }
+}
+
+impl Drop for UtPex {
+    fn drop(&mut self) {
+        if let Some(listen_addr) = &self.listen_addr {
+            self.swarm.0.lock().unwrap().remove(listen_addr);
+        }
+    }
+}
+
+impl Extension for UtPex {
+    fn name(&self) -> &'static str {
+        "ut_pex"
+    }
+
+    fn on_handshake(&mut self, handshake: &ExtensionHandshake) -> Result<Vec<Vec<u8>>> {
+        // The port of an incoming connection is not the one the peer listens on
+        let port = match handshake.p.filter(|&port| port != 0) {
+            Some(port) => Some(port),
+            None if !self.incoming => Some(self.peer_addr.port()),
+            None => None,
+        };
+        let listen_addr = port.map(|port| SocketAddr::new(self.peer_addr.ip(), port));
+        let mut swarm = self.swarm.0.lock().unwrap();
+        if let Some(previous) = std::mem::replace(&mut self.listen_addr, listen_addr) {
+            swarm.remove(&previous);
+        }
+        if let Some(listen_addr) = listen_addr {
+            swarm.insert(listen_addr);
+        }
+        Ok(Vec::new())
+    }
+
+    fn on_message(&mut self, payload: &[u8]) -> Result<Vec<Vec<u8>>> {
+        self.on_message_at(payload, Instant::now())?;
+        Ok(Vec::new())
+    }
+
+    fn tick(&mut self) -> Result<Vec<Vec<u8>>> {
+        Ok(self.tick_at(Instant::now()))
+    }
+}
+
+fn push_compact(compact: &mut ByteBuf, addr: &SocketAddr) {
+    match addr.ip() {
+        IpAddr::V4(ip) => compact.extend_from_slice(&ip.octets()),
+        IpAddr::V6(ip) => compact.extend_from_slice(&ip.octets()),
+    }
+    compact.extend_from_slice(&addr.port().to_be_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_bytes::ByteBuf;
+    use std::net::SocketAddr;
+    use std::time::{Duration, Instant};
+    use tokio::sync::mpsc;
+
+    use crate::extension::{Extension, ExtensionHandshake};
+    use crate::pex::{PexMessage, PexSwarm, UtPex, MAX_PEERS_PER_MESSAGE};
+    use crate::tracker::Peer;
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    fn connect(swarm: &PexSwarm, peer_addr: &str, incoming: bool, port: Option<u16>) -> UtPex {
+        let (peers_tx, _) = mpsc::unbounded_channel();
+        let mut pex = UtPex::new(swarm.clone(), addr(peer_addr), incoming, peers_tx);
+        let handshake = ExtensionHandshake {
+            p: port,
+            ..Default::default()
+        };
+        pex.on_handshake(&handshake).unwrap();
+        pex
+    }
+
+    fn decode(payloads: Vec<Vec<u8>>) -> PexMessage {
+        assert_eq!(payloads.len(), 1);
+        serde_bencode::from_bytes(&payloads[0]).unwrap()
+    }
+
+    #[test]
+    fn connected_peers_should_be_sent_once_a_minute() {
+        let swarm = PexSwarm::default();
+        let mut first = connect(&swarm, "10.0.0.1:6881", false, None);
+        let second = connect(&swarm, "10.0.0.2:50000", true, Some(6882));
+        let third = connect(&swarm, "[2001:db8::1]:6881", false, None);
+
+        let start = Instant::now();
+        let message = decode(first.tick_at(start));
+        assert_eq!(message.added.as_slice(), &[10, 0, 0, 2, 0x1a, 0xe2]);
+        assert_eq!(message.added_f.as_slice(), &[0]);
+        assert_eq!(message.added6.len(), 18);
+        assert_eq!(message.added6_f.as_slice(), &[0]);
+        assert!(message.dropped.is_empty());
+
+        drop(second);
+        drop(third);
+        let _fourth = connect(&swarm, "10.0.0.4:6881", false, None);
+        assert!(first.tick_at(start + Duration::from_secs(30)).is_empty());
+        let message = decode(first.tick_at(start + Duration::from_secs(60)));
+        assert_eq!(message.added.as_slice(), &[10, 0, 0, 4, 0x1a, 0xe1]);
+        assert_eq!(message.dropped.as_slice(), &[10, 0, 0, 2, 0x1a, 0xe2]);
+        assert_eq!(message.dropped6.len(), 18);
+
+        // Nothing changed
+        assert!(first.tick_at(start + Duration::from_secs(120)).is_empty());
+    }
+
+    #[test]
+    fn incoming_peers_should_be_advertised_only_with_their_port() {
+        let swarm = PexSwarm::default();
+        let mut first = connect(&swarm, "10.0.0.1:6881", false, None);
+        let _ephemeral = connect(&swarm, "10.0.0.2:50000", true, None);
+        let _listening = connect(&swarm, "10.0.0.3:50001", true, Some(6883));
+        let message = decode(first.tick_at(Instant::now()));
+        assert_eq!(message.added.as_slice(), &[10, 0, 0, 3, 0x1a, 0xe3]);
+    }
+
+    #[test]
+    fn received_peers_should_be_checked_and_limited() {
+        let (peers_tx, mut peers_rx) = mpsc::unbounded_channel();
+        let mut pex = UtPex::new(PexSwarm::default(), addr("10.0.0.1:6881"), false, peers_tx);
+
+        let mut added = Vec::new();
+        for peer in [
+            [10, 0, 0, 2, 0x1a, 0xe1],
+            // Port 0, broadcast, loopback, multicast
+            [10, 0, 0, 3, 0, 0],
+            [255, 255, 255, 255, 0x1a, 0xe1],
+            [127, 0, 0, 1, 0x1a, 0xe1],
+            [224, 0, 0, 1, 0x1a, 0xe1],
+            // Duplicate
+            [10, 0, 0, 2, 0x1a, 0xe1],
+        ] {
+            added.extend_from_slice(&peer);
+        }
+        for i in 0..MAX_PEERS_PER_MESSAGE as u8 {
+            added.extend_from_slice(&[192, 168, 0, i, 0x1a, 0xe1]);
+        }
+        let message = PexMessage {
+            added: ByteBuf::from(added),
+            ..Default::default()
+        };
+        let payload = serde_bencode::to_bytes(&message).unwrap();
+        let start = Instant::now();
+        pex.on_message_at(&payload, start).unwrap();
+        let peers = peers_rx.try_recv().unwrap();
+        assert_eq!(
+            peers[0],
+            Peer {
+                ip: "10.0.0.2".parse().unwrap(),
+                port: 6881
+            }
+        );
+        // Truncated to the first 50
+        assert_eq!(peers.len(), 1 + MAX_PEERS_PER_MESSAGE - 6);
+
+        // Too soon, then already known
+        pex.on_message_at(&payload, start + Duration::from_secs(10))
+            .unwrap();
+        pex.on_message_at(&payload, start + Duration::from_secs(60))
+            .unwrap();
+        assert!(peers_rx.try_recv().is_err());
+
+        assert!(pex.on_message_at(b"d5:added5:12345e", start).is_err());
+        assert!(pex.on_message_at(b"not bencode", start).is_err());
+    }
+}
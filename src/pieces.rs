@@ -1,40 +1,825 @@
 use actix::prelude::*;
+use anyhow::{bail, Result};
 use bit_vec::BitVec;
+use rand::Rng;
+use sha1::{Digest, Sha1};
+use std::collections::HashMap;
+use tokio::sync::mpsc;
 
-use crate::message::{Message as M, BLOCK_LENGTH};
+use crate::fs::{FileActor, ReadBlock};
+use crate::message::{Block, Message as M, BLOCK_LENGTH};
+use crate::peer::{PeerCommand, PeerConnected, PeerGone};
+use crate::state::DownloadState;
+use crate::torrent_file::{Info, PieceGeometry};
+
+/// Ask for up to `count` blocks to request from a peer.
+#[derive(Message)]
+#[rtype(result = "Vec<Block>")]
+pub struct NextBlocks {
+    pub peer: usize,
+    pub count: usize,
+}
+
+/// A peer sent the bitfield of the pieces it has.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct PeerBitfield {
+    pub peer: usize,
+    pub have: BitVec,
+}
+
+/// A peer announced it has a new piece.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct PeerHave {
+    pub peer: usize,
+    pub index: u32,
+}
+
+/// Blocks requested from a peer that will not be received, e.g. because it choked us or
+/// disconnected, so that they can be requested again.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct ReleaseBlocks {
+    pub peer: usize,
+    pub blocks: Vec<Block>,
+}
+
+/// A block requested from a peer was received.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct BlockReceived {
+    pub peer: usize,
+    pub index: u32,
+    pub begin: u32,
+    pub data: Vec<u8>,
+}
+
+/// Get the bitfield of the verified pieces.
+#[derive(Message)]
+#[rtype(result = "BitVec")]
+pub struct HavePieces;
+
+/// Get the amounts of data transferred and left, for the tracker.
+#[derive(Message)]
+#[rtype(result = "DownloadState")]
+pub struct GetDownloadState;
+
+/// All the pieces are verified.
+#[derive(Message, Clone, Copy)]
+#[rtype(result = "()")]
+pub struct DownloadCompleted;
+
+/// Be notified when all the pieces are verified.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct SubscribeCompleted(pub Recipient<DownloadCompleted>);
+
+/// Blocks of a piece received so far, kept in memory until the piece is complete.
+struct PartialPiece {
+    data: Vec<u8>,
+    have_blocks: BitVec,
+    // Peers each block is requested from: several in endgame mode
+    requested_from: Vec<Vec<usize>>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum BlockOutcome {
+    Incomplete,
+    Verified(Vec<u8>),
+    Corrupt,
+}
 
 pub struct PiecesActor {
-    // have_pieces: BitVec,
-    have_chunks: BitVec,
-    blocks_per_piece: usize,
+    have_pieces: BitVec,
+    partial_pieces: HashMap<u32, PartialPiece>,
+    // Neither verified nor in progress
+    pending_pieces: BitVec,
+    // Pieces of each connected peer
+    peers: HashMap<usize, BitVec>,
+    // How many connected peers have each piece
+    availability: Vec<u32>,
+    connections: HashMap<usize, mpsc::UnboundedSender<PeerCommand>>,
+    endgame: bool,
+    piece_hashes: Vec<[u8; 20]>,
+    geometry: PieceGeometry,
+    file_actor: Addr<FileActor>,
+    // Bytes of verified pieces received, and of blocks read for peers
+    downloaded: u64,
+    uploaded: u64,
+    completed_subscribers: Vec<Recipient<DownloadCompleted>>,
 }
 
 impl Actor for PiecesActor {
     type Context = Context<Self>;
 }
 
-impl Handler<M> for PiecesActor {
+impl Handler<BlockReceived> for PiecesActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: BlockReceived, _: &mut Context<Self>) -> Self::Result {
+        let BlockReceived {
+            peer,
+            index,
+            begin,
+            data,
+        } = msg;
+        log::debug!("Block: index={} begin={} len={}", index, begin, data.len());
+        match self.add_block(peer, index, begin, data) {
+            Ok(BlockOutcome::Incomplete) => {}
+            Ok(BlockOutcome::Verified(data)) => {
+                log::debug!("Piece verified: index={}", index);
+                self.have_pieces.set(index as usize, true);
+                self.downloaded += data.len() as u64;
+                for commands in self.connections.values() {
+                    let _ = commands.send(PeerCommand::Have(index));
+                }
+                self.file_actor.do_send(M::Piece {
+                    index,
+                    begin: 0,
+                    data,
+                });
+                if self.have_pieces.all() {
+                    log::info!("Download completed");
+                    for subscriber in &self.completed_subscribers {
+                        let _ = subscriber.do_send(DownloadCompleted);
+                    }
+                }
+            }
+            Ok(BlockOutcome::Corrupt) => {
+                log::warn!("Piece hash mismatch, re-scheduling: index={}", index);
+                self.pending_pieces.set(index as usize, true);
+            }
+            Err(err) => log::warn!("Invalid block: {}", err),
+        }
+    }
+}
+
+impl Handler<PeerConnected> for PiecesActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: PeerConnected, _: &mut Context<Self>) -> Self::Result {
+        self.connections.insert(msg.peer, msg.commands);
+    }
+}
+
+impl Handler<NextBlocks> for PiecesActor {
+    type Result = MessageResult<NextBlocks>;
+
+    fn handle(&mut self, msg: NextBlocks, _: &mut Context<Self>) -> Self::Result {
+        MessageResult(self.next_blocks(msg.peer, msg.count))
+    }
+}
+
+impl Handler<PeerBitfield> for PiecesActor {
     type Result = ();
 
-    fn handle(&mut self, msg: M, _: &mut Context<Self>) -> Self::Result {
-        println!("Msg={:?}", msg);
-        match msg {
-            M::Piece { index, .. } => {
-                self.have_chunks
-                    .set(index as usize * self.blocks_per_piece, true)
-                //  TODO: have_pieces, checksum
+    fn handle(&mut self, msg: PeerBitfield, _: &mut Context<Self>) -> Self::Result {
+        self.remove_peer(msg.peer);
+        for (index, has) in msg.have.iter().enumerate().take(self.availability.len()) {
+            if has {
+                self.availability[index] += 1;
             }
-            _ => todo!(),
         }
+        self.peers.insert(msg.peer, msg.have);
+    }
+}
+
+impl Handler<PeerHave> for PiecesActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: PeerHave, _: &mut Context<Self>) -> Self::Result {
+        let pieces_count = self.availability.len();
+        let index = msg.index as usize;
+        if index >= pieces_count {
+            return;
+        }
+        let have = self
+            .peers
+            .entry(msg.peer)
+            .or_insert_with(|| BitVec::from_elem(pieces_count, false));
+        if !have[index] {
+            have.set(index, true);
+            self.availability[index] += 1;
+        }
+    }
+}
+
+impl Handler<PeerGone> for PiecesActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: PeerGone, _: &mut Context<Self>) -> Self::Result {
+        self.remove_peer(msg.peer);
+        self.connections.remove(&msg.peer);
+    }
+}
+
+impl Handler<ReleaseBlocks> for PiecesActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: ReleaseBlocks, _: &mut Context<Self>) -> Self::Result {
+        self.release_blocks(msg.peer, &msg.blocks);
+    }
+}
+
+impl Handler<HavePieces> for PiecesActor {
+    type Result = MessageResult<HavePieces>;
+
+    fn handle(&mut self, _: HavePieces, _: &mut Context<Self>) -> Self::Result {
+        MessageResult(self.have_pieces.clone())
+    }
+}
+
+impl Handler<ReadBlock> for PiecesActor {
+    type Result = ResponseFuture<Result<Vec<u8>>>;
+
+    /// Only blocks of verified pieces are read from disk.
+    fn handle(&mut self, msg: ReadBlock, _: &mut Context<Self>) -> Self::Result {
+        let index = msg.index as usize;
+        if index >= self.have_pieces.len() || !self.have_pieces[index] {
+            return Box::pin(async move { bail!("Piece not available: index={}", msg.index) });
+        }
+        let piece_len = self.geometry.piece_len(msg.index) as u64;
+        if msg.begin as u64 + msg.length as u64 > piece_len {
+            return Box::pin(async move {
+                bail!(
+                    "Block out of bounds: index={} begin={} length={}",
+                    msg.index,
+                    msg.begin,
+                    msg.length
+                )
+            });
+        }
+
+        self.uploaded += msg.length as u64;
+        let read = self.file_actor.send(msg);
+        Box::pin(async move { read.await? })
+    }
+}
+
+impl Handler<GetDownloadState> for PiecesActor {
+    type Result = MessageResult<GetDownloadState>;
+
+    fn handle(&mut self, _: GetDownloadState, _: &mut Context<Self>) -> Self::Result {
+        let have: u64 = self
+            .have_pieces
+            .iter()
+            .enumerate()
+            .filter(|(_, have)| *have)
+            .map(|(index, _)| self.geometry.piece_len(index as u32) as u64)
+            .sum();
+        MessageResult(DownloadState {
+            uploaded: self.uploaded as usize,
+            downloaded: self.downloaded as usize,
+            left: (self.geometry.total_length - have) as usize,
+        })
+    }
+}
+
+impl Handler<SubscribeCompleted> for PiecesActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: SubscribeCompleted, _: &mut Context<Self>) -> Self::Result {
+        self.completed_subscribers.push(msg.0);
     }
 }
 
 impl PiecesActor {
-    pub fn new(pieces_count: usize, piece_length: u32) -> Self {
-        let blocks_per_piece: usize = piece_length as usize / BLOCK_LENGTH as usize;
+    pub fn new(info: &Info, file_actor: Addr<FileActor>) -> Self {
+        let piece_hashes: Vec<[u8; 20]> = (0..).map_while(|i| info.piece_hash(i)).collect();
+        let pieces_count = piece_hashes.len();
         PiecesActor {
-            blocks_per_piece,
-            have_chunks: BitVec::with_capacity(blocks_per_piece * pieces_count),
+            have_pieces: BitVec::from_elem(pieces_count, false),
+            partial_pieces: HashMap::new(),
+            pending_pieces: BitVec::from_elem(pieces_count, true),
+            peers: HashMap::new(),
+            availability: vec![0; pieces_count],
+            connections: HashMap::new(),
+            endgame: false,
+            piece_hashes,
+            geometry: info.geometry(),
+            file_actor,
+            downloaded: 0,
+            uploaded: 0,
+            completed_subscribers: Vec::new(),
+        }
+    }
+
+    fn partial_piece(&mut self, index: u32) -> &mut PartialPiece {
+        let geometry = self.geometry;
+        self.pending_pieces.set(index as usize, false);
+        self.partial_pieces.entry(index).or_insert_with(|| {
+            let blocks_count = geometry.blocks_in_piece(index) as usize;
+            PartialPiece {
+                data: vec![0; geometry.piece_len(index) as usize],
+                have_blocks: BitVec::from_elem(blocks_count, false),
+                requested_from: vec![Vec::new(); blocks_count],
+            }
+        })
+    }
+
+    fn remove_peer(&mut self, peer: usize) {
+        if let Some(have) = self.peers.remove(&peer) {
+            for (index, has) in have.iter().enumerate().take(self.availability.len()) {
+                if has {
+                    self.availability[index] -= 1;
+                }
+            }
+        }
+    }
+
+    /// Pick the rarest piece among the ones the peer has, breaking ties at random. The first
+    /// piece is picked at random so that it completes quickly.
+    fn pick_piece(&self, have: &BitVec) -> Option<u32> {
+        let first_piece = self.have_pieces.none() && self.partial_pieces.is_empty();
+        let mut rng = rand::thread_rng();
+        let mut picked = None;
+        let mut min_availability = u32::MAX;
+        let mut ties = 0;
+        for index in 0..self.pending_pieces.len() {
+            if !self.pending_pieces[index] || !have.get(index).unwrap_or(false) {
+                continue;
+            }
+            let availability = if first_piece {
+                0
+            } else {
+                self.availability[index]
+            };
+            if availability < min_availability {
+                min_availability = availability;
+                ties = 1;
+                picked = Some(index as u32);
+            } else if availability == min_availability {
+                ties += 1;
+                if rng.gen_range(0..ties) == 0 {
+                    picked = Some(index as u32);
+                }
+            }
+        }
+        picked
+    }
+
+    /// All the blocks left are requested from at least one peer.
+    fn all_requested(&self) -> bool {
+        self.pending_pieces.none()
+            && self.partial_pieces.values().all(|partial| {
+                partial
+                    .requested_from
+                    .iter()
+                    .zip(partial.have_blocks.iter())
+                    .all(|(peers, have)| have || !peers.is_empty())
+            })
+    }
+
+    /// Mark up to `count` blocks not requested yet as requested from the peer. Pieces in progress
+    /// are finished before new ones are started. Once all the blocks left are requested, the
+    /// endgame starts: blocks are requested from several peers at once, so that the download
+    /// does not wait on the slowest one.
+    fn next_blocks(&mut self, peer: usize, count: usize) -> Vec<Block> {
+        let have = match self.peers.get(&peer) {
+            Some(have) => have.clone(),
+            None => return Vec::new(),
+        };
+        let geometry = self.geometry;
+        let mut blocks = Vec::with_capacity(count);
+        let request_from =
+            |index: u32, partial: &mut PartialPiece, endgame: bool, blocks: &mut Vec<Block>| {
+                for block_index in 0..partial.requested_from.len() {
+                    if blocks.len() >= count {
+                        return;
+                    }
+                    let requested_from = &mut partial.requested_from[block_index];
+                    if partial.have_blocks[block_index]
+                        || requested_from.contains(&peer)
+                        || (!endgame && !requested_from.is_empty())
+                    {
+                        continue;
+                    }
+                    requested_from.push(peer);
+                    let begin = block_index as u32 * BLOCK_LENGTH;
+                    blocks.push(Block {
+                        index,
+                        begin,
+                        length: geometry.block_len(index, begin),
+                    });
+                }
+            };
+
+        for (index, partial) in self.partial_pieces.iter_mut() {
+            if have.get(*index as usize).unwrap_or(false) {
+                request_from(*index, partial, false, &mut blocks);
+            }
+        }
+
+        while blocks.len() < count {
+            let index = match self.pick_piece(&have) {
+                Some(index) => index,
+                None => break,
+            };
+            request_from(index, self.partial_piece(index), false, &mut blocks);
+        }
+
+        if blocks.len() < count && self.all_requested() {
+            if !self.endgame {
+                log::info!("Entering endgame");
+                self.endgame = true;
+            }
+            for (index, partial) in self.partial_pieces.iter_mut() {
+                if have.get(*index as usize).unwrap_or(false) {
+                    request_from(*index, partial, true, &mut blocks);
+                }
+            }
+        }
+        blocks
+    }
+
+    fn release_blocks(&mut self, peer: usize, blocks: &[Block]) {
+        for block in blocks {
+            if let Some(partial) = self.partial_pieces.get_mut(&block.index) {
+                let block_index = (block.begin / BLOCK_LENGTH) as usize;
+                if let Some(requested_from) = partial.requested_from.get_mut(block_index) {
+                    requested_from.retain(|p| *p != peer);
+                }
+            }
+        }
+    }
+
+    fn add_block(
+        &mut self,
+        peer: usize,
+        index: u32,
+        begin: u32,
+        data: Vec<u8>,
+    ) -> Result<BlockOutcome> {
+        if index as usize >= self.piece_hashes.len() {
+            bail!("Unknown piece: index={}", index);
+        }
+        if self.have_pieces[index as usize] {
+            return Ok(BlockOutcome::Incomplete);
+        }
+
+        let piece_len = self.geometry.piece_len(index) as usize;
+        if !begin.is_multiple_of(BLOCK_LENGTH) || begin as usize >= piece_len {
+            bail!("Invalid block offset: index={} begin={}", index, begin);
+        }
+        let block_len = self.geometry.block_len(index, begin) as usize;
+        if data.len() != block_len {
+            bail!(
+                "Invalid block length: index={} begin={} expected={} got={}",
+                index,
+                begin,
+                block_len,
+                data.len()
+            );
+        }
+
+        let block = Block {
+            index,
+            begin,
+            length: block_len as u32,
+        };
+        let begin = begin as usize;
+        let block_index = begin / BLOCK_LENGTH as usize;
+        let partial = self.partial_piece(index);
+        partial.data[begin..begin + block_len].copy_from_slice(&data);
+        partial.have_blocks.set(block_index, true);
+
+        // Endgame: the other peers do not need to send it anymore
+        for other in std::mem::take(&mut partial.requested_from[block_index]) {
+            if other == peer {
+                continue;
+            }
+            if let Some(commands) = self.connections.get(&other) {
+                let _ = commands.send(PeerCommand::Cancel(block));
+            }
+        }
+
+        let partial = self.partial_piece(index);
+        if !partial.have_blocks.all() {
+            return Ok(BlockOutcome::Incomplete);
+        }
+
+        // Complete: the blocks are discarded in both cases
+        let partial = self.partial_pieces.remove(&index).unwrap();
+        let hash: [u8; 20] = Sha1::digest(&partial.data).into();
+        if hash == self.piece_hashes[index as usize] {
+            Ok(BlockOutcome::Verified(partial.data))
+        } else {
+            Ok(BlockOutcome::Corrupt)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix::clock::sleep;
+    use sha1::{Digest, Sha1};
+    use std::fs::{File, OpenOptions};
+    use std::io::Read;
+    use std::{env, time::Duration};
+
+    use crate::{fs::FileActor, message::BLOCK_LENGTH, pieces::*};
+
+    fn info(piece_length: u32, pieces: &[&[u8]]) -> Info {
+        let length: usize = pieces.iter().map(|p| p.len()).sum();
+        let mut bytes = format!(
+            "d6:lengthi{}e4:name4:test12:piece lengthi{}e6:pieces{}:",
+            length,
+            piece_length,
+            pieces.len() * 20
+        )
+        .into_bytes();
+        for piece in pieces {
+            bytes.extend_from_slice(&Sha1::digest(piece));
+        }
+        bytes.push(b'e');
+        serde_bencode::from_bytes(&bytes).unwrap()
+    }
+
+    fn pieces_actor(name: &str, info: &Info) -> PiecesActor {
+        let mut tmp_path = env::temp_dir();
+        tmp_path.push(name);
+        let file_actor_addr = FileActor::new(&tmp_path, info.total_length(), info.piece_length)
+            .unwrap()
+            .start();
+        PiecesActor::new(info, file_actor_addr)
+    }
+
+    fn bitfield(bits: &[bool]) -> BitVec {
+        let mut bitfield = BitVec::new();
+        bits.iter().for_each(|bit| bitfield.push(*bit));
+        bitfield
+    }
+
+    #[actix::test]
+    async fn released_blocks_should_be_requested_again() {
+        let piece = vec![1u8; 3 * BLOCK_LENGTH as usize];
+        let info = info(3 * BLOCK_LENGTH, &[&piece]);
+        let mut pieces_actor =
+            pieces_actor("sharku_released_blocks_should_be_requested_again", &info);
+        pieces_actor.peers.insert(0, bitfield(&[false]));
+        pieces_actor.peers.insert(1, bitfield(&[true]));
+
+        assert!(pieces_actor.next_blocks(0, 10).is_empty());
+        assert!(pieces_actor.next_blocks(2, 10).is_empty());
+
+        let blocks = pieces_actor.next_blocks(1, 2);
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(pieces_actor.next_blocks(1, 2)[0].begin, 2 * BLOCK_LENGTH);
+
+        pieces_actor
+            .add_block(1, 0, 0, piece[..BLOCK_LENGTH as usize].to_vec())
+            .unwrap();
+        // Only the block not received yet is requested again
+        pieces_actor.release_blocks(1, &blocks);
+        assert_eq!(
+            pieces_actor.next_blocks(1, 10),
+            vec![Block {
+                index: 0,
+                begin: BLOCK_LENGTH,
+                length: BLOCK_LENGTH
+            }]
+        );
+    }
+
+    #[actix::test]
+    async fn endgame_should_request_blocks_twice_and_cancel_duplicates() {
+        let piece = vec![1u8; 2 * BLOCK_LENGTH as usize];
+        let info = info(2 * BLOCK_LENGTH, &[&piece]);
+        let mut pieces_actor = pieces_actor(
+            "sharku_endgame_should_request_blocks_twice_and_cancel_duplicates",
+            &info,
+        );
+        let mut commands = Vec::new();
+        for peer in 0..3 {
+            let (commands_tx, commands_rx) = mpsc::unbounded_channel();
+            pieces_actor.connections.insert(peer, commands_tx);
+            pieces_actor.peers.insert(peer, bitfield(&[true]));
+            commands.push(commands_rx);
+        }
+
+        assert_eq!(pieces_actor.next_blocks(0, 1).len(), 1);
+        assert!(!pieces_actor.endgame);
+        assert_eq!(pieces_actor.next_blocks(1, 1)[0].begin, BLOCK_LENGTH);
+        // Everything is requested: peer 2 gets both blocks
+        assert_eq!(pieces_actor.next_blocks(2, 10).len(), 2);
+        assert!(pieces_actor.endgame);
+        assert!(pieces_actor.next_blocks(2, 10).is_empty());
+
+        pieces_actor
+            .add_block(2, 0, 0, piece[..BLOCK_LENGTH as usize].to_vec())
+            .unwrap();
+        let cancel = PeerCommand::Cancel(Block {
+            index: 0,
+            begin: 0,
+            length: BLOCK_LENGTH,
+        });
+        assert_eq!(commands[0].try_recv().unwrap(), cancel);
+        assert!(commands[1].try_recv().is_err());
+        assert!(commands[2].try_recv().is_err());
+    }
+
+    #[actix::test]
+    async fn rarest_piece_should_be_picked_first() {
+        let pieces: Vec<Vec<u8>> = (0..4).map(|i| vec![i; BLOCK_LENGTH as usize]).collect();
+        let pieces: Vec<&[u8]> = pieces.iter().map(|p| p.as_slice()).collect();
+        let info = info(BLOCK_LENGTH, &pieces);
+        let mut pieces_actor = pieces_actor("sharku_rarest_piece_should_be_picked_first", &info);
+        pieces_actor.have_pieces.set(0, true);
+        pieces_actor.pending_pieces.set(0, false);
+
+        let peers = [
+            [true, true, true, true],
+            [false, true, false, true],
+            [false, true, true, true],
+        ];
+        for (peer, have) in peers.iter().enumerate() {
+            let have = bitfield(have);
+            for (index, has) in have.iter().enumerate() {
+                pieces_actor.availability[index] += has as u32;
+            }
+            pieces_actor.peers.insert(peer, have);
+        }
+
+        // Availability: piece 2 is held by 2 peers, 1 and 3 by 3 peers
+        let picked: Vec<u32> = pieces_actor
+            .next_blocks(0, 3)
+            .iter()
+            .map(|b| b.index)
+            .collect();
+        assert_eq!(picked[0], 2);
+        let mut rest = picked[1..].to_vec();
+        rest.sort_unstable();
+        assert_eq!(rest, vec![1, 3]);
+    }
+
+    #[actix::test]
+    async fn availability_should_follow_peers() {
+        let pieces = [vec![0u8; 10]];
+        let info = info(BLOCK_LENGTH, &[&pieces[0]]);
+        let pieces_actor = pieces_actor("sharku_availability_should_follow_peers", &info).start();
+
+        pieces_actor
+            .send(PeerBitfield {
+                peer: 0,
+                have: bitfield(&[true]),
+            })
+            .await
+            .unwrap();
+        pieces_actor
+            .send(PeerHave { peer: 1, index: 0 })
+            .await
+            .unwrap();
+        // Have messages for unknown pieces are ignored
+        pieces_actor
+            .send(PeerHave { peer: 1, index: 7 })
+            .await
+            .unwrap();
+        pieces_actor.send(PeerGone { peer: 0 }).await.unwrap();
+
+        assert_eq!(
+            pieces_actor
+                .send(NextBlocks { peer: 0, count: 1 })
+                .await
+                .unwrap(),
+            vec![]
+        );
+        assert_eq!(
+            pieces_actor
+                .send(NextBlocks { peer: 1, count: 1 })
+                .await
+                .unwrap(),
+            vec![Block {
+                index: 0,
+                begin: 0,
+                length: 10
+            }]
+        );
+    }
+
+    #[actix::test]
+    async fn verified_piece_should_be_written_and_corrupt_piece_rescheduled() {
+        let mut tmp_path = env::temp_dir();
+        tmp_path.push("sharku_verified_piece_should_be_written_and_corrupt_piece_rescheduled");
+        let file = OpenOptions::new()
+            .write(true)
+            .truncate(true)
+            .create(true)
+            .open(&tmp_path)
+            .unwrap();
+        drop(file);
+
+        let piece_0 = vec![1u8; 2 * BLOCK_LENGTH as usize];
+        let piece_1 = vec![2u8; 10];
+        let info = info(2 * BLOCK_LENGTH, &[&piece_0, &piece_1]);
+
+        let file_actor_addr =
+            FileActor::new(&tmp_path, info.length.unwrap() as u64, BLOCK_LENGTH * 2)
+                .unwrap()
+                .start();
+        let pieces_actor_addr = PiecesActor::new(&info, file_actor_addr).start();
+        let (commands_tx, mut commands_rx) = mpsc::unbounded_channel();
+        pieces_actor_addr.do_send(PeerConnected {
+            peer: 0,
+            commands: commands_tx,
+            stats: Default::default(),
+        });
+        pieces_actor_addr
+            .send(PeerBitfield {
+                peer: 0,
+                have: BitVec::from_elem(2, true),
+            })
+            .await
+            .unwrap();
+
+        let next_blocks = || NextBlocks { peer: 0, count: 10 };
+        let block = |index, begin, length| Block {
+            index,
+            begin,
+            length,
+        };
+        // The first piece is picked at random
+        let mut blocks = pieces_actor_addr.send(next_blocks()).await.unwrap();
+        blocks.sort_by_key(|b| (b.index, b.begin));
+        assert_eq!(
+            blocks,
+            vec![
+                block(0, 0, BLOCK_LENGTH),
+                block(0, BLOCK_LENGTH, BLOCK_LENGTH),
+                block(1, 0, 10)
+            ]
+        );
+        assert_eq!(pieces_actor_addr.send(next_blocks()).await.unwrap(), vec![]);
+
+        for begin in [BLOCK_LENGTH, 0] {
+            pieces_actor_addr
+                .try_send(BlockReceived {
+                    peer: 0,
+                    index: 0,
+                    begin,
+                    data: piece_0[..BLOCK_LENGTH as usize].to_vec(),
+                })
+                .unwrap();
+        }
+        pieces_actor_addr
+            .try_send(BlockReceived {
+                peer: 0,
+                index: 1,
+                begin: 0,
+                data: vec![3u8; 10],
+            })
+            .unwrap();
+
+        assert_eq!(
+            pieces_actor_addr.send(next_blocks()).await.unwrap(),
+            vec![block(1, 0, 10)]
+        );
+        // Only the verified piece is announced to the peers
+        assert_eq!(commands_rx.try_recv().unwrap(), PeerCommand::Have(0));
+        assert!(commands_rx.try_recv().is_err());
+        assert_eq!(pieces_actor_addr.send(next_blocks()).await.unwrap(), vec![]);
+
+        let read_block = |index, begin, length| ReadBlock {
+            index,
+            begin,
+            length,
+        };
+        assert_eq!(
+            pieces_actor_addr
+                .send(read_block(0, BLOCK_LENGTH, 3))
+                .await
+                .unwrap()
+                .unwrap(),
+            vec![1u8; 3]
+        );
+        assert!(pieces_actor_addr
+            .send(read_block(0, 2 * BLOCK_LENGTH - 2, 3))
+            .await
+            .unwrap()
+            .is_err());
+        assert!(pieces_actor_addr
+            .send(read_block(1, 0, 3))
+            .await
+            .unwrap()
+            .is_err());
+
+        let state = pieces_actor_addr.send(GetDownloadState).await.unwrap();
+        assert_eq!(state.downloaded, piece_0.len());
+        assert_eq!(state.uploaded, 3);
+        assert_eq!(state.left, piece_1.len());
+
+        for _ in 1..=5 {
+            let mut buf = Vec::new();
+            File::open(&tmp_path)
+                .unwrap()
+                .read_to_end(&mut buf)
+                .unwrap();
+            if buf[..piece_0.len()] == piece_0[..] {
+                assert_eq!(&buf[piece_0.len()..], &[0u8; 10]);
+                return;
+            }
+            sleep(Duration::from_millis(10)).await;
         }
+        panic!("Verified piece was not written");
     }
 }
@@ -0,0 +1,179 @@
+use std::time::{Duration, Instant};
+
+use crate::message::{Block, BLOCK_LENGTH};
+
+/// How long it should take the peer, at its measured rate, to answer all the requests in flight.
+const QUEUE_TIME: Duration = Duration::from_secs(3);
+/// How often the rate of the peer is measured.
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+
+/// Bounds of the number of block requests in flight to one peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PipelineConfig {
+    pub min_requests: usize,
+    pub max_requests: usize,
+}
+
+impl Default for PipelineConfig {
+    fn default() -> Self {
+        PipelineConfig {
+            min_requests: 5,
+            max_requests: 250,
+        }
+    }
+}
+
+/// The block requests in flight to one peer. Their number adapts to the download rate of the
+/// peer, so that a fast peer always has enough requests queued to never sit idle for a round
+/// trip.
+pub struct RequestPipeline {
+    config: PipelineConfig,
+    outstanding: Vec<Block>,
+    max_outstanding: usize,
+    window_start: Instant,
+    window_bytes: usize,
+    // Bytes per second
+    rate: f64,
+}
+
+impl RequestPipeline {
+    pub fn new(config: PipelineConfig, now: Instant) -> Self {
+        assert!(config.min_requests > 0 && config.min_requests <= config.max_requests);
+        RequestPipeline {
+            config,
+            outstanding: Vec::with_capacity(config.max_requests),
+            max_outstanding: config.min_requests,
+            window_start: now,
+            window_bytes: 0,
+            rate: 0.0,
+        }
+    }
+
+    /// How many more requests can be sent.
+    pub fn capacity(&self) -> usize {
+        self.max_outstanding.saturating_sub(self.outstanding.len())
+    }
+
+    pub fn max_outstanding(&self) -> usize {
+        self.max_outstanding
+    }
+
+    /// Download rate of the peer in bytes per second.
+    pub fn rate(&self) -> f64 {
+        self.rate
+    }
+
+    pub fn push(&mut self, block: Block) {
+        self.outstanding.push(block);
+    }
+
+    /// Record a received block. Returns `false` if it was not requested.
+    pub fn on_block(&mut self, index: u32, begin: u32, len: usize, now: Instant) -> bool {
+        let position = self
+            .outstanding
+            .iter()
+            .position(|b| b.index == index && b.begin == begin && b.length as usize == len);
+        let position = match position {
+            Some(position) => position,
+            None => return false,
+        };
+        self.outstanding.swap_remove(position);
+        self.window_bytes += len;
+        self.update_rate(now);
+        true
+    }
+
+    /// Forget a request in flight. Returns `false` if it was not requested.
+    pub fn cancel(&mut self, block: &Block) -> bool {
+        let position = self.outstanding.iter().position(|b| b == block);
+        match position {
+            Some(position) => {
+                self.outstanding.swap_remove(position);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Forget all the requests in flight, e.g. when the peer chokes us, and return them.
+    pub fn drain(&mut self) -> Vec<Block> {
+        std::mem::take(&mut self.outstanding)
+    }
+
+    fn update_rate(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.window_start);
+        if elapsed < RATE_WINDOW {
+            return;
+        }
+
+        self.rate = self.window_bytes as f64 / elapsed.as_secs_f64();
+        let wanted = (self.rate * QUEUE_TIME.as_secs_f64() / BLOCK_LENGTH as f64) as usize;
+        self.max_outstanding = wanted.clamp(self.config.min_requests, self.config.max_requests);
+        self.window_start = now;
+        self.window_bytes = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use crate::message::{Block, BLOCK_LENGTH};
+    use crate::pipeline::{PipelineConfig, RequestPipeline};
+
+    fn block(index: u32) -> Block {
+        Block {
+            index,
+            begin: 0,
+            length: BLOCK_LENGTH,
+        }
+    }
+
+    #[test]
+    fn pipeline_should_grow_with_rate_and_stay_within_bounds() {
+        let start = Instant::now();
+        let config = PipelineConfig {
+            min_requests: 5,
+            max_requests: 50,
+        };
+        let mut pipeline = RequestPipeline::new(config, start);
+        assert_eq!(pipeline.capacity(), 5);
+
+        // 20 blocks per second: 60 blocks to cover 3 seconds, clamped to 50
+        for i in 0..20 {
+            pipeline.push(block(i));
+        }
+        assert_eq!(pipeline.capacity(), 0);
+        for i in 0..20 {
+            assert!(pipeline.on_block(i, 0, BLOCK_LENGTH as usize, start));
+        }
+        assert!(!pipeline.on_block(20, 0, BLOCK_LENGTH as usize, start));
+        pipeline.push(block(20));
+        assert!(pipeline.on_block(
+            20,
+            0,
+            BLOCK_LENGTH as usize,
+            start + Duration::from_millis(1050)
+        ));
+        assert_eq!(pipeline.max_outstanding(), 50);
+
+        // 1 block per second: 3 blocks, clamped to 5
+        pipeline.push(block(21));
+        assert!(pipeline.on_block(
+            21,
+            0,
+            BLOCK_LENGTH as usize,
+            start + Duration::from_millis(2050)
+        ));
+        assert_eq!(pipeline.max_outstanding(), 5);
+    }
+
+    #[test]
+    fn drain_should_return_outstanding_requests() {
+        let mut pipeline = RequestPipeline::new(PipelineConfig::default(), Instant::now());
+        pipeline.push(block(1));
+        pipeline.push(block(2));
+        assert_eq!(pipeline.drain(), vec![block(1), block(2)]);
+        assert_eq!(pipeline.capacity(), 5);
+    }
+}
@@ -1,19 +1,31 @@
+use crate::message::BLOCK_LENGTH;
 use anyhow::{Context, Result};
 use derivative::Derivative;
 use serde::{Deserialize, Serialize};
 use serde_bencode::de;
 use serde_bytes::ByteBuf;
+use std::convert::TryInto;
 use std::fs::File as F;
 use std::io::Read;
-use std::path::Path;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
 
+/// A DHT node to bootstrap from, as a host and a port (BEP 5).
 #[derive(Debug, Deserialize)]
+// serde_bencode only reads a list of several tuple structs through a tuple
+#[serde(from = "(String, i64)")]
 pub struct Node(String, i64);
 
+impl From<(String, i64)> for Node {
+    fn from((host, port): (String, i64)) -> Self {
+        Node(host, port)
+    }
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct File {
     pub path: Vec<String>,
-    length: i64,
+    pub length: u64,
     md5sum: Option<String>,
 }
 
@@ -36,24 +48,137 @@ pub struct Info {
 }
 
 impl Info {
+    /// Peers of a private torrent only come from its trackers, not from the DHT (BEP 27).
+    pub fn is_private(&self) -> bool {
+        self.private == Some(1)
+    }
+
+    /// The 20 bytes SHA-1 hash of the piece at `index`, as listed in the torrent file.
+    pub fn piece_hash(&self, index: usize) -> Option<[u8; 20]> {
+        self.pieces
+            .chunks_exact(20)
+            .nth(index)
+            .map(|hash| hash.try_into().unwrap())
+    }
+
+    /// Length in bytes of the whole content, summed over all files for a multi-file torrent.
+    pub fn total_length(&self) -> u64 {
+        match (self.length, &self.files) {
+            (Some(length), _) => length as u64,
+            (None, Some(files)) => files.iter().map(|f| f.length).sum(),
+            (None, None) => 0,
+        }
+    }
+
+    /// The files in the order they appear in the pieces, with their path relative to the download
+    /// directory. The files of a multi-file torrent are stored in a directory named `name`.
+    pub fn file_paths(&self) -> Result<Vec<(PathBuf, u64)>> {
+        check_path_component(&self.name)?;
+        match (self.length, &self.files) {
+            (Some(length), None) => Ok(vec![(PathBuf::from(&self.name), length as u64)]),
+            (None, Some(files)) => files
+                .iter()
+                .map(|f| {
+                    if f.path.is_empty() {
+                        anyhow::bail!("Empty file path in torrent file");
+                    }
+                    let mut path = PathBuf::from(&self.name);
+                    for component in &f.path {
+                        check_path_component(component)?;
+                        path.push(component);
+                    }
+                    Ok((path, f.length))
+                })
+                .collect(),
+            _ => anyhow::bail!("Torrent file must have either a length or a list of files"),
+        }
+    }
+
+    pub fn geometry(&self) -> PieceGeometry {
+        PieceGeometry {
+            piece_length: self.piece_length,
+            total_length: self.total_length(),
+        }
+    }
+
+    pub fn pieces_count(&self) -> usize {
+        self.geometry().pieces_count()
+    }
+
+    pub fn piece_len(&self, index: u32) -> u32 {
+        self.geometry().piece_len(index)
+    }
+
+    pub fn blocks_in_piece(&self, index: u32) -> u32 {
+        self.geometry().blocks_in_piece(index)
+    }
+
+    pub fn bitfield_len(&self) -> usize {
+        self.geometry().bitfield_len()
+    }
+}
+
+/// How the content is cut into pieces, and pieces into blocks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PieceGeometry {
+    pub piece_length: u32,
+    pub total_length: u64,
+}
+
+impl PieceGeometry {
     pub fn pieces_count(&self) -> usize {
         assert!(self.piece_length > 0);
-        // Div ceil
-        let piece_length = self.piece_length as usize;
-        let length = self.length.unwrap_or(0);
-        // Div ceil
-        let pieces_count = (length + piece_length - 1) / piece_length;
-        // Pad remaining bits of the last byte
-        let res = (pieces_count + 8 - 1) / 8;
-        res * 8
+        self.total_length.div_ceil(self.piece_length as u64) as usize
+    }
+
+    /// Length in bytes of the piece at `index`: the last piece is usually shorter.
+    pub fn piece_len(&self, index: u32) -> u32 {
+        let start = index as u64 * self.piece_length as u64;
+        self.total_length
+            .saturating_sub(start)
+            .min(self.piece_length as u64) as u32
+    }
+
+    pub fn blocks_in_piece(&self, index: u32) -> u32 {
+        self.piece_len(index).div_ceil(BLOCK_LENGTH)
+    }
+
+    /// Length in bytes of the block starting at `begin` in the piece at `index`: the last block
+    /// of the last piece is usually shorter.
+    pub fn block_len(&self, index: u32, begin: u32) -> u32 {
+        self.piece_len(index)
+            .saturating_sub(begin)
+            .min(BLOCK_LENGTH)
+    }
+
+    /// Length in bytes of a bitfield, with the spare bits of the last byte.
+    pub fn bitfield_len(&self) -> usize {
+        self.pieces_count().div_ceil(8)
     }
 }
 
+/// Reject path components that would escape the download directory.
+fn check_path_component(component: &str) -> Result<()> {
+    if component.is_empty()
+        || component == "."
+        || component == ".."
+        || component.contains(['/', '\\', '\0'])
+    {
+        anyhow::bail!("Invalid path component in torrent file: {:?}", component);
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use serde_bytes::ByteBuf;
+    use sha1::{Digest, Sha1};
+    use std::path::{Path, PathBuf};
 
-    use crate::torrent_file::Info;
+    use crate::message::BLOCK_LENGTH;
+    use crate::torrent_file::{
+        decode_torrent, decode_torrent_from_file, File, Info, PieceGeometry,
+    };
 
     #[test]
     fn compute_pieces_count() {
@@ -68,13 +193,132 @@ mod tests {
             path: None,
             root_hash: None,
         };
-        assert_eq!(info.pieces_count(), 1512);
+        assert_eq!(info.pieces_count(), 1508);
+        assert_eq!(info.bitfield_len(), 189);
+        assert_eq!(info.piece_len(0), 262144);
+        assert_eq!(info.piece_len(1507), 262144);
+        assert_eq!(info.piece_len(1508), 0);
+        assert_eq!(info.blocks_in_piece(1507), 16);
+    }
+
+    #[test]
+    fn last_piece_should_be_shorter() {
+        let geometry = PieceGeometry {
+            piece_length: 2 * BLOCK_LENGTH,
+            total_length: 5 * BLOCK_LENGTH as u64 + 10,
+        };
+        assert_eq!(geometry.pieces_count(), 3);
+        assert_eq!(geometry.bitfield_len(), 1);
+        assert_eq!(geometry.piece_len(2), BLOCK_LENGTH + 10);
+        assert_eq!(geometry.blocks_in_piece(2), 2);
+        assert_eq!(geometry.block_len(2, 0), BLOCK_LENGTH);
+        assert_eq!(geometry.block_len(2, BLOCK_LENGTH), 10);
+    }
+
+    fn multi_file_info(paths: &[&[&str]]) -> Info {
+        Info {
+            name: String::from("dir"),
+            pieces: ByteBuf::new(),
+            piece_length: 16384,
+            md5sum: None,
+            length: None,
+            files: Some(
+                paths
+                    .iter()
+                    .map(|path| File {
+                        path: path.iter().map(|s| s.to_string()).collect(),
+                        length: 10,
+                        md5sum: None,
+                    })
+                    .collect(),
+            ),
+            private: None,
+            path: None,
+            root_hash: None,
+        }
+    }
+
+    #[test]
+    fn multi_file_paths_and_length() {
+        let info = multi_file_info(&[&["a.txt"], &["sub", "b.txt"]]);
+        assert_eq!(info.total_length(), 20);
+        assert_eq!(info.pieces_count(), 1);
+        assert_eq!(
+            info.file_paths().unwrap(),
+            vec![
+                (PathBuf::from("dir/a.txt"), 10),
+                (PathBuf::from("dir/sub/b.txt"), 10)
+            ]
+        );
+    }
+
+    #[test]
+    fn file_paths_should_reject_path_traversal() {
+        assert!(multi_file_info(&[&["..", "etc", "passwd"]])
+            .file_paths()
+            .is_err());
+        assert!(multi_file_info(&[&["/etc/passwd"]]).file_paths().is_err());
+        assert!(multi_file_info(&[&[]]).file_paths().is_err());
+    }
+
+    #[test]
+    fn info_bytes_should_keep_unknown_keys() {
+        let info = b"d6:lengthi3e4:name1:a12:piece lengthi16384e6:pieces20:012345678901234567896:source3:fooe";
+        let mut content = b"d8:announce3:url4:info".to_vec();
+        content.extend_from_slice(info);
+        content.extend_from_slice(b"7:comment2:hie");
+
+        let torrent = decode_torrent(&content).unwrap();
+        assert_eq!(torrent.info_bytes, info);
+    }
+
+    #[test]
+    fn dht_nodes_should_be_host_and_port() {
+        let info = b"d6:lengthi3e4:name1:a12:piece lengthi16384e6:pieces20:01234567890123456789e";
+        let mut content = b"d4:info".to_vec();
+        content.extend_from_slice(info);
+        content.extend_from_slice(b"5:nodesll9:127.0.0.1i6881eel3:::1i6882eel4:nodei0eeee");
+
+        let torrent = decode_torrent(&content).unwrap();
+        assert_eq!(torrent.nodes(), vec!["127.0.0.1:6881", "[::1]:6882"]);
+    }
+
+    #[test]
+    fn info_hash_of_debian_torrent() {
+        let torrent = decode_torrent_from_file(Path::new("debian.torrent")).unwrap();
+        let hash: [u8; 20] = Sha1::digest(&torrent.info_bytes).into();
+        assert_eq!(
+            hash.iter()
+                .map(|b| format!("{:02x}", b))
+                .collect::<String>(),
+            "3b4bd6f8296403dfebd41062f4658f5b61d2bc26"
+        );
+    }
+
+    #[test]
+    fn decode_torrent_should_reject_truncated_info() {
+        assert!(decode_torrent(b"d4:infod4:name1:a").is_err());
+    }
+
+    #[test]
+    fn decode_torrent_should_reject_zero_piece_length() {
+        assert!(
+            decode_torrent(b"d4:infod6:lengthi3e4:name1:a12:piece lengthi0e6:pieces0:ee").is_err()
+        );
     }
 }
 
-#[derive(Debug, Deserialize)]
+#[allow(dead_code)]
+#[derive(Derivative)]
+#[derivative(Debug)]
+#[derive(Deserialize)]
 pub struct Torrent {
     pub info: Info,
+    /// The `info` dictionary exactly as it appears in the torrent file, which is what the info_hash
+    /// is computed from.
+    #[serde(skip)]
+    #[derivative(Debug = "ignore")]
+    pub info_bytes: Vec<u8>,
     pub announce: Option<String>,
     nodes: Option<Vec<Node>>,
     encoding: Option<String>,
@@ -89,11 +333,123 @@ pub struct Torrent {
     created_by: Option<String>,
 }
 
+impl Torrent {
+    /// Tracker URLs grouped in tiers (BEP 12): the `announce-list` if any, otherwise `announce`
+    /// alone.
+    pub fn announce_tiers(&self) -> Vec<Vec<String>> {
+        let tiers: Vec<Vec<String>> = self
+            .announce_list
+            .iter()
+            .flatten()
+            .filter(|tier| !tier.is_empty())
+            .cloned()
+            .collect();
+        if !tiers.is_empty() {
+            return tiers;
+        }
+        self.announce.iter().map(|url| vec![url.clone()]).collect()
+    }
+
+    /// The DHT nodes of a trackerless torrent, as `host:port`.
+    pub fn nodes(&self) -> Vec<String> {
+        self.nodes
+            .iter()
+            .flatten()
+            .filter(|Node(_, port)| (1..=u16::MAX as i64).contains(port))
+            .map(|Node(host, port)| match host.contains(':') {
+                // An IPv6 address
+                true => format!("[{}]:{}", host, port),
+                false => format!("{}:{}", host, port),
+            })
+            .collect()
+    }
+}
+
 pub fn decode_torrent_from_file(file_name: &Path) -> Result<Torrent> {
     let mut f = F::open(file_name).context("Failed to open torrent file")?;
     let mut content = Vec::with_capacity(100_000);
     f.read_to_end(&mut content)
         .context("Failed to read torrent file")?;
 
-    de::from_bytes::<Torrent>(&content).context("Failed to parse torrent file")
+    decode_torrent(&content)
+}
+
+pub fn decode_torrent(content: &[u8]) -> Result<Torrent> {
+    let mut torrent = de::from_bytes::<Torrent>(content).context("Failed to parse torrent file")?;
+    let info_span = find_info_span(content).context("Failed to find info in torrent file")?;
+    torrent.info_bytes = content[info_span].to_vec();
+
+    if torrent.info.piece_length == 0 {
+        anyhow::bail!("Invalid piece length in torrent file: 0");
+    }
+    let hashes_count = torrent.info.pieces.len() / 20;
+    if !torrent.info.pieces.len().is_multiple_of(20) || hashes_count != torrent.info.pieces_count()
+    {
+        anyhow::bail!(
+            "Wrong number of piece hashes in torrent file: expected={} got={}",
+            torrent.info.pieces_count(),
+            hashes_count
+        );
+    }
+    Ok(torrent)
+}
+
+/// Returns the position right after the bencoded value starting at `start`.
+pub(crate) fn skip_bencode_value(buf: &[u8], start: usize) -> Result<usize> {
+    let mut pos = start;
+    let mut depth = 0usize;
+    loop {
+        match buf.get(pos) {
+            Some(b'd') | Some(b'l') => {
+                depth += 1;
+                pos += 1;
+            }
+            Some(b'e') if depth > 0 => {
+                depth -= 1;
+                pos += 1;
+            }
+            Some(b'i') => {
+                let len = buf[pos..]
+                    .iter()
+                    .position(|b| *b == b'e')
+                    .context("Unterminated integer")?;
+                pos += len + 1;
+            }
+            Some(b'0'..=b'9') => {
+                let colon = buf[pos..]
+                    .iter()
+                    .position(|b| *b == b':')
+                    .context("Unterminated string length")?;
+                let len: usize = std::str::from_utf8(&buf[pos..pos + colon])?
+                    .parse()
+                    .context("Invalid string length")?;
+                pos = (pos + colon + 1)
+                    .checked_add(len)
+                    .filter(|end| *end <= buf.len())
+                    .context("String out of bounds")?;
+            }
+            other => anyhow::bail!("Unexpected bencode byte at {}: {:?}", pos, other),
+        }
+        if depth == 0 {
+            return Ok(pos);
+        }
+    }
+}
+
+/// Finds the byte span of the value of the `info` key in the top-level dictionary.
+fn find_info_span(buf: &[u8]) -> Result<Range<usize>> {
+    if buf.first() != Some(&b'd') {
+        anyhow::bail!("Torrent file is not a dictionary");
+    }
+    let mut pos = 1;
+    while buf.get(pos) != Some(&b'e') {
+        let key_end = skip_bencode_value(buf, pos)?;
+        let key = &buf[pos..key_end];
+        let value_end = skip_bencode_value(buf, key_end)?;
+        if key == b"4:info" {
+            return Ok(key_end..value_end);
+        }
+        pos = value_end;
+    }
+    anyhow::bail!("Missing info in torrent file")
 }
@@ -1,15 +1,28 @@
 use crate::message::PEER_ID;
+use crate::pieces::{DownloadCompleted, GetDownloadState, PiecesActor, SubscribeCompleted};
 use crate::state::DownloadState;
 use crate::torrent_file::Torrent;
-use anyhow::{Context, Result};
+use actix::prelude::*;
+use anyhow::{Context as _, Result};
+use rand::seq::SliceRandom;
 use serde::Deserialize;
 use serde_bencode::de;
 use serde_bytes::ByteBuf;
 use sha1::{Digest, Sha1};
+use std::collections::HashMap;
 use std::convert::TryInto;
-use std::net::{IpAddr, Ipv4Addr};
+use std::fmt;
+use std::future::Future;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
 
-#[derive(Debug, Deserialize)]
+pub mod udp;
+
+use udp::UdpTracker;
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
 pub struct Peer {
     pub port: u16,
     pub ip: IpAddr,
@@ -19,63 +32,747 @@ pub struct Peer {
 pub struct TrackerResponse {
     #[serde(rename = "failure reason")]
     pub failure_reason: Option<String>,
+    #[serde(rename = "warning message")]
+    pub warning_message: Option<String>,
     pub interval: Option<usize>,
-    pub peers: ByteBuf,
+    #[serde(rename = "min interval")]
+    pub min_interval: Option<usize>,
+    #[serde(rename = "tracker id")]
+    pub tracker_id: Option<String>,
+    /// Seeders
+    pub complete: Option<u32>,
+    /// Leechers
+    pub incomplete: Option<u32>,
+    pub peers: Option<PeerList>,
+    /// Compact IPv6 peers (BEP 7)
+    pub peers6: Option<ByteBuf>,
+}
+
+/// Trackers send the compact format when asked to, but some only know the dictionary one.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum PeerList {
+    Compact(ByteBuf),
+    Dictionaries(Vec<PeerDictionary>),
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PeerDictionary {
+    #[serde(rename = "peer id")]
+    pub peer_id: Option<ByteBuf>,
+    /// IP address or DNS name
+    pub ip: String,
+    pub port: u16,
+}
+
+/// The tracker answered, but refused the request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TrackerError {
+    /// `failure reason` of an HTTP tracker, or error message of a UDP tracker
+    Failure(String),
+}
+
+impl fmt::Display for TrackerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TrackerError::Failure(reason) => write!(f, "Tracker failure: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for TrackerError {}
+
+/// Response to an announce, over HTTP or UDP.
+#[derive(Debug)]
+pub struct Announce {
+    /// How long to wait before the next regular announce
+    pub interval: Option<Duration>,
+    /// Do not announce more often than that, even with an event
+    pub min_interval: Option<Duration>,
+    /// To send back in the next announces to the same tracker
+    pub tracker_id: Option<String>,
+    pub seeders: Option<u32>,
+    pub leechers: Option<u32>,
+    pub peers: Vec<Peer>,
+}
+
+/// Why we announce.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AnnounceEvent {
+    /// Regular announce
+    None,
+    Completed,
+    Started,
+    Stopped,
+}
+
+impl AnnounceEvent {
+    fn as_query(&self) -> Option<&'static str> {
+        match self {
+            AnnounceEvent::None => None,
+            AnnounceEvent::Completed => Some("completed"),
+            AnnounceEvent::Started => Some("started"),
+            AnnounceEvent::Stopped => Some("stopped"),
+        }
+    }
+}
+
+/// Stats of a torrent, as scraped from a tracker.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScrapeStats {
+    pub seeders: u64,
+    /// Number of times the download was completed
+    pub completed: u64,
+    pub leechers: u64,
+}
+
+/// Retries of a UDP request when other trackers are left to try: the next one is tried after
+/// 15 + 30 + 60 seconds instead of the hours of the whole BEP 15 schedule.
+const FAILOVER_RETRIES: u32 = 2;
+/// An HTTP tracker that does not answer by then is given up on, and the next one tried.
+const HTTP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How we reach the trackers: one HTTP client for all of them, and one client per UDP tracker to
+/// reuse its connection id from one announce to the next.
+#[derive(Clone)]
+pub struct TrackerClients {
+    http: reqwest::Client,
+    http_timeout: Duration,
+    // By `host:port`
+    udp: Arc<Mutex<HashMap<String, Arc<tokio::sync::Mutex<UdpTracker>>>>>,
+    udp_retries: u32,
+}
+
+impl Default for TrackerClients {
+    fn default() -> Self {
+        TrackerClients {
+            http: reqwest::Client::new(),
+            http_timeout: HTTP_TIMEOUT,
+            udp: Default::default(),
+            udp_retries: udp::MAX_RETRIES,
+        }
+    }
+}
+
+impl TrackerClients {
+    /// The same clients, for a tracker that is the `last` one to try or not.
+    fn for_attempt(&self, last: bool) -> Self {
+        TrackerClients {
+            udp_retries: if last {
+                udp::MAX_RETRIES
+            } else {
+                FAILOVER_RETRIES
+            },
+            ..self.clone()
+        }
+    }
+
+    /// The client of the UDP tracker at `addr`, created on first use.
+    async fn udp(&self, addr: &str) -> Result<Arc<tokio::sync::Mutex<UdpTracker>>> {
+        if let Some(tracker) = self.udp.lock().unwrap().get(addr) {
+            return Ok(tracker.clone());
+        }
+        let tracker = Arc::new(tokio::sync::Mutex::new(UdpTracker::connect(addr).await?));
+        let mut udp = self.udp.lock().unwrap();
+        Ok(udp.entry(addr.to_owned()).or_insert(tracker).clone())
+    }
 }
 
-pub fn info_hash(torrent: &Torrent) -> Result<[u8; 20]> {
-    let info_bytes =
-        serde_bencode::to_bytes(&torrent.info).context("Failed to serialize torrent info")?;
+pub fn info_hash(torrent: &Torrent) -> [u8; 20] {
     let mut hasher = Sha1::new();
-    hasher.update(info_bytes);
-    Ok(hasher.finalize().into())
+    hasher.update(&torrent.info_bytes);
+    hasher.finalize().into()
 }
 
-pub async fn tracker_start(
-    client: reqwest::Client,
-    torrent: &Torrent,
-    download_state: &DownloadState,
+/// The trackers of a torrent, tried tier after tier (BEP 12).
+#[derive(Debug, Clone)]
+pub struct TrackerTiers {
+    tiers: Vec<Vec<String>>,
+}
+
+impl TrackerTiers {
+    pub fn new(torrent: &Torrent) -> Result<Self> {
+        Self::from_tiers(torrent.announce_tiers())
+    }
+
+    /// The URLs in each tier are shuffled.
+    pub fn from_tiers(mut tiers: Vec<Vec<String>>) -> Result<Self> {
+        if tiers.is_empty() {
+            anyhow::bail!("Missing announce URL");
+        }
+        let mut rng = rand::thread_rng();
+        tiers.iter_mut().for_each(|tier| tier.shuffle(&mut rng));
+        Ok(TrackerTiers { tiers })
+    }
+
+    pub fn tiers(&self) -> &[Vec<String>] {
+        &self.tiers
+    }
+
+    /// Call `f` on each tracker in order until one succeeds, telling it whether the tracker is the
+    /// last one to try. That tracker moves to the front of its tier, to be tried first next time.
+    pub async fn try_each<T, F, Fut>(&mut self, mut f: F) -> Result<T>
+    where
+        F: FnMut(String, bool) -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let mut errors = Vec::new();
+        let mut left: usize = self.tiers.iter().map(Vec::len).sum();
+        for tier in self.tiers.iter_mut() {
+            for i in 0..tier.len() {
+                left -= 1;
+                match f(tier[i].clone(), left == 0).await {
+                    Ok(res) => {
+                        let url = tier.remove(i);
+                        tier.insert(0, url);
+                        return Ok(res);
+                    }
+                    Err(err) => {
+                        log::debug!("Tracker failed: url={} err={:#}", tier[i], err);
+                        errors.push(format!("{}: {:#}", tier[i], err));
+                    }
+                }
+            }
+        }
+        anyhow::bail!("All trackers failed:\n{}", errors.join("\n"))
+    }
+}
+
+/// Announce a torrent we only know the info_hash of, e.g. from a magnet link, to find peers to
+/// fetch its metadata from.
+pub async fn announce_info_hash(
+    clients: &TrackerClients,
+    tiers: &mut TrackerTiers,
     port: u16,
     info_hash: &[u8; 20],
 ) -> Result<Vec<Peer>> {
-    let url = torrent
-        .announce
-        .as_ref()
-        .context("Missing announce URL in the torrent file")?;
+    // We do not know the size yet, but must not pass for a seeder
+    let download_state = &DownloadState {
+        left: 1,
+        ..Default::default()
+    };
+    tiers
+        .try_each(|url, last| async move {
+            announce(
+                &clients.for_attempt(last),
+                &url,
+                download_state,
+                port,
+                info_hash,
+                AnnounceEvent::None,
+                None,
+            )
+            .await
+        })
+        .await
+        .map(|res| res.peers)
+}
 
-    let info_hash_percent_encoded = info_hash
-        .iter()
-        .map(|b| format!("%{:02X}", b))
-        .collect::<String>();
+/// Announce to one tracker, over HTTP or UDP depending on the URL.
+async fn announce(
+    clients: &TrackerClients,
+    url: &str,
+    download_state: &DownloadState,
+    port: u16,
+    info_hash: &[u8; 20],
+    event: AnnounceEvent,
+    tracker_id: Option<&str>,
+) -> Result<Announce> {
+    if url.starts_with("udp://") {
+        let tracker = clients.udp(&udp_tracker_addr(url)?).await?;
+        let mut tracker = tracker.lock().await;
+        tracker.set_max_retries(clients.udp_retries);
+        let res = tracker
+            .announce(info_hash, download_state, port, event)
+            .await?;
+        return Ok(Announce {
+            interval: Some(Duration::from_secs(res.interval as u64)),
+            min_interval: None,
+            tracker_id: None,
+            seeders: Some(res.seeders),
+            leechers: Some(res.leechers),
+            peers: res.peers,
+        });
+    }
 
-    let query = format!(
+    let mut query = format!(
         "port={}&compact=1&peer_id={}&left={}&uploaded={}&downloaded={}&info_hash={}",
         port,
         String::from_utf8_lossy(PEER_ID),
         download_state.left,
         download_state.uploaded,
         download_state.downloaded,
-        info_hash_percent_encoded
+        percent_encode(info_hash)
     );
-    let req = format!("{}?{}", url, query);
+    if let Some(event) = event.as_query() {
+        query.push_str("&event=");
+        query.push_str(event);
+    }
+    if let Some(tracker_id) = tracker_id {
+        query.push_str("&trackerid=");
+        query.push_str(&percent_encode(tracker_id.as_bytes()));
+    }
+    // Lets the tracker give our IPv6 address to the other peers even if we announce over IPv4
+    if let Some(ipv6) = local_ipv6() {
+        query.push_str("&ipv6=");
+        query.push_str(&percent_encode(ipv6.to_string().as_bytes()));
+    }
+    let req = with_query(url, &query);
     log::debug!("url={}", url);
 
-    let res = client
+    let res = clients
+        .http
         .get(req)
+        .timeout(clients.http_timeout)
         .send()
         .await
         .context("Failed to contact tracker")?
         .bytes()
         .await?;
 
-    let decoded_res: TrackerResponse = de::from_bytes::<TrackerResponse>(&res)
+    decode_http_response(&res)
+}
+
+/// Append `query` to the parameters already in `url`, such as a passkey.
+fn with_query(url: &str, query: &str) -> String {
+    let separator = if url.contains('?') { '&' } else { '?' };
+    format!("{}{}{}", url, separator, query)
+}
+
+/// `host:port` of a `udp://` tracker URL.
+fn udp_tracker_addr(url: &str) -> Result<String> {
+    let parsed = reqwest::Url::parse(url).context("Invalid tracker URL")?;
+    let host = parsed.host_str().context("Missing host in tracker URL")?;
+    let port = parsed.port().context("Missing port in tracker URL")?;
+    Ok(format!("{}:{}", host, port))
+}
+
+#[derive(Debug, Deserialize)]
+struct ScrapeResponse {
+    #[serde(rename = "failure reason")]
+    failure_reason: Option<String>,
+    #[serde(default)]
+    files: HashMap<ByteBuf, ScrapeFile>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ScrapeFile {
+    complete: u64,
+    /// Optional (BEP 48)
+    #[serde(default)]
+    downloaded: u64,
+    incomplete: u64,
+}
+
+/// Ask the tracker behind `announce_url` for the size of the swarms of `info_hashes` (BEP 48).
+/// The stats are in the same order as `info_hashes`, all zero for torrents the tracker does not
+/// know.
+pub async fn scrape(
+    clients: &TrackerClients,
+    announce_url: &str,
+    info_hashes: &[[u8; 20]],
+) -> Result<Vec<ScrapeStats>> {
+    if announce_url.starts_with("udp://") {
+        let tracker = clients.udp(&udp_tracker_addr(announce_url)?).await?;
+        let mut tracker = tracker.lock().await;
+        let mut stats = Vec::with_capacity(info_hashes.len());
+        for chunk in info_hashes.chunks(udp::MAX_SCRAPE_HASHES) {
+            stats.extend(tracker.scrape(chunk).await?);
+        }
+        return Ok(stats);
+    }
+
+    let query: Vec<String> = info_hashes
+        .iter()
+        .map(|info_hash| format!("info_hash={}", percent_encode(info_hash)))
+        .collect();
+    let url = scrape_url(announce_url)?;
+    let res = clients
+        .http
+        .get(with_query(&url, &query.join("&")))
+        .timeout(clients.http_timeout)
+        .send()
+        .await
+        .context("Failed to contact tracker")?
+        .bytes()
+        .await?;
+
+    decode_scrape_response(&res, info_hashes)
+}
+
+/// The scrape URL is the announce URL with `announce` replaced by `scrape` in the last path
+/// component, when it starts with it. Otherwise the tracker does not support scraping.
+fn scrape_url(announce_url: &str) -> Result<String> {
+    let last_slash = announce_url
+        .rfind('/')
+        .with_context(|| format!("Invalid announce URL: {}", announce_url))?;
+    let (base, last) = announce_url.split_at(last_slash + 1);
+    match last.strip_prefix("announce") {
+        Some(rest) => Ok(format!("{}scrape{}", base, rest)),
+        None => anyhow::bail!("Tracker does not support scraping: {}", announce_url),
+    }
+}
+
+fn decode_scrape_response(res: &[u8], info_hashes: &[[u8; 20]]) -> Result<Vec<ScrapeStats>> {
+    let decoded_res: ScrapeResponse =
+        de::from_bytes(res).with_context(|| "Failed to deserialize scrape response")?;
+    if let Some(reason) = decoded_res.failure_reason {
+        return Err(TrackerError::Failure(reason).into());
+    }
+
+    Ok(info_hashes
+        .iter()
+        .map(|info_hash| {
+            decoded_res
+                .files
+                .get(serde_bytes::Bytes::new(info_hash))
+                .map(|file| ScrapeStats {
+                    seeders: file.complete,
+                    completed: file.downloaded,
+                    leechers: file.incomplete,
+                })
+                .unwrap_or(ScrapeStats {
+                    seeders: 0,
+                    completed: 0,
+                    leechers: 0,
+                })
+        })
+        .collect())
+}
+
+/// Our global IPv6 address, if any: the one the system would send from.
+fn local_ipv6() -> Option<Ipv6Addr> {
+    let socket = std::net::UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0)).ok()?;
+    // No packet is sent, this only picks a route
+    socket
+        .connect((
+            Ipv6Addr::new(0x2001, 0x4860, 0x4860, 0, 0, 0, 0, 0x8888),
+            53,
+        ))
+        .ok()?;
+    match socket.local_addr().ok()?.ip() {
+        IpAddr::V6(ip) if is_global_ipv6(&ip) => Some(ip),
+        _ => None,
+    }
+}
+
+fn is_global_ipv6(ip: &Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+    !ip.is_loopback()
+        && !ip.is_unspecified()
+        // Link-local and unique local
+        && first & 0xffc0 != 0xfe80
+        && first & 0xfe00 != 0xfc00
+}
+
+fn percent_encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("%{:02X}", b)).collect()
+}
+
+/// Decode the bencoded answer of an HTTP tracker to an announce.
+pub fn decode_http_response(res: &[u8]) -> Result<Announce> {
+    let decoded_res: TrackerResponse = de::from_bytes::<TrackerResponse>(res)
         .with_context(|| "Failed to deserialize tracker response")?;
 
-    Ok(decode_compact_peers(decoded_res.peers.as_slice())?)
+    if let Some(reason) = decoded_res.failure_reason {
+        return Err(TrackerError::Failure(reason).into());
+    }
+    if let Some(warning) = &decoded_res.warning_message {
+        log::warn!("Tracker warning: {}", warning);
+    }
+
+    let mut peers = match decoded_res.peers {
+        None => Vec::new(),
+        Some(PeerList::Compact(compact_peers)) => decode_compact_peers(&compact_peers)?,
+        Some(PeerList::Dictionaries(dictionaries)) => dictionaries
+            .into_iter()
+            .filter_map(|peer| match peer.ip.parse() {
+                Ok(ip) => Some(Peer {
+                    ip,
+                    port: peer.port,
+                }),
+                Err(_) => {
+                    log::debug!("Ignoring peer with a DNS name: {}", peer.ip);
+                    None
+                }
+            })
+            .collect(),
+    };
+    if let Some(compact_peers6) = decoded_res.peers6 {
+        peers.extend(decode_compact_peers6(&compact_peers6)?);
+    }
+
+    let seconds = |s: usize| Duration::from_secs(s as u64);
+    Ok(Announce {
+        interval: decoded_res.interval.map(seconds),
+        min_interval: decoded_res.min_interval.map(seconds),
+        tracker_id: decoded_res.tracker_id,
+        seeders: decoded_res.complete,
+        leechers: decoded_res.incomplete,
+        peers,
+    })
 }
 
-fn decode_compact_peers(compact_peers: &[u8]) -> Result<Vec<Peer>> {
-    if compact_peers.len() % 6 != 0 {
+/// Wait that long between announces when the tracker does not say.
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(30 * 60);
+/// Wait that long before trying again when all the trackers failed.
+const RETRY_INTERVAL: Duration = Duration::from_secs(60);
+/// Do not delay the shutdown more than that for the `stopped` announce.
+const STOP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Stop announcing, after telling the trackers.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct StopAnnouncing;
+
+/// Size of the swarm according to the last tracker that said.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SwarmSize {
+    pub seeders: u32,
+    pub leechers: u32,
+}
+
+#[derive(Message)]
+#[rtype(result = "Option<SwarmSize>")]
+pub struct GetSwarmSize;
+
+/// Announces a torrent to its trackers for as long as it runs, and hands the peers they return to
+/// the connection code.
+pub struct TrackerActor {
+    tiers: TrackerTiers,
+    in_flight: bool,
+    clients: TrackerClients,
+    info_hash: [u8; 20],
+    port: u16,
+    pieces_actor: Addr<PiecesActor>,
+    peers: mpsc::UnboundedSender<Vec<Peer>>,
+    /// Event of the next announce
+    event: AnnounceEvent,
+    last_announce: Option<Instant>,
+    min_interval: Duration,
+    next_announce: Option<SpawnHandle>,
+    stopped: bool,
+    // By tracker URL
+    tracker_ids: HashMap<String, String>,
+    swarm: Option<SwarmSize>,
+}
+
+impl TrackerActor {
+    pub fn new(
+        torrent: &Torrent,
+        info_hash: [u8; 20],
+        port: u16,
+        pieces_actor: Addr<PiecesActor>,
+        peers: mpsc::UnboundedSender<Vec<Peer>>,
+    ) -> Result<Self> {
+        Ok(TrackerActor {
+            tiers: TrackerTiers::new(torrent)?,
+            in_flight: false,
+            clients: TrackerClients::default(),
+            info_hash,
+            port,
+            pieces_actor,
+            peers,
+            event: AnnounceEvent::Started,
+            last_announce: None,
+            min_interval: Duration::from_secs(0),
+            next_announce: None,
+            stopped: false,
+            tracker_ids: HashMap::new(),
+            swarm: None,
+        })
+    }
+
+    fn schedule(&mut self, delay: Duration, ctx: &mut Context<Self>) {
+        if let Some(handle) = self.next_announce.take() {
+            ctx.cancel_future(handle);
+        }
+        self.next_announce = Some(ctx.run_later(delay, |tracker, ctx| tracker.announce(ctx)));
+    }
+
+    fn announce(&mut self, ctx: &mut Context<Self>) {
+        // The event is sent next time
+        if self.in_flight {
+            return;
+        }
+        self.in_flight = true;
+        self.next_announce = None;
+
+        let event = self.event;
+        let mut tiers = self.tiers.clone();
+        let clients = self.clients.clone();
+        let info_hash = self.info_hash;
+        let port = self.port;
+        let tracker_ids = self.tracker_ids.clone();
+        let download_state = self.pieces_actor.send(GetDownloadState);
+        let fut = async move {
+            let res = match download_state.await {
+                Ok(download_state) => {
+                    let download_state = &download_state;
+                    let tracker_ids = &tracker_ids;
+                    let clients = &clients;
+                    tiers
+                        .try_each(|url, last| async move {
+                            let tracker_id = tracker_ids.get(&url).map(String::as_str);
+                            let res = announce(
+                                &clients.for_attempt(last),
+                                &url,
+                                download_state,
+                                port,
+                                &info_hash,
+                                event,
+                                tracker_id,
+                            )
+                            .await?;
+                            Ok((url, res))
+                        })
+                        .await
+                }
+                Err(err) => Err(err.into()),
+            };
+            (tiers, res)
+        };
+        ctx.spawn(fut.into_actor(self).map(move |(tiers, res), tracker, ctx| {
+            tracker.tiers = tiers;
+            tracker.in_flight = false;
+            tracker.on_announce(event, res, ctx);
+        }));
+    }
+
+    fn on_announce(
+        &mut self,
+        event: AnnounceEvent,
+        res: Result<(String, Announce)>,
+        ctx: &mut Context<Self>,
+    ) {
+        if self.stopped {
+            return;
+        }
+        let (url, res) = match res {
+            Ok(res) => res,
+            Err(err) => {
+                log::warn!("Failed to announce: {:#}", err);
+                self.schedule(RETRY_INTERVAL, ctx);
+                return;
+            }
+        };
+        log::debug!("Announced: event={:?} peers={}", event, res.peers.len());
+        self.last_announce = Some(Instant::now());
+        if self.event == event {
+            self.event = AnnounceEvent::None;
+        }
+        if let Some(tracker_id) = res.tracker_id {
+            self.tracker_ids.insert(url, tracker_id);
+        }
+        if let (Some(seeders), Some(leechers)) = (res.seeders, res.leechers) {
+            self.swarm = Some(SwarmSize { seeders, leechers });
+        }
+        if !res.peers.is_empty() {
+            let _ = self.peers.send(res.peers);
+        }
+
+        self.min_interval = res.min_interval.unwrap_or_default();
+        let interval = res
+            .interval
+            .unwrap_or(DEFAULT_INTERVAL)
+            .max(self.min_interval);
+        if self.event == AnnounceEvent::None {
+            self.schedule(interval, ctx);
+        } else {
+            self.schedule(self.min_interval, ctx);
+        }
+    }
+}
+
+impl Actor for TrackerActor {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Context<Self>) {
+        self.pieces_actor
+            .do_send(SubscribeCompleted(ctx.address().recipient()));
+        self.announce(ctx);
+    }
+}
+
+impl Handler<DownloadCompleted> for TrackerActor {
+    type Result = ();
+
+    fn handle(&mut self, _: DownloadCompleted, ctx: &mut Context<Self>) -> Self::Result {
+        // Never announced yet: the tracker learns it from `left` in the `started` announce
+        if self.event == AnnounceEvent::Started || self.stopped {
+            return;
+        }
+        self.event = AnnounceEvent::Completed;
+        // Otherwise sent when the announce in flight is done
+        if !self.in_flight {
+            let delay = self
+                .last_announce
+                .map(|last| (last + self.min_interval).saturating_duration_since(Instant::now()))
+                .unwrap_or_default();
+            self.schedule(delay, ctx);
+        }
+    }
+}
+
+impl Handler<StopAnnouncing> for TrackerActor {
+    type Result = ResponseFuture<()>;
+
+    fn handle(&mut self, _: StopAnnouncing, ctx: &mut Context<Self>) -> Self::Result {
+        self.stopped = true;
+        if let Some(handle) = self.next_announce.take() {
+            ctx.cancel_future(handle);
+        }
+        // The trackers never heard of us
+        if self.last_announce.is_none() && !self.in_flight {
+            return Box::pin(async {});
+        }
+
+        let mut tiers = self.tiers.clone();
+        let clients = self.clients.clone();
+        let info_hash = self.info_hash;
+        let port = self.port;
+        let tracker_ids = std::mem::take(&mut self.tracker_ids);
+        let download_state = self.pieces_actor.send(GetDownloadState);
+        Box::pin(async move {
+            let stop = async {
+                let download_state = &download_state.await?;
+                let tracker_ids = &tracker_ids;
+                let clients = &clients;
+                tiers
+                    .try_each(|url, last| async move {
+                        announce(
+                            &clients.for_attempt(last),
+                            &url,
+                            download_state,
+                            port,
+                            &info_hash,
+                            AnnounceEvent::Stopped,
+                            tracker_ids.get(&url).map(String::as_str),
+                        )
+                        .await
+                    })
+                    .await
+            };
+            match tokio::time::timeout(STOP_TIMEOUT, stop).await {
+                Ok(Ok(_)) => log::debug!("Announced: event=Stopped"),
+                Ok(Err(err)) => log::warn!("Failed to announce: {:#}", err),
+                Err(_) => log::warn!("Timed out announcing the stop"),
+            }
+        })
+    }
+}
+
+impl Handler<GetSwarmSize> for TrackerActor {
+    type Result = Option<SwarmSize>;
+
+    fn handle(&mut self, _: GetSwarmSize, _: &mut Context<Self>) -> Self::Result {
+        self.swarm
+    }
+}
+
+/// Peers as 4 bytes of IPv4 address and 2 bytes of port each.
+pub fn decode_compact_peers(compact_peers: &[u8]) -> Result<Vec<Peer>> {
+    if !compact_peers.len().is_multiple_of(6) {
         anyhow::bail!(
             "The compact peers list has the wrong size: {}",
             compact_peers.len()
@@ -99,3 +796,382 @@ fn decode_compact_peers(compact_peers: &[u8]) -> Result<Vec<Peer>> {
         })
         .collect())
 }
+
+/// Peers as 16 bytes of IPv6 address and 2 bytes of port each (BEP 7).
+pub fn decode_compact_peers6(compact_peers: &[u8]) -> Result<Vec<Peer>> {
+    if !compact_peers.len().is_multiple_of(18) {
+        anyhow::bail!(
+            "The compact IPv6 peers list has the wrong size: {}",
+            compact_peers.len()
+        );
+    }
+    Ok(compact_peers
+        .chunks(18)
+        .map(|bytes| {
+            let ip_bytes: [u8; 16] = bytes[0..16].try_into().unwrap();
+            Peer {
+                ip: IpAddr::V6(Ipv6Addr::from(ip_bytes)),
+                port: u16::from_be_bytes([bytes[16], bytes[17]]),
+            }
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use actix::prelude::*;
+    use anyhow::bail;
+    use sha1::{Digest, Sha1};
+    use std::convert::TryInto;
+    use std::env;
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::net::{TcpListener, UdpSocket};
+    use tokio::sync::mpsc;
+
+    use crate::fs::FileActor;
+    use crate::pieces::{BlockReceived, PiecesActor};
+    use crate::torrent_file::decode_torrent;
+    use crate::tracker::{
+        announce_info_hash, decode_http_response, decode_scrape_response, info_hash, scrape_url,
+        with_query, ScrapeStats, StopAnnouncing, TrackerActor, TrackerClients, TrackerError,
+        TrackerTiers,
+    };
+
+    /// A torrent with a single piece.
+    fn torrent_with_piece(trackers: &str, piece: &[u8]) -> Vec<u8> {
+        let mut content =
+            format!("d{}4:infod6:lengthi{}e4:name4:test", trackers, piece.len()).into_bytes();
+        content.extend_from_slice(b"12:piece lengthi16384e6:pieces20:");
+        content.extend_from_slice(&Sha1::digest(piece));
+        content.extend_from_slice(b"ee");
+        content
+    }
+
+    fn torrent_bytes(trackers: &str) -> Vec<u8> {
+        torrent_with_piece(trackers, &[0; 10])
+    }
+
+    /// Answer announces, reporting the event and `left` of each.
+    async fn fake_udp_tracker(socket: UdpSocket, events: mpsc::UnboundedSender<(u32, u64)>) {
+        let mut buf = [0u8; 2048];
+        loop {
+            let (len, from) = socket.recv_from(&mut buf).await.unwrap();
+            let req = &buf[..len];
+            let mut res = req[8..16].to_vec();
+            if req[8..12] == 0u32.to_be_bytes() {
+                res.extend_from_slice(&1u64.to_be_bytes());
+            } else {
+                let left = u64::from_be_bytes(req[64..72].try_into().unwrap());
+                let event = u32::from_be_bytes(req[80..84].try_into().unwrap());
+                events.send((event, left)).unwrap();
+                // interval, leechers, seeders and one peer
+                res.extend_from_slice(&[0, 0, 7, 8, 0, 0, 0, 1, 0, 0, 0, 1]);
+                res.extend_from_slice(&[127, 0, 0, 1, 0x1a, 0xe1]);
+            }
+            socket.send_to(&res, from).await.unwrap();
+        }
+    }
+
+    #[test]
+    fn http_response_should_accept_both_peer_formats() {
+        let mut res = b"d8:completei5e10:incompletei3e8:intervali1800e".to_vec();
+        res.extend_from_slice(b"5:peers6:");
+        res.extend_from_slice(&[10, 0, 0, 1, 0x1a, 0xe1]);
+        res.extend_from_slice(b"10:tracker id3:abc15:warning message4:slowe");
+        let announce = decode_http_response(&res).unwrap();
+        assert_eq!(announce.seeders, Some(5));
+        assert_eq!(announce.leechers, Some(3));
+        assert_eq!(announce.tracker_id.as_deref(), Some("abc"));
+        assert_eq!(announce.peers.len(), 1);
+        assert_eq!(announce.peers[0].port, 6881);
+
+        let res = b"d8:intervali1800e5:peersld2:ip8:10.0.0.27:peer id20:aaaaaaaaaaaaaaaaaaaa4:porti6882eed2:ip11:example.org4:porti6883eed2:ip3:::14:porti6884eeee";
+        let announce = decode_http_response(res).unwrap();
+        assert_eq!(announce.seeders, None);
+        let peers: Vec<String> = announce
+            .peers
+            .iter()
+            .map(|peer| format!("{}:{}", peer.ip, peer.port))
+            .collect();
+        assert_eq!(peers, vec!["10.0.0.2:6882", "::1:6884"]);
+    }
+
+    #[test]
+    fn http_response_should_add_ipv6_peers() {
+        let mut res = b"d5:peers0:6:peers636:".to_vec();
+        for last in [1, 2] {
+            res.extend_from_slice(&[0x20, 0x01, 0x0d, 0xb8]);
+            res.extend_from_slice(&[0; 11]);
+            res.push(last);
+            res.extend_from_slice(&6881u16.to_be_bytes());
+        }
+        res.push(b'e');
+        let peers: Vec<SocketAddr> = decode_http_response(&res)
+            .unwrap()
+            .peers
+            .iter()
+            .map(|peer| SocketAddr::new(peer.ip, peer.port))
+            .collect();
+        assert_eq!(
+            peers,
+            vec![
+                "[2001:db8::1]:6881".parse().unwrap(),
+                "[2001:db8::2]:6881".parse().unwrap()
+            ]
+        );
+
+        let mut res = b"d6:peers617:".to_vec();
+        res.extend_from_slice(&[0; 17]);
+        res.push(b'e');
+        assert!(decode_http_response(&res).is_err());
+    }
+
+    #[test]
+    fn scrape_url_should_replace_announce() {
+        for (announce, scrape) in [
+            ("http://example.com/announce", "http://example.com/scrape"),
+            (
+                "http://example.com/x/announce",
+                "http://example.com/x/scrape",
+            ),
+            (
+                "http://example.com/announce.php",
+                "http://example.com/scrape.php",
+            ),
+            (
+                "http://example.com/announce?x2%0644",
+                "http://example.com/scrape?x2%0644",
+            ),
+        ] {
+            assert_eq!(scrape_url(announce).unwrap(), scrape);
+        }
+        for announce in [
+            "http://example.com/a",
+            "http://example.com/announce?x=2/4",
+            "http://example.com/x%064announce",
+        ] {
+            assert!(scrape_url(announce).is_err(), "{}", announce);
+        }
+    }
+
+    #[test]
+    fn query_should_follow_the_parameters_of_the_url() {
+        assert_eq!(
+            with_query("http://example.com/announce", "port=6881"),
+            "http://example.com/announce?port=6881"
+        );
+        assert_eq!(
+            with_query("http://example.com/announce?passkey=x", "port=6881"),
+            "http://example.com/announce?passkey=x&port=6881"
+        );
+    }
+
+    #[test]
+    fn scrape_response_should_follow_requested_order() {
+        let mut res = b"d5:filesd20:".to_vec();
+        res.extend_from_slice(&[8; 20]);
+        res.extend_from_slice(b"d8:completei5e10:downloadedi50e10:incompletei10eeee");
+        let stats = decode_scrape_response(&res, &[[7; 20], [8; 20]]).unwrap();
+        assert_eq!(
+            stats,
+            vec![
+                ScrapeStats {
+                    seeders: 0,
+                    completed: 0,
+                    leechers: 0
+                },
+                ScrapeStats {
+                    seeders: 5,
+                    completed: 50,
+                    leechers: 10
+                }
+            ]
+        );
+
+        let mut res = b"d5:filesd20:".to_vec();
+        res.extend_from_slice(&[7; 20]);
+        res.extend_from_slice(b"d8:completei5000000000e10:incompletei1eeee");
+        assert_eq!(
+            decode_scrape_response(&res, &[[7; 20]]).unwrap(),
+            vec![ScrapeStats {
+                seeders: 5_000_000_000,
+                completed: 0,
+                leechers: 1
+            }]
+        );
+
+        let err = decode_scrape_response(b"d14:failure reason7:privatee", &[[7; 20]]).unwrap_err();
+        assert!(err.downcast_ref::<TrackerError>().is_some());
+    }
+
+    #[test]
+    fn http_failure_reason_should_be_a_tracker_error() {
+        let err = decode_http_response(b"d14:failure reason17:torrent not founde").unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<TrackerError>(),
+            Some(&TrackerError::Failure(String::from("torrent not found")))
+        );
+    }
+
+    #[test]
+    fn announce_list_should_take_precedence_over_announce() {
+        let torrent = decode_torrent(&torrent_bytes(
+            "8:announce5:http013:announce-listll5:http15:http2el5:http3ee",
+        ))
+        .unwrap();
+        assert_eq!(
+            torrent.announce_tiers(),
+            vec![
+                vec![String::from("http1"), String::from("http2")],
+                vec![String::from("http3")]
+            ]
+        );
+
+        let torrent = decode_torrent(&torrent_bytes("8:announce5:http0")).unwrap();
+        assert_eq!(torrent.announce_tiers(), vec![vec![String::from("http0")]]);
+
+        let torrent = decode_torrent(&torrent_bytes("")).unwrap();
+        assert!(TrackerTiers::new(&torrent).is_err());
+    }
+
+    #[tokio::test]
+    async fn responding_tracker_should_move_to_front_of_its_tier() {
+        let torrent = decode_torrent(&torrent_bytes("13:announce-listll1:a1:b1:cel1:dee")).unwrap();
+        let mut tiers = TrackerTiers::new(&torrent).unwrap();
+
+        let mut tried = Vec::new();
+        let res = tiers
+            .try_each(|url, _| {
+                tried.push(url.clone());
+                async move {
+                    if url == "c" {
+                        Ok(url)
+                    } else {
+                        bail!("down")
+                    }
+                }
+            })
+            .await
+            .unwrap();
+        assert_eq!(res, "c");
+        assert_eq!(tiers.tiers()[0][0], "c");
+        assert_eq!(tiers.tiers()[1], vec![String::from("d")]);
+        // The first tier is tried before the second one
+        assert!(!tried.contains(&String::from("d")));
+
+        let mut tried = Vec::new();
+        tiers
+            .try_each(|url, _| {
+                tried.push(url.clone());
+                async move { Ok(url) }
+            })
+            .await
+            .unwrap();
+        assert_eq!(tried, vec![String::from("c")]);
+    }
+
+    #[tokio::test]
+    async fn all_trackers_failing_should_aggregate_errors() {
+        let torrent = decode_torrent(&torrent_bytes("13:announce-listll1:ael1:bee")).unwrap();
+        let mut tiers = TrackerTiers::new(&torrent).unwrap();
+        let mut last = Vec::new();
+        let err = tiers
+            .try_each(|url, is_last| {
+                last.push(is_last);
+                async move { Err::<(), _>(anyhow::anyhow!("{} is down", url)) }
+            })
+            .await
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("a: a is down"), "{}", err);
+        assert!(err.contains("b: b is down"), "{}", err);
+        // Only the last tracker gets the whole retransmission schedule
+        assert_eq!(last, vec![false, true]);
+    }
+
+    #[tokio::test]
+    async fn silent_http_tracker_should_fail_over_to_the_next() {
+        // Accepts connections but never answers
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let silent = format!("http://{}/announce", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let mut sockets = Vec::new();
+            while let Ok((socket, _)) = listener.accept().await {
+                sockets.push(socket);
+            }
+        });
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let udp = format!("udp://{}/announce", socket.local_addr().unwrap());
+        let (events_tx, _events_rx) = mpsc::unbounded_channel();
+        tokio::spawn(fake_udp_tracker(socket, events_tx));
+
+        let mut tiers = TrackerTiers::from_tiers(vec![vec![silent], vec![udp]]).unwrap();
+        let clients = TrackerClients {
+            http_timeout: Duration::from_millis(50),
+            ..TrackerClients::default()
+        };
+        let announce = announce_info_hash(&clients, &mut tiers, 6881, &[7; 20]);
+        let peers = tokio::time::timeout(Duration::from_secs(10), announce)
+            .await
+            .expect("Still waiting for the silent tracker")
+            .unwrap();
+        assert_eq!(peers.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn udp_tracker_clients_should_be_reused() {
+        let clients = TrackerClients::default();
+        let first = clients.udp("127.0.0.1:6969").await.unwrap();
+        let same = clients.clone().udp("127.0.0.1:6969").await.unwrap();
+        let other = clients.udp("127.0.0.1:6970").await.unwrap();
+        assert!(Arc::ptr_eq(&first, &same));
+        assert!(!Arc::ptr_eq(&first, &other));
+    }
+
+    #[actix::test]
+    async fn tracker_actor_should_announce_events_and_forward_peers() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("udp://{}/announce", socket.local_addr().unwrap());
+        let (events_tx, mut events_rx) = mpsc::unbounded_channel();
+        tokio::spawn(fake_udp_tracker(socket, events_tx));
+
+        let piece = vec![5u8; 10];
+        let trackers = format!("8:announce{}:{}", url.len(), url);
+        let torrent = decode_torrent(&torrent_with_piece(&trackers, &piece)).unwrap();
+        let mut path = env::temp_dir();
+        path.push("sharku_tracker_actor_should_announce_events_and_forward_peers");
+        let file_actor = FileActor::new(&path, 10, 16384).unwrap().start();
+        let pieces_actor = PiecesActor::new(&torrent.info, file_actor).start();
+        let (peers_tx, mut peers_rx) = mpsc::unbounded_channel();
+        let tracker = TrackerActor::new(
+            &torrent,
+            info_hash(&torrent),
+            6881,
+            pieces_actor.clone(),
+            peers_tx,
+        )
+        .unwrap()
+        .start();
+
+        assert_eq!(events_rx.recv().await.unwrap(), (2, 10));
+        let peers = peers_rx.recv().await.unwrap();
+        assert_eq!(peers.len(), 1);
+        assert_eq!(peers[0].port, 6881);
+
+        pieces_actor
+            .send(BlockReceived {
+                peer: 0,
+                index: 0,
+                begin: 0,
+                data: piece,
+            })
+            .await
+            .unwrap();
+        assert_eq!(events_rx.recv().await.unwrap(), (1, 0));
+
+        tracker.send(StopAnnouncing).await.unwrap();
+        assert_eq!(events_rx.recv().await.unwrap(), (3, 0));
+    }
+}
@@ -0,0 +1,452 @@
+use anyhow::{bail, Context, Result};
+use std::convert::TryInto;
+use std::time::{Duration, Instant};
+use tokio::net::{lookup_host, UdpSocket};
+
+use crate::message::PEER_ID;
+use crate::state::DownloadState;
+use crate::tracker::{
+    decode_compact_peers, decode_compact_peers6, AnnounceEvent, Peer, ScrapeStats, TrackerError,
+};
+
+/// Magic constant identifying a connect request.
+const PROTOCOL_ID: u64 = 0x417_2710_1980;
+/// A connection id can be used for that long after it was received.
+const CONNECTION_ID_LIFETIME: Duration = Duration::from_secs(60);
+/// The timeout doubles after each retransmission, up to `TIMEOUT_BASE * 2^MAX_RETRIES`.
+const TIMEOUT_BASE: Duration = Duration::from_secs(15);
+pub const MAX_RETRIES: u32 = 8;
+/// Largest UDP datagram: an announce response over IPv6 takes 18 bytes per peer.
+const MAX_PACKET_LEN: usize = 65535;
+/// Scrape requests carry at most that many info hashes.
+pub const MAX_SCRAPE_HASHES: usize = 74;
+
+const ACTION_CONNECT: u32 = 0;
+const ACTION_ANNOUNCE: u32 = 1;
+const ACTION_SCRAPE: u32 = 2;
+const ACTION_ERROR: u32 = 3;
+
+/// Response to an announce.
+#[derive(Debug)]
+pub struct UdpAnnounce {
+    /// Seconds to wait before the next announce
+    pub interval: u32,
+    pub leechers: u32,
+    pub seeders: u32,
+    pub peers: Vec<Peer>,
+}
+
+/// A client of one UDP tracker (BEP 15).
+pub struct UdpTracker {
+    socket: UdpSocket,
+    connection: Option<(u64, Instant)>,
+    timeout_base: Duration,
+    max_retries: u32,
+    /// Identifies us across IP changes
+    key: u32,
+}
+
+impl UdpTracker {
+    /// `addr` is the `host:port` part of the `udp://` URL.
+    pub async fn connect(addr: &str) -> Result<Self> {
+        let addr = lookup_host(addr)
+            .await
+            .with_context(|| format!("Failed to resolve tracker address: {}", addr))?
+            .next()
+            .with_context(|| format!("No address for tracker: {}", addr))?;
+        let local = if addr.is_ipv4() {
+            "0.0.0.0:0"
+        } else {
+            "[::]:0"
+        };
+        let socket = UdpSocket::bind(local)
+            .await
+            .with_context(|| "Failed to bind UDP socket")?;
+        socket
+            .connect(addr)
+            .await
+            .with_context(|| format!("Failed to connect UDP socket to {}", addr))?;
+
+        Ok(UdpTracker {
+            socket,
+            connection: None,
+            timeout_base: TIMEOUT_BASE,
+            max_retries: MAX_RETRIES,
+            key: rand::random(),
+        })
+    }
+
+    /// Change the retransmission schedule, `timeout_base * 2^n` for the n-th retry.
+    pub fn set_timeouts(&mut self, timeout_base: Duration, max_retries: u32) {
+        self.timeout_base = timeout_base;
+        self.max_retries = max_retries;
+    }
+
+    /// Give up after fewer retries, keeping the same timeouts.
+    pub fn set_max_retries(&mut self, max_retries: u32) {
+        self.max_retries = max_retries;
+    }
+
+    pub async fn announce(
+        &mut self,
+        info_hash: &[u8; 20],
+        download_state: &DownloadState,
+        port: u16,
+        event: AnnounceEvent,
+    ) -> Result<UdpAnnounce> {
+        let event = match event {
+            AnnounceEvent::None => 0u32,
+            AnnounceEvent::Completed => 1,
+            AnnounceEvent::Started => 2,
+            AnnounceEvent::Stopped => 3,
+        };
+        let key = self.key;
+        let res = self
+            .connected_request(ACTION_ANNOUNCE, |req| {
+                req.extend_from_slice(info_hash);
+                req.extend_from_slice(PEER_ID);
+                req.extend_from_slice(&(download_state.downloaded as u64).to_be_bytes());
+                req.extend_from_slice(&(download_state.left as u64).to_be_bytes());
+                req.extend_from_slice(&(download_state.uploaded as u64).to_be_bytes());
+                req.extend_from_slice(&event.to_be_bytes());
+                // Our IP address: the one the packet comes from
+                req.extend_from_slice(&0u32.to_be_bytes());
+                req.extend_from_slice(&key.to_be_bytes());
+                // Number of peers wanted: the default
+                req.extend_from_slice(&(-1i32).to_be_bytes());
+                req.extend_from_slice(&port.to_be_bytes());
+            })
+            .await?;
+
+        if res.len() < 12 {
+            bail!("Announce response too short: {} bytes", res.len());
+        }
+        Ok(UdpAnnounce {
+            interval: read_u32(&res[0..4]),
+            leechers: read_u32(&res[4..8]),
+            seeders: read_u32(&res[8..12]),
+            // The peers have the address family of the tracker (BEP 15)
+            peers: if self.socket.local_addr()?.is_ipv6() {
+                decode_compact_peers6(&res[12..])?
+            } else {
+                decode_compact_peers(&res[12..])?
+            },
+        })
+    }
+
+    /// Stats of each torrent, in the same order as `info_hashes`.
+    pub async fn scrape(&mut self, info_hashes: &[[u8; 20]]) -> Result<Vec<ScrapeStats>> {
+        if info_hashes.is_empty() || info_hashes.len() > MAX_SCRAPE_HASHES {
+            bail!(
+                "Wrong number of info hashes to scrape: {}",
+                info_hashes.len()
+            );
+        }
+        let res = self
+            .connected_request(ACTION_SCRAPE, |req| {
+                info_hashes
+                    .iter()
+                    .for_each(|info_hash| req.extend_from_slice(info_hash))
+            })
+            .await?;
+
+        if res.len() != info_hashes.len() * 12 {
+            bail!(
+                "Scrape response has the wrong size: expected={} got={}",
+                info_hashes.len() * 12,
+                res.len()
+            );
+        }
+        Ok(res
+            .chunks(12)
+            .map(|stats| ScrapeStats {
+                seeders: read_u32(&stats[0..4]) as u64,
+                completed: read_u32(&stats[4..8]) as u64,
+                leechers: read_u32(&stats[8..12]) as u64,
+            })
+            .collect())
+    }
+
+    /// Send a request needing a connection id, getting a new one first if needed. Returns the
+    /// response after the action and transaction id.
+    async fn connected_request(
+        &mut self,
+        action: u32,
+        write_body: impl Fn(&mut Vec<u8>),
+    ) -> Result<Vec<u8>> {
+        let connection_id = self.connection_id().await?;
+        let transaction_id: u32 = rand::random();
+        let mut req = Vec::with_capacity(98);
+        req.extend_from_slice(&connection_id.to_be_bytes());
+        req.extend_from_slice(&action.to_be_bytes());
+        req.extend_from_slice(&transaction_id.to_be_bytes());
+        write_body(&mut req);
+
+        self.request(&req, action, transaction_id).await
+    }
+
+    async fn connection_id(&mut self) -> Result<u64> {
+        if let Some((connection_id, received_at)) = self.connection {
+            if received_at.elapsed() < CONNECTION_ID_LIFETIME {
+                return Ok(connection_id);
+            }
+        }
+
+        let transaction_id: u32 = rand::random();
+        let mut req = Vec::with_capacity(16);
+        req.extend_from_slice(&PROTOCOL_ID.to_be_bytes());
+        req.extend_from_slice(&ACTION_CONNECT.to_be_bytes());
+        req.extend_from_slice(&transaction_id.to_be_bytes());
+
+        let res = self.request(&req, ACTION_CONNECT, transaction_id).await?;
+        if res.len() < 8 {
+            bail!("Connect response too short: {} bytes", res.len());
+        }
+        let connection_id = u64::from_be_bytes(res[..8].try_into().unwrap());
+        self.connection = Some((connection_id, Instant::now()));
+        Ok(connection_id)
+    }
+
+    /// Send `req` until the matching response arrives, following the retransmission schedule.
+    async fn request(&mut self, req: &[u8], action: u32, transaction_id: u32) -> Result<Vec<u8>> {
+        let mut buf = vec![0; MAX_PACKET_LEN];
+        for retry in 0..=self.max_retries {
+            self.socket
+                .send(req)
+                .await
+                .with_context(|| "Failed to send to tracker")?;
+
+            let deadline = tokio::time::Instant::now() + self.timeout_base * 2u32.pow(retry);
+            loop {
+                let len = match tokio::time::timeout_at(deadline, self.socket.recv(&mut buf)).await
+                {
+                    Ok(len) => len.with_context(|| "Failed to receive from tracker")?,
+                    Err(_) => {
+                        log::debug!("Tracker timed out: retry={}", retry);
+                        break;
+                    }
+                };
+                let res = &buf[..len];
+                if len < 8 || read_u32(&res[4..8]) != transaction_id {
+                    log::debug!("Ignoring unexpected packet from tracker: len={}", len);
+                    continue;
+                }
+                match read_u32(&res[0..4]) {
+                    ACTION_ERROR => {
+                        let message = String::from_utf8_lossy(&res[8..]);
+                        let message = message.trim_end_matches('\0').to_owned();
+                        return Err(TrackerError::Failure(message).into());
+                    }
+                    a if a == action => return Ok(res[8..].to_vec()),
+                    a => bail!("Tracker answered with the wrong action: {}", a),
+                }
+            }
+        }
+        // The connection id may be what the tracker does not like
+        self.connection = None;
+        bail!("Tracker did not answer after {} retries", self.max_retries)
+    }
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes(bytes[..4].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryInto;
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+    use std::time::Duration;
+    use tokio::net::UdpSocket;
+
+    use crate::message::PEER_ID;
+    use crate::state::DownloadState;
+    use crate::tracker::udp::{UdpTracker, PROTOCOL_ID};
+    use crate::tracker::{AnnounceEvent, TrackerError};
+
+    const CONNECTION_ID: u64 = 0xdead_beef;
+
+    /// Answer like a tracker, ignoring the first `drop` packets. Returns the number of connect
+    /// requests received.
+    async fn fake_tracker(socket: UdpSocket, mut drop: usize, requests: usize) -> usize {
+        let mut connects = 0;
+        let mut buf = [0u8; 2048];
+        let mut answered = 0;
+        while answered < requests {
+            let (len, from) = socket.recv_from(&mut buf).await.unwrap();
+            if drop > 0 {
+                drop -= 1;
+                continue;
+            }
+            let req = &buf[..len];
+            let action = u32::from_be_bytes(req[8..12].try_into().unwrap());
+            let transaction_id = &req[12..16];
+            let mut res = Vec::new();
+            res.extend_from_slice(&action.to_be_bytes());
+            res.extend_from_slice(transaction_id);
+            match action {
+                0 => {
+                    assert_eq!(
+                        u64::from_be_bytes(req[..8].try_into().unwrap()),
+                        PROTOCOL_ID
+                    );
+                    connects += 1;
+                    res.extend_from_slice(&CONNECTION_ID.to_be_bytes());
+                }
+                1 => {
+                    assert_eq!(len, 98);
+                    assert_eq!(
+                        u64::from_be_bytes(req[..8].try_into().unwrap()),
+                        CONNECTION_ID
+                    );
+                    assert_eq!(&req[16..36], &[7u8; 20]);
+                    assert_eq!(&req[36..56], PEER_ID);
+                    // left
+                    assert_eq!(&req[64..72], &42u64.to_be_bytes());
+                    // event started
+                    assert_eq!(&req[80..84], &2u32.to_be_bytes());
+                    assert_eq!(&req[96..98], &6881u16.to_be_bytes());
+                    res.extend_from_slice(&1800u32.to_be_bytes());
+                    res.extend_from_slice(&3u32.to_be_bytes());
+                    res.extend_from_slice(&5u32.to_be_bytes());
+                    res.extend_from_slice(&[10, 0, 0, 1, 0x1a, 0xe1, 10, 0, 0, 2, 0x1a, 0xe2]);
+                }
+                2 => {
+                    for _ in req[16..].chunks(20) {
+                        res.extend_from_slice(&[0, 0, 0, 5, 0, 0, 0, 9, 0, 0, 0, 3]);
+                    }
+                }
+                _ => unreachable!(),
+            }
+            socket.send_to(&res, from).await.unwrap();
+            answered += 1;
+        }
+        connects
+    }
+
+    async fn tracker_socket() -> (UdpSocket, String) {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap().to_string();
+        (socket, addr)
+    }
+
+    #[tokio::test]
+    async fn announce_and_scrape_should_reuse_connection_id() {
+        let (socket, addr) = tracker_socket().await;
+        let fake = tokio::spawn(fake_tracker(socket, 0, 3));
+
+        let mut tracker = UdpTracker::connect(&addr).await.unwrap();
+        let download_state = DownloadState {
+            left: 42,
+            ..DownloadState::default()
+        };
+        let announce = tracker
+            .announce(&[7; 20], &download_state, 6881, AnnounceEvent::Started)
+            .await
+            .unwrap();
+        assert_eq!(announce.interval, 1800);
+        assert_eq!(announce.leechers, 3);
+        assert_eq!(announce.seeders, 5);
+        assert_eq!(announce.peers.len(), 2);
+        assert_eq!(announce.peers[1].ip, IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)));
+        assert_eq!(announce.peers[1].port, 6882);
+
+        let stats = tracker.scrape(&[[7; 20], [8; 20]]).await.unwrap();
+        assert_eq!(stats.len(), 2);
+        assert_eq!(stats[1].seeders, 5);
+        assert_eq!(stats[1].completed, 9);
+        assert_eq!(stats[1].leechers, 3);
+
+        assert_eq!(fake.await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn lost_packets_should_be_retransmitted() {
+        let (socket, addr) = tracker_socket().await;
+        let fake = tokio::spawn(fake_tracker(socket, 2, 2));
+
+        let mut tracker = UdpTracker::connect(&addr).await.unwrap();
+        tracker.set_timeouts(Duration::from_millis(20), 3);
+        let announce = tracker
+            .announce(
+                &[7; 20],
+                &DownloadState {
+                    left: 42,
+                    ..DownloadState::default()
+                },
+                6881,
+                AnnounceEvent::Started,
+            )
+            .await
+            .unwrap();
+        assert_eq!(announce.peers.len(), 2);
+        assert_eq!(fake.await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn announce_response_with_200_ipv6_peers_should_be_received_whole() {
+        let socket = UdpSocket::bind("[::1]:0").await.unwrap();
+        let addr = socket.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 2048];
+            loop {
+                let (len, from) = socket.recv_from(&mut buf).await.unwrap();
+                let req = &buf[..len];
+                let mut res = req[8..16].to_vec();
+                if req[8..12] == 0u32.to_be_bytes() {
+                    res.extend_from_slice(&CONNECTION_ID.to_be_bytes());
+                } else {
+                    res.extend_from_slice(&[0, 0, 7, 8, 0, 0, 0, 200, 0, 0, 0, 0]);
+                    for i in 0..200u16 {
+                        res.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+                        res.extend_from_slice(&(6881 + i).to_be_bytes());
+                    }
+                }
+                socket.send_to(&res, from).await.unwrap();
+            }
+        });
+
+        let mut tracker = UdpTracker::connect(&addr).await.unwrap();
+        tracker.set_timeouts(Duration::from_millis(200), 2);
+        let announce = tracker
+            .announce(
+                &[7; 20],
+                &DownloadState::default(),
+                6881,
+                AnnounceEvent::None,
+            )
+            .await
+            .unwrap();
+        assert_eq!(announce.peers.len(), 200);
+        assert_eq!(announce.peers[199].ip, IpAddr::V6(Ipv6Addr::LOCALHOST));
+        assert_eq!(announce.peers[199].port, 6881 + 199);
+    }
+
+    #[tokio::test]
+    async fn silent_tracker_should_time_out() {
+        let (_socket, addr) = tracker_socket().await;
+        let mut tracker = UdpTracker::connect(&addr).await.unwrap();
+        tracker.set_timeouts(Duration::from_millis(5), 2);
+        assert!(tracker.scrape(&[[7; 20]]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn error_action_should_be_a_tracker_error() {
+        let (socket, addr) = tracker_socket().await;
+        tokio::spawn(async move {
+            let mut buf = [0u8; 2048];
+            let (_, from) = socket.recv_from(&mut buf).await.unwrap();
+            let mut res = 3u32.to_be_bytes().to_vec();
+            res.extend_from_slice(&buf[12..16]);
+            res.extend_from_slice(b"banned\0");
+            socket.send_to(&res, from).await.unwrap();
+        });
+
+        let mut tracker = UdpTracker::connect(&addr).await.unwrap();
+        let err = tracker.scrape(&[[7; 20]]).await.unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<TrackerError>(),
+            Some(&TrackerError::Failure(String::from("banned")))
+        );
+    }
+}